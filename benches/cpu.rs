@@ -0,0 +1,133 @@
+//! Benchmarks of the cpu core. Throughput is emulated cycles, so `Melem/s` is MHz.
+//!
+//! Each program runs with `Box<dyn Memory>`, with concrete memory, and with the block
+//! cache engine. nestest runs only if `NESTEST_ROM` is set to the path of nestest.nes.
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nes_cpu::bus::Bus;
+use nes_cpu::cartridge::Cartridge;
+use nes_cpu::cpu::{CodeCache, Cpu};
+use nes_cpu::memory::{FlatMemory, Memory};
+use nes_cpu::opcode::lookup;
+
+const CYCLES: u64 = 100_000;
+
+// $8000: LDA #$01; ADC #$03; EOR #$55; AND #$F0; ORA #$0F; ASL A; ROR A; INX; BNE $8000
+// $800F: JMP $8000
+const ALU: &[u8] = &[
+    0xA9, 0x01, 0x69, 0x03, 0x49, 0x55, 0x29, 0xF0, 0x09, 0x0F, 0x0A, 0x6A, 0xE8, 0xD0, 0xF1,
+    0x4C, 0x00, 0x80,
+];
+
+// $8000: LDX #0
+// $8002: LDA $0200,X; STA $0300,X; LDA ($10),Y; STA ($12),Y; INY; INX; BNE $8002
+// $8010: JMP $8000
+const COPY: &[u8] = &[
+    0xA2, 0x00, 0xBD, 0x00, 0x02, 0x9D, 0x00, 0x03, 0xB1, 0x10, 0x91, 0x12, 0xC8, 0xE8, 0xD0, 0xF2,
+    0x4C, 0x00, 0x80,
+];
+
+// $8000: INX; TXA; BMI $8009; AND #$01; BEQ $8009; INY
+// $8009: BNE $8000; JMP $8000
+const BRANCH: &[u8] = &[
+    0xE8, 0x8A, 0x30, 0x05, 0x29, 0x01, 0xF0, 0x01, 0xC8, 0xD0, 0xF5, 0x4C, 0x00, 0x80,
+];
+
+fn memory(program: &[u8]) -> FlatMemory {
+    let mut mem = FlatMemory::new();
+    mem.load(0x8000, program);
+    // Pointers used by COPY
+    mem.load(0x0010, &[0x00, 0x04, 0x00, 0x05]);
+    mem
+}
+
+fn run_blocks<M: Memory>(cpu: &mut Cpu<CodeCache<M>>, cycles: u64) {
+    let end = cpu.cycles() + cycles;
+    while cpu.cycles() < end {
+        cpu.step_block();
+    }
+}
+
+fn program(c: &mut Criterion, name: &str, program: &[u8]) {
+    let mut group = c.benchmark_group(name);
+    group.throughput(Throughput::Elements(CYCLES));
+
+    let mut cpu = Cpu::new(Box::new(memory(program)));
+    cpu.reg_mut().pc = 0x8000;
+    group.bench_function("dyn", |b| b.iter(|| cpu.run_cycles(CYCLES)));
+
+    let mut cpu = Cpu::with_memory(memory(program));
+    cpu.reg_mut().pc = 0x8000;
+    group.bench_function("concrete", |b| b.iter(|| cpu.run_cycles(CYCLES)));
+
+    let mut cpu = Cpu::with_memory(CodeCache::new(Box::new(memory(program)) as Box<dyn Memory>));
+    cpu.reg_mut().pc = 0x8000;
+    group.bench_function("block-dyn", |b| b.iter(|| run_blocks(&mut cpu, CYCLES)));
+
+    let mut cpu = Cpu::with_memory(CodeCache::new(memory(program)));
+    cpu.reg_mut().pc = 0x8000;
+    group.bench_function("block-concrete", |b| b.iter(|| run_blocks(&mut cpu, CYCLES)));
+
+    group.finish();
+}
+
+fn alu(c: &mut Criterion) {
+    program(c, "alu", ALU);
+}
+
+fn copy(c: &mut Criterion) {
+    program(c, "copy", COPY);
+}
+
+fn branch(c: &mut Criterion) {
+    program(c, "branch", BRANCH);
+}
+
+// Run nestest in automation mode ($C000) from the saved state, for the number of
+// instructions executed before the first unofficial opcode, up to the length of
+// the official log.
+fn nestest(c: &mut Criterion) {
+    const LOG_LINES: u64 = 8991;
+
+    let Ok(path) = std::env::var("NESTEST_ROM") else {
+        eprintln!("NESTEST_ROM is not set, skipping nestest");
+        return;
+    };
+    let rom  = std::fs::read(&path).expect("failed to read NESTEST_ROM");
+    let cart = || Cartridge::from_bytes(&rom).expect("invalid rom");
+
+    let mut cpu = Cpu::with_memory(Bus::new(cart()).expect("unsupported rom"));
+    cpu.reg_mut().pc = 0xC000;
+    cpu.reg_mut().s  = 0xFD;
+    let state = cpu.save_state().unwrap();
+
+    let mut steps = 0;
+    while steps < LOG_LINES && lookup(cpu.mem().read_byte(cpu.reg().pc)).is_some() {
+        cpu.step();
+        steps += 1;
+    }
+    let cycles = cpu.cycles();
+
+    let mut group = c.benchmark_group("nestest");
+    group.throughput(Throughput::Elements(cycles));
+
+    group.bench_function("concrete", |b| {
+        b.iter(|| {
+            cpu.load_state(&state).unwrap();
+            cpu.run_instructions(steps)
+        })
+    });
+
+    let mut cpu = Cpu::new(Box::new(Bus::new(cart()).unwrap()));
+    group.bench_function("dyn", |b| {
+        b.iter(|| {
+            cpu.load_state(&state).unwrap();
+            cpu.run_instructions(steps)
+        })
+    });
+
+    group.finish();
+}
+
+criterion_group!(benches, alu, copy, branch, nestest);
+criterion_main!(benches);
@@ -0,0 +1,48 @@
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use nes_cpu::cpu::Cpu;
+use nes_cpu::memory::FlatMemory;
+
+const STEPS: u64 = 100_000;
+
+// $8000: LDX #0
+// $8002: LDA $0200,X; ADC #1; STA $0300,X; INX; BNE $8002
+// $800C: JMP $8000
+const PROGRAM: [u8; 16] = [
+    0xA2, 0x00, 0xBD, 0x00, 0x02, 0x69, 0x01, 0x9D, 0x00, 0x03, 0xE8, 0xD0, 0xF5, 0x4C, 0x00, 0x80,
+];
+
+fn memory() -> FlatMemory {
+    let mut mem = FlatMemory::new();
+    mem.load(0x8000, &PROGRAM);
+    mem
+}
+
+// Throughput is instructions per second
+fn step(c: &mut Criterion) {
+    let mut group = c.benchmark_group("step");
+    group.throughput(Throughput::Elements(STEPS));
+
+    let mut cpu = Cpu::new(Box::new(memory()));
+    cpu.reg_mut().pc = 0x8000;
+    group.bench_function("dyn", |b| {
+        b.iter(|| {
+            for _ in 0..STEPS {
+                cpu.step();
+            }
+        })
+    });
+
+    let mut cpu = Cpu::with_memory(memory());
+    cpu.reg_mut().pc = 0x8000;
+    group.bench_function("concrete", |b| {
+        b.iter(|| {
+            for _ in 0..STEPS {
+                cpu.step();
+            }
+        })
+    });
+    group.finish();
+}
+
+criterion_group!(benches, step);
+criterion_main!(benches);
@@ -0,0 +1,451 @@
+//! Provide APU: two pulse channels, triangle, noise, DMC and the frame counter
+//!
+//! APU is clocked every cpu cycle with `Apu::step`. Output of the channels is
+//! mixed with the nonlinear mixer of the real chip, averaged down to the sample
+//! rate and filtered like the audio path of the console (high-pass at 90Hz and
+//! 440Hz, low-pass at 14kHz).
+//!
+//! DMC reads its samples through the owner of the APU, see `Apu::run`.
+//! Frame counter and DMC interrupts are output by `Apu::irq_line`.
+
+mod units;
+mod pulse;
+mod triangle;
+mod noise;
+mod dmc;
+
+use std::cell::Cell;
+
+use crate::region::Region;
+use crate::state::{StateError, StateReader, StateWriter};
+use dmc::Dmc;
+use noise::Noise;
+use pulse::Pulse;
+use triangle::Triangle;
+
+pub const DEFAULT_SAMPLE_RATE: u32 = 44_100;
+
+/// Write to an APU register, with the cpu cycle it was done
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ApuWrite {
+    pub cycle: u64,
+    pub addr: u16,
+    pub value: u8,
+}
+
+/// Cycles the cpu is stopped by DMC sample read
+pub const DMC_DMA_CYCLES: u64 = 4;
+
+// Frame counter steps in cpu cycles, for 4-step and 5-step mode. Quarter frame is
+// clocked at the first 4 steps, half frame at the 2nd and 4th one, and the
+// sequence restarts at the last one.
+const NTSC_STEPS: [[u32; 5]; 2] = [
+    [7457, 14913, 22371, 29829, 29830],
+    [7457, 14913, 22371, 37281, 37282],
+];
+const PAL_STEPS: [[u32; 5]; 2] = [
+    [8313, 16627, 24939, 33253, 33254],
+    [8313, 16627, 24939, 41565, 41566],
+];
+
+// First-order filter of the output
+struct Filter {
+    high_pass: bool,
+    alpha: f32,
+    input: f32,
+    output: f32,
+}
+
+impl Filter {
+    fn new(high_pass: bool, cutoff: f32, sample_rate: u32) -> Filter {
+        let rc    = 1.0 / (2.0 * std::f32::consts::PI * cutoff);
+        let dt    = 1.0 / sample_rate as f32;
+        let alpha = if high_pass { rc / (rc + dt) } else { dt / (rc + dt) };
+        Filter { high_pass, alpha, input: 0.0, output: 0.0 }
+    }
+
+    fn apply(&mut self, x: f32) -> f32 {
+        self.output = if self.high_pass {
+            self.alpha * (self.output + x - self.input)
+        } else {
+            self.output + self.alpha * (x - self.output)
+        };
+        self.input = x;
+        self.output
+    }
+}
+
+pub struct Apu {
+    region: Region,
+    pulse1: Pulse,
+    pulse2: Pulse,
+    triangle: Triangle,
+    noise: Noise,
+    dmc: Dmc,
+
+    five_step: bool,
+    irq_inhibit: bool,
+    // Cleared by reading $4015 through `&self`
+    frame_irq: Cell<bool>,
+    frame_cycle: u32,
+    cycle: u64,
+
+    pulse_table: [f32; 31],
+    tnd_table: [f32; 203],
+    sample_rate: u32,
+    cycles_per_sample: f64,
+    sample_clock: f64,
+    sum: f32,
+    count: u32,
+    filters: Vec<Filter>,
+    samples: Vec<f32>,
+}
+
+impl Apu {
+    pub fn new(region: Region) -> Apu {
+        let mut apu = Apu {
+            region,
+            pulse1: Pulse::new(false),
+            pulse2: Pulse::new(true),
+            triangle: Triangle::default(),
+            noise: Noise::new(region),
+            dmc: Dmc::new(region),
+            five_step: false,
+            irq_inhibit: false,
+            frame_irq: Cell::new(false),
+            frame_cycle: 0,
+            cycle: 0,
+            pulse_table: std::array::from_fn(|n| if n == 0 { 0.0 } else { 95.52 / (8128.0 / n as f32 + 100.0) }),
+            tnd_table: std::array::from_fn(|n| if n == 0 { 0.0 } else { 163.67 / (24329.0 / n as f32 + 100.0) }),
+            sample_rate: 0,
+            cycles_per_sample: 0.0,
+            sample_clock: 0.0,
+            sum: 0.0,
+            count: 0,
+            filters: Vec::new(),
+            samples: Vec::new(),
+        };
+        apu.set_sample_rate(DEFAULT_SAMPLE_RATE);
+        apu
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn sample_rate(&self) -> u32 {
+        self.sample_rate
+    }
+
+    /// Set rate of output samples in Hz. 0 disables output.
+    pub fn set_sample_rate(&mut self, rate: u32) {
+        self.sample_rate = rate;
+        self.sample_clock = 0.0;
+        self.sum   = 0.0;
+        self.count = 0;
+        if rate == 0 {
+            self.filters.clear();
+            return;
+        }
+        self.cycles_per_sample = self.region.cpu_frequency() / rate as f64;
+        let nyquist = rate as f32 / 2.0;
+        self.filters = vec![
+            Filter::new(true, 90.0, rate),
+            Filter::new(true, 440.0, rate),
+            Filter::new(false, 14_000.0f32.min(nyquist * 0.9), rate),
+        ];
+    }
+
+    /// Take samples output since last call, in range about -1.0 to 1.0
+    pub fn take_samples(&mut self) -> Vec<f32> {
+        std::mem::take(&mut self.samples)
+    }
+
+    /// Level of IRQ output: frame counter or DMC interrupt is pending
+    pub fn irq_line(&self) -> bool {
+        self.frame_irq.get() || self.dmc.irq
+    }
+
+    /// Address of DMC sample byte to be read. Owner of the APU reads it from the cpu
+    /// bus and gives it to `dmc_fill`, stopping the cpu for the read.
+    pub fn dmc_request(&self) -> Option<u16> {
+        self.dmc.request()
+    }
+
+    pub fn dmc_fill(&mut self, value: u8) {
+        self.dmc.fill(value);
+    }
+
+    /// Run given cpu cycles. DMC sample bytes are read by `read` at the cycle they
+    /// are fetched. Return cycles the cpu must be stopped for the reads.
+    pub fn run(&mut self, cycles: u64, mut read: impl FnMut(u16) -> u8) -> u64 {
+        let mut stall = 0;
+        for _ in 0..cycles {
+            self.step();
+            if let Some(addr) = self.dmc_request() {
+                let value = read(addr);
+                self.dmc_fill(value);
+                stall += DMC_DMA_CYCLES;
+            }
+        }
+        stall
+    }
+
+    /// Read $4015
+    pub fn read_status(&self) -> u8 {
+        let status = self.peek_status();
+        self.frame_irq.set(false);
+        status
+    }
+
+    /// Value of $4015 without clearing the frame interrupt flag
+    pub fn peek_status(&self) -> u8 {
+        let mut status = 0;
+        for (bit, active) in [
+            self.pulse1.length.active(),
+            self.pulse2.length.active(),
+            self.triangle.length.active(),
+            self.noise.length.active(),
+            self.dmc.active(),
+        ].into_iter().enumerate() {
+            status |= (active as u8) << bit;
+        }
+        status |= (self.frame_irq.get() as u8) << 6;
+        status |= (self.dmc.irq as u8) << 7;
+        status
+    }
+
+    /// Write register of $4000-$4013, $4015 and $4017
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x4000..=0x4003 => self.pulse1.write(addr, value),
+            0x4004..=0x4007 => self.pulse2.write(addr, value),
+            0x4008..=0x400B => self.triangle.write(addr, value),
+            0x400C..=0x400F => self.noise.write(addr, value),
+            0x4010..=0x4013 => self.dmc.write(addr, value),
+            0x4015 => {
+                self.pulse1.length.set_enabled(value & 0x01 != 0);
+                self.pulse2.length.set_enabled(value & 0x02 != 0);
+                self.triangle.length.set_enabled(value & 0x04 != 0);
+                self.noise.length.set_enabled(value & 0x08 != 0);
+                self.dmc.set_enabled(value & 0x10 != 0);
+            }
+            0x4017 => {
+                self.five_step   = value & 0x80 != 0;
+                self.irq_inhibit = value & 0x40 != 0;
+                if self.irq_inhibit {
+                    self.frame_irq.set(false);
+                }
+                self.frame_cycle = 0;
+                if self.five_step {
+                    self.quarter_frame();
+                    self.half_frame();
+                }
+            }
+            _ => (),
+        }
+    }
+
+    /// Run one cpu cycle
+    pub fn step(&mut self) {
+        self.clock_frame_counter();
+
+        self.triangle.clock_timer();
+        self.noise.clock_timer();
+        self.dmc.clock_timer();
+        if self.cycle & 1 == 1 {
+            self.pulse1.clock_timer();
+            self.pulse2.clock_timer();
+        }
+        self.cycle += 1;
+
+        if self.sample_rate > 0 {
+            self.sum   += self.output();
+            self.count += 1;
+            self.sample_clock += 1.0;
+            if self.sample_clock >= self.cycles_per_sample {
+                self.sample_clock -= self.cycles_per_sample;
+                let mut sample = self.sum / self.count as f32;
+                for filter in &mut self.filters {
+                    sample = filter.apply(sample);
+                }
+                self.samples.push(sample);
+                self.sum   = 0.0;
+                self.count = 0;
+            }
+        }
+    }
+
+    /// Mixed output of the channels before filters, 0.0 to 1.0
+    pub fn output(&self) -> f32 {
+        let pulse = self.pulse1.output() + self.pulse2.output();
+        let tnd   = 3 * self.triangle.output() as usize + 2 * self.noise.output() as usize + self.dmc.output() as usize;
+        self.pulse_table[pulse as usize] + self.tnd_table[tnd]
+    }
+
+    fn frame_steps(&self) -> &'static [u32; 5] {
+        let steps = if self.region == Region::Pal { &PAL_STEPS } else { &NTSC_STEPS };
+        &steps[self.five_step as usize]
+    }
+
+    fn clock_frame_counter(&mut self) {
+        let steps = self.frame_steps();
+
+        self.frame_cycle += 1;
+        match steps.iter().position(|&s| s == self.frame_cycle) {
+            Some(0) | Some(2) => self.quarter_frame(),
+            Some(1) => {
+                self.quarter_frame();
+                self.half_frame();
+            }
+            Some(3) => {
+                self.quarter_frame();
+                self.half_frame();
+                if !self.five_step && !self.irq_inhibit {
+                    self.frame_irq.set(true);
+                }
+            }
+            Some(_) => self.frame_cycle = 0,
+            None => (),
+        }
+    }
+
+    fn quarter_frame(&mut self) {
+        self.pulse1.envelope.clock();
+        self.pulse2.envelope.clock();
+        self.triangle.clock_linear();
+        self.noise.envelope.clock();
+    }
+
+    fn half_frame(&mut self) {
+        self.pulse1.length.clock();
+        self.pulse2.length.clock();
+        self.triangle.length.clock();
+        self.noise.length.clock();
+        self.pulse1.clock_sweep();
+        self.pulse2.clock_sweep();
+    }
+
+    /// Save state of the channels and frame counter. Samples not taken are not saved.
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.section(*b"APU ", 1, |out| {
+            self.pulse1.save_state(out);
+            self.pulse2.save_state(out);
+            self.triangle.save_state(out);
+            self.noise.save_state(out);
+            self.dmc.save_state(out);
+            out.write_bool(self.five_step);
+            out.write_bool(self.irq_inhibit);
+            out.write_bool(self.frame_irq.get());
+            out.write_u32(self.frame_cycle);
+            out.write_u64(self.cycle);
+        });
+    }
+
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let (_, mut apu) = input.section(*b"APU ", 1)?;
+        self.pulse1.load_state(&mut apu)?;
+        self.pulse2.load_state(&mut apu)?;
+        self.triangle.load_state(&mut apu)?;
+        self.noise.load_state(&mut apu)?;
+        self.dmc.load_state(&mut apu)?;
+        self.five_step   = apu.read_bool()?;
+        self.irq_inhibit = apu.read_bool()?;
+        self.frame_irq.set(apu.read_bool()?);
+        self.frame_cycle = apu.read_u32()?;
+        self.cycle       = apu.read_u64()?;
+        if self.frame_cycle >= self.frame_steps()[4] {
+            return Err(StateError::InvalidData(format!("frame counter at cycle {}", self.frame_cycle)));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn run(apu: &mut Apu, cycles: u32) {
+        for _ in 0..cycles {
+            apu.step();
+            if let Some(addr) = apu.dmc_request() {
+                apu.dmc_fill(addr as u8);
+            }
+        }
+    }
+
+    #[test]
+    fn test_frame_counter() {
+        let mut apu = Apu::new(Region::Ntsc);
+        // Pulse 1 with length index 1 (254) and halt clear
+        apu.write_register(0x4015, 0x01);
+        apu.write_register(0x4000, 0x10);
+        apu.write_register(0x4003, 0x08);
+        assert_eq!(apu.read_status() & 0x01, 0x01);
+
+        run(&mut apu, 29828);
+        assert!(!apu.irq_line());
+        run(&mut apu, 1);
+        assert!(apu.irq_line());
+        assert_eq!(apu.read_status(), 0x41);
+        assert!(!apu.irq_line());
+
+        // 2 half frames per sequence
+        run(&mut apu, 29830 * 125 + 1);
+        assert_eq!(apu.read_status() & 0x01, 0x01);
+        run(&mut apu, 29830);
+        assert_eq!(apu.read_status() & 0x01, 0x00);
+
+        // No IRQ in 5-step mode
+        apu.write_register(0x4017, 0x80);
+        run(&mut apu, 40000);
+        assert!(!apu.irq_line());
+    }
+
+    #[test]
+    fn test_output() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.set_sample_rate(48_000);
+        // 440Hz square at constant volume 15, and DMC sample with IRQ
+        apu.write_register(0x4015, 0x11);
+        apu.write_register(0x4000, 0xBF);
+        apu.write_register(0x4002, 253);
+        apu.write_register(0x4003, 0xF8);
+        apu.write_register(0x4010, 0x8F);
+        apu.write_register(0x4013, 0x01);
+        apu.write_register(0x4015, 0x11);
+
+        run(&mut apu, 1_789_773 / 10);
+        let samples = apu.take_samples();
+        assert!((4799..=4801).contains(&samples.len()));
+        assert!(apu.take_samples().is_empty());
+        assert!(apu.irq_line());
+
+        // Count rising edges over the last 1/20 second, after the filters settled
+        let tail  = &samples[2400..];
+        let edges = tail.windows(2).filter(|w| w[0] < 0.0 && w[1] >= 0.0).count();
+        assert!((21..=23).contains(&edges), "{} edges", edges);
+        assert!(tail.iter().all(|s| s.abs() < 1.0));
+    }
+
+    #[test]
+    fn test_load_invalid_state() {
+        let mut apu = Apu::new(Region::Ntsc);
+        apu.set_sample_rate(48_000);
+        let mut out = StateWriter::new();
+        apu.save_state(&mut out);
+        // Every value after the tag, version and length is out of range
+        let mut state = out.into_bytes();
+        state[10..].fill(0xFF);
+
+        let error = apu.load_state(&mut StateReader::new(&state)).unwrap_err();
+        assert_eq!(error, StateError::InvalidData("frame counter at cycle 4294967295".to_string()));
+
+        // Frame counter and cycle at 0
+        let len = state.len();
+        state[len - 12..].fill(0);
+        apu.load_state(&mut StateReader::new(&state)).unwrap();
+        run(&mut apu, 10_000);
+        assert!(apu.output() <= 1.0);
+    }
+}
@@ -0,0 +1,175 @@
+//! Delta modulation channel ($4010-$4013)
+//!
+//! Sample bytes are read from the cpu bus by the owner of the APU: it reads the
+//! address given by `Dmc::request` and passes the byte to `Dmc::fill`.
+
+use crate::region::Region;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub(super) struct Dmc {
+    rates: &'static [u16; 16],
+    irq_enabled: bool,
+    pub(super) irq: bool,
+    looping: bool,
+    rate: u16,
+    timer: u16,
+    level: u8,
+    sample_addr: u16,
+    sample_len: u16,
+    addr: u16,
+    remaining: u16,
+    buffer: Option<u8>,
+    shift: u8,
+    bits: u8,
+    silence: bool,
+}
+
+impl Dmc {
+    pub(super) fn new(region: Region) -> Dmc {
+        let rates = region.dmc_rates();
+        Dmc {
+            rates,
+            irq_enabled: false,
+            irq: false,
+            looping: false,
+            rate: rates[0],
+            timer: 0,
+            level: 0,
+            sample_addr: 0xC000,
+            sample_len: 1,
+            addr: 0xC000,
+            remaining: 0,
+            buffer: None,
+            shift: 0,
+            bits: 8,
+            silence: true,
+        }
+    }
+
+    pub(super) fn write(&mut self, reg: u16, value: u8) {
+        match reg & 3 {
+            0 => {
+                self.irq_enabled = value & 0x80 != 0;
+                self.looping     = value & 0x40 != 0;
+                self.rate        = self.rates[value as usize & 0x0F];
+                if !self.irq_enabled {
+                    self.irq = false;
+                }
+            }
+            1 => self.level = value & 0x7F,
+            2 => self.sample_addr = 0xC000 | ((value as u16) << 6),
+            _ => self.sample_len  = ((value as u16) << 4) + 1,
+        }
+    }
+
+    /// Set by $4015. Sample is started if it is not playing.
+    pub(super) fn set_enabled(&mut self, enabled: bool) {
+        self.irq = false;
+        if !enabled {
+            self.remaining = 0;
+        } else if self.remaining == 0 {
+            self.restart();
+        }
+    }
+
+    pub(super) fn active(&self) -> bool {
+        self.remaining > 0
+    }
+
+    fn restart(&mut self) {
+        self.addr      = self.sample_addr;
+        self.remaining = self.sample_len;
+    }
+
+    /// Address of the sample byte to be read, if the buffer is empty
+    pub(super) fn request(&self) -> Option<u16> {
+        if self.buffer.is_none() && self.remaining > 0 { Some(self.addr) } else { None }
+    }
+
+    /// Give the byte read at the requested address
+    pub(super) fn fill(&mut self, value: u8) {
+        self.buffer    = Some(value);
+        self.addr      = self.addr.checked_add(1).unwrap_or(0x8000);
+        self.remaining -= 1;
+        if self.remaining == 0 {
+            if self.looping {
+                self.restart();
+            } else if self.irq_enabled {
+                self.irq = true;
+            }
+        }
+    }
+
+    /// Clocked every cpu cycle
+    pub(super) fn clock_timer(&mut self) {
+        if self.timer > 0 {
+            self.timer -= 1;
+            return;
+        }
+        self.timer = self.rate - 1;
+
+        if !self.silence {
+            if self.shift & 1 != 0 {
+                if self.level <= 125 {
+                    self.level += 2;
+                }
+            } else if self.level >= 2 {
+                self.level -= 2;
+            }
+        }
+        self.shift >>= 1;
+        self.bits   -= 1;
+        if self.bits == 0 {
+            self.bits = 8;
+            match self.buffer.take() {
+                Some(value) => {
+                    self.silence = false;
+                    self.shift   = value;
+                }
+                None => self.silence = true,
+            }
+        }
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        self.level
+    }
+
+    pub(super) fn save_state(&self, out: &mut StateWriter) {
+        out.write_bool(self.irq_enabled);
+        out.write_bool(self.irq);
+        out.write_bool(self.looping);
+        out.write_u16(self.rate);
+        out.write_u16(self.timer);
+        out.write_u8(self.level);
+        out.write_u16(self.sample_addr);
+        out.write_u16(self.sample_len);
+        out.write_u16(self.addr);
+        out.write_u16(self.remaining);
+        out.write_bool(self.buffer.is_some());
+        out.write_u8(self.buffer.unwrap_or(0));
+        out.write_u8(self.shift);
+        out.write_u8(self.bits);
+        out.write_bool(self.silence);
+    }
+
+    pub(super) fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.irq_enabled = input.read_bool()?;
+        self.irq         = input.read_bool()?;
+        self.looping     = input.read_bool()?;
+        self.rate        = input.read_u16()?.max(1);
+        self.timer       = input.read_u16()?;
+        self.level       = input.read_u8()? & 0x7F;
+        self.sample_addr = input.read_u16()?;
+        self.sample_len  = input.read_u16()?;
+        self.addr        = input.read_u16()?;
+        self.remaining   = input.read_u16()?;
+        let buffered     = input.read_bool()?;
+        let buffer       = input.read_u8()?;
+        self.buffer      = buffered.then_some(buffer);
+        self.shift       = input.read_u8()?;
+        self.bits        = input.read_u8()?.clamp(1, 8);
+        self.silence     = input.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,91 @@
+//! Noise channel ($400C-$400F)
+
+use super::units::{Envelope, LengthCounter};
+use crate::region::Region;
+use crate::state::{StateError, StateReader, StateWriter};
+
+// Periods in cpu cycles
+const NTSC_PERIODS: [u16; 16] = [
+    4, 8, 16, 32, 64, 96, 128, 160, 202, 254, 380, 508, 762, 1016, 2034, 4068,
+];
+const PAL_PERIODS: [u16; 16] = [
+    4, 8, 14, 30, 60, 88, 118, 148, 188, 236, 354, 472, 708, 944, 1890, 3778,
+];
+
+pub(super) struct Noise {
+    periods: &'static [u16; 16],
+    // Short mode takes feedback from bit 6 instead of bit 1
+    short: bool,
+    period: u16,
+    timer: u16,
+    shift: u16,
+    pub(super) envelope: Envelope,
+    pub(super) length: LengthCounter,
+}
+
+impl Noise {
+    pub(super) fn new(region: Region) -> Noise {
+        let periods = if region == Region::Pal { &PAL_PERIODS } else { &NTSC_PERIODS };
+        Noise {
+            periods,
+            short: false,
+            period: periods[0],
+            timer: 0,
+            shift: 1,
+            envelope: Envelope::default(),
+            length: LengthCounter::default(),
+        }
+    }
+
+    pub(super) fn write(&mut self, reg: u16, value: u8) {
+        match reg & 3 {
+            0 => {
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => (),
+            2 => {
+                self.short  = value & 0x80 != 0;
+                self.period = self.periods[value as usize & 0x0F];
+            }
+            _ => {
+                self.length.load(value >> 3);
+                self.envelope.restart();
+            }
+        }
+    }
+
+    /// Clocked every cpu cycle
+    pub(super) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period - 1;
+            let tap      = if self.short { 6 } else { 1 };
+            let feedback = (self.shift ^ (self.shift >> tap)) & 1;
+            self.shift   = (self.shift >> 1) | (feedback << 14);
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if self.shift & 1 != 0 || !self.length.active() { 0 } else { self.envelope.output() }
+    }
+
+    pub(super) fn save_state(&self, out: &mut StateWriter) {
+        out.write_bool(self.short);
+        out.write_u16(self.period);
+        out.write_u16(self.timer);
+        out.write_u16(self.shift);
+        self.envelope.save_state(out);
+        self.length.save_state(out);
+    }
+
+    pub(super) fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.short  = input.read_bool()?;
+        self.period = input.read_u16()?.max(1);
+        self.timer  = input.read_u16()?;
+        self.shift  = input.read_u16()?;
+        self.envelope.load_state(input)?;
+        self.length.load_state(input)
+    }
+}
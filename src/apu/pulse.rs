@@ -0,0 +1,136 @@
+//! Pulse channel ($4000-$4003, $4004-$4007)
+
+use super::units::{Envelope, LengthCounter};
+use crate::state::{StateError, StateReader, StateWriter};
+
+const DUTY: [[u8; 8]; 4] = [
+    [0, 1, 0, 0, 0, 0, 0, 0],
+    [0, 1, 1, 0, 0, 0, 0, 0],
+    [0, 1, 1, 1, 1, 0, 0, 0],
+    [1, 0, 0, 1, 1, 1, 1, 1],
+];
+
+#[derive(Default)]
+pub(super) struct Pulse {
+    // Second channel negates sweep with two's complement, first one with ones' complement
+    second: bool,
+    duty: u8,
+    step: u8,
+    timer: u16,
+    period: u16,
+    pub(super) envelope: Envelope,
+    pub(super) length: LengthCounter,
+    sweep_enabled: bool,
+    sweep_period: u8,
+    sweep_negate: bool,
+    sweep_shift: u8,
+    sweep_divider: u8,
+    sweep_reload: bool,
+}
+
+impl Pulse {
+    pub(super) fn new(second: bool) -> Pulse {
+        Pulse { second, ..Pulse::default() }
+    }
+
+    pub(super) fn write(&mut self, reg: u16, value: u8) {
+        match reg & 3 {
+            0 => {
+                self.duty        = value >> 6;
+                self.length.halt = value & 0x20 != 0;
+                self.envelope.write(value);
+            }
+            1 => {
+                self.sweep_enabled = value & 0x80 != 0;
+                self.sweep_period  = (value >> 4) & 0x07;
+                self.sweep_negate  = value & 0x08 != 0;
+                self.sweep_shift   = value & 0x07;
+                self.sweep_reload  = true;
+            }
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.step = 0;
+                self.envelope.restart();
+            }
+        }
+    }
+
+    /// Clocked every APU cycle (2 cpu cycles)
+    pub(super) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            self.step  = (self.step + 1) & 7;
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocked by half frame
+    pub(super) fn clock_sweep(&mut self) {
+        if self.sweep_divider == 0 && self.sweep_enabled && self.sweep_shift > 0 && !self.muted() {
+            self.period = self.target_period();
+        }
+        if self.sweep_divider == 0 || self.sweep_reload {
+            self.sweep_divider = self.sweep_period;
+            self.sweep_reload  = false;
+        } else {
+            self.sweep_divider -= 1;
+        }
+    }
+
+    fn target_period(&self) -> u16 {
+        let change = self.period >> self.sweep_shift;
+        if self.sweep_negate {
+            self.period.saturating_sub(change + !self.second as u16)
+        } else {
+            self.period + change
+        }
+    }
+
+    // Muted by sweep unit even if sweep is disabled
+    fn muted(&self) -> bool {
+        self.period < 8 || self.target_period() > 0x07FF
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if !self.length.active() || self.muted() || DUTY[self.duty as usize][self.step as usize] == 0 {
+            0
+        } else {
+            self.envelope.output()
+        }
+    }
+
+    pub(super) fn save_state(&self, out: &mut StateWriter) {
+        out.write_u8(self.duty);
+        out.write_u8(self.step);
+        out.write_u16(self.timer);
+        out.write_u16(self.period);
+        self.envelope.save_state(out);
+        self.length.save_state(out);
+        out.write_bool(self.sweep_enabled);
+        out.write_u8(self.sweep_period);
+        out.write_bool(self.sweep_negate);
+        out.write_u8(self.sweep_shift);
+        out.write_u8(self.sweep_divider);
+        out.write_bool(self.sweep_reload);
+    }
+
+    pub(super) fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        // Masked like the registers, since they index tables
+        self.duty   = input.read_u8()? & 0x03;
+        self.step   = input.read_u8()? & 0x07;
+        self.timer  = input.read_u16()?;
+        self.period = input.read_u16()?;
+        self.envelope.load_state(input)?;
+        self.length.load_state(input)?;
+        self.sweep_enabled = input.read_bool()?;
+        self.sweep_period  = input.read_u8()? & 0x07;
+        self.sweep_negate  = input.read_bool()?;
+        self.sweep_shift   = input.read_u8()? & 0x07;
+        self.sweep_divider = input.read_u8()?;
+        self.sweep_reload  = input.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,86 @@
+//! Triangle channel ($4008-$400B)
+
+use super::units::LengthCounter;
+use crate::state::{StateError, StateReader, StateWriter};
+
+#[derive(Default)]
+pub(super) struct Triangle {
+    // Also halt flag of length counter
+    control: bool,
+    linear_reload: u8,
+    linear: u8,
+    reload: bool,
+    period: u16,
+    timer: u16,
+    step: u8,
+    pub(super) length: LengthCounter,
+}
+
+impl Triangle {
+    pub(super) fn write(&mut self, reg: u16, value: u8) {
+        match reg & 3 {
+            0 => {
+                self.control       = value & 0x80 != 0;
+                self.length.halt   = self.control;
+                self.linear_reload = value & 0x7F;
+            }
+            1 => (),
+            2 => self.period = (self.period & 0x0700) | value as u16,
+            _ => {
+                self.period = (self.period & 0x00FF) | ((value as u16 & 0x07) << 8);
+                self.length.load(value >> 3);
+                self.reload = true;
+            }
+        }
+    }
+
+    /// Clocked every cpu cycle. Sequence stops while either counter is 0.
+    pub(super) fn clock_timer(&mut self) {
+        if self.timer == 0 {
+            self.timer = self.period;
+            if self.length.active() && self.linear > 0 {
+                self.step = (self.step + 1) & 31;
+            }
+        } else {
+            self.timer -= 1;
+        }
+    }
+
+    /// Clocked by quarter frame
+    pub(super) fn clock_linear(&mut self) {
+        if self.reload {
+            self.linear = self.linear_reload;
+        } else if self.linear > 0 {
+            self.linear -= 1;
+        }
+        if !self.control {
+            self.reload = false;
+        }
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if self.step < 16 { 15 - self.step } else { self.step - 16 }
+    }
+
+    pub(super) fn save_state(&self, out: &mut StateWriter) {
+        out.write_bool(self.control);
+        out.write_u8(self.linear_reload);
+        out.write_u8(self.linear);
+        out.write_bool(self.reload);
+        out.write_u16(self.period);
+        out.write_u16(self.timer);
+        out.write_u8(self.step);
+        self.length.save_state(out);
+    }
+
+    pub(super) fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.control       = input.read_bool()?;
+        self.linear_reload = input.read_u8()? & 0x7F;
+        self.linear        = input.read_u8()? & 0x7F;
+        self.reload        = input.read_bool()?;
+        self.period        = input.read_u16()?;
+        self.timer         = input.read_u16()?;
+        self.step          = input.read_u8()? & 0x1F;
+        self.length.load_state(input)
+    }
+}
@@ -0,0 +1,121 @@
+//! Units shared by the channels
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+const LENGTH_TABLE: [u8; 32] = [
+    10, 254, 20,  2, 40,  4, 80,  6, 160,  8, 60, 10, 14, 12, 26, 14,
+    12,  16, 24, 18, 48, 20, 96, 22, 192, 24, 72, 26, 16, 28, 32, 30,
+];
+
+/// Volume envelope of pulse and noise
+#[derive(Default)]
+pub(super) struct Envelope {
+    start: bool,
+    looping: bool,
+    constant: bool,
+    volume: u8,
+    divider: u8,
+    decay: u8,
+}
+
+impl Envelope {
+    pub(super) fn write(&mut self, value: u8) {
+        self.looping  = value & 0x20 != 0;
+        self.constant = value & 0x10 != 0;
+        self.volume   = value & 0x0F;
+    }
+
+    pub(super) fn restart(&mut self) {
+        self.start = true;
+    }
+
+    /// Clocked by quarter frame
+    pub(super) fn clock(&mut self) {
+        if self.start {
+            self.start   = false;
+            self.decay   = 15;
+            self.divider = self.volume;
+        } else if self.divider == 0 {
+            self.divider = self.volume;
+            if self.decay > 0 {
+                self.decay -= 1;
+            } else if self.looping {
+                self.decay = 15;
+            }
+        } else {
+            self.divider -= 1;
+        }
+    }
+
+    pub(super) fn output(&self) -> u8 {
+        if self.constant { self.volume } else { self.decay }
+    }
+
+    pub(super) fn save_state(&self, out: &mut StateWriter) {
+        for value in [self.start, self.looping, self.constant] {
+            out.write_bool(value);
+        }
+        for value in [self.volume, self.divider, self.decay] {
+            out.write_u8(value);
+        }
+    }
+
+    pub(super) fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.start    = input.read_bool()?;
+        self.looping  = input.read_bool()?;
+        self.constant = input.read_bool()?;
+        self.volume   = input.read_u8()? & 0x0F;
+        self.divider  = input.read_u8()? & 0x0F;
+        self.decay    = input.read_u8()? & 0x0F;
+        Ok(())
+    }
+}
+
+/// Length counter that silences the channel when it reaches 0
+#[derive(Default)]
+pub(super) struct LengthCounter {
+    enabled: bool,
+    pub(super) halt: bool,
+    count: u8,
+}
+
+impl LengthCounter {
+    /// Set by $4015. Counter is cleared when disabled.
+    pub(super) fn set_enabled(&mut self, enabled: bool) {
+        self.enabled = enabled;
+        if !enabled {
+            self.count = 0;
+        }
+    }
+
+    /// Load from index written in bit 3-7 of the last register
+    pub(super) fn load(&mut self, index: u8) {
+        if self.enabled {
+            self.count = LENGTH_TABLE[index as usize & 0x1F];
+        }
+    }
+
+    /// Clocked by half frame
+    pub(super) fn clock(&mut self) {
+        if !self.halt && self.count > 0 {
+            self.count -= 1;
+        }
+    }
+
+    pub(super) fn active(&self) -> bool {
+        self.count > 0
+    }
+
+    pub(super) fn save_state(&self, out: &mut StateWriter) {
+        out.write_bool(self.enabled);
+        out.write_bool(self.halt);
+        out.write_u8(self.count);
+    }
+
+    pub(super) fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.enabled = input.read_bool()?;
+        self.halt    = input.read_bool()?;
+        self.count   = input.read_u8()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,210 @@
+//! Provide recorder of APU register writes, and export of them as VGM and CSV
+//!
+//! `ApuRecorder` is cpu hooks that record every write to $4000-$4013, $4015 and
+//! $4017 with the cycle counter of the cpu. It is given to `Nes` or `NsfPlayer`. Writes are collected to `ApuLog`,
+//! which is written as VGM 1.61 with the NES APU command (0xB4) or as CSV.
+
+use std::fmt::Write as _;
+use std::io;
+use std::path::Path;
+
+use crate::apu::ApuWrite;
+use crate::cpu::{CpuHooks, InstructionEvent};
+use crate::register::Register;
+
+/// Sample rate of VGM timing
+pub const VGM_SAMPLE_RATE: u32 = 44_100;
+
+const VGM_VERSION: u32 = 0x0000_0161;
+const VGM_HEADER_SIZE: usize = 0xC0;
+
+/// Hooks that record writes to APU registers.
+/// Cycle of a write is the last cycle of its instruction, where stores and
+/// read-modify-write instructions write the value.
+#[derive(Debug, Default, Clone)]
+pub struct ApuRecorder {
+    cycles: u64,
+    writes: Vec<ApuWrite>,
+}
+
+impl ApuRecorder {
+    pub fn new() -> ApuRecorder {
+        ApuRecorder::default()
+    }
+
+    pub fn writes(&self) -> &[ApuWrite] {
+        &self.writes
+    }
+
+    /// Return writes recorded since last call
+    pub fn take_writes(&mut self) -> Vec<ApuWrite> {
+        std::mem::take(&mut self.writes)
+    }
+}
+
+impl CpuHooks for ApuRecorder {
+    fn before_instruction(&mut self, _: &Register, event: &InstructionEvent) {
+        self.cycles = event.cycles + event.info.cycle as u64 - 1;
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        if matches!(addr, 0x4000..=0x4013 | 0x4015 | 0x4017) {
+            self.writes.push(ApuWrite { cycle: self.cycles, addr, value });
+        }
+    }
+}
+
+/// Writes to APU registers from a start cycle, with the cpu frequency to time them
+#[derive(Debug, Clone)]
+pub struct ApuLog {
+    frequency: f64,
+    start: u64,
+    writes: Vec<ApuWrite>,
+    // Contents of $C000-$FFFF for DMC samples
+    dpcm: Option<Vec<u8>>,
+}
+
+impl ApuLog {
+    /// Log starting at given cpu cycle. Earlier writes are put at the start.
+    pub fn new(frequency: f64, start: u64) -> ApuLog {
+        ApuLog { frequency, start, writes: Vec::new(), dpcm: None }
+    }
+
+    pub fn writes(&self) -> &[ApuWrite] {
+        &self.writes
+    }
+
+    pub fn extend(&mut self, writes: impl IntoIterator<Item = ApuWrite>) {
+        self.writes.extend(writes);
+    }
+
+    /// Set memory of $C000-$FFFF to be read by DMC. It is written to VGM as
+    /// data block, since VGM players do not have cpu memory.
+    pub fn set_dpcm(&mut self, memory: Vec<u8>) {
+        self.dpcm = Some(memory);
+    }
+
+    /// Seconds from the start to the cycle
+    pub fn seconds(&self, cycle: u64) -> f64 {
+        cycle.saturating_sub(self.start) as f64 / self.frequency
+    }
+
+    fn samples(&self, cycle: u64) -> u32 {
+        (self.seconds(cycle) * VGM_SAMPLE_RATE as f64).round() as u32
+    }
+
+    /// Encode as VGM lasting until the end cycle
+    pub fn to_vgm(&self, end: u64) -> Vec<u8> {
+        let mut vgm = vec![0; VGM_HEADER_SIZE];
+
+        if let Some(dpcm) = &self.dpcm {
+            // Data block of NES APU RAM: start address and the data
+            vgm.extend_from_slice(&[0x67, 0x66, 0xC2]);
+            vgm.extend_from_slice(&(dpcm.len() as u32 + 2).to_le_bytes());
+            vgm.extend_from_slice(&0xC000u16.to_le_bytes());
+            vgm.extend_from_slice(dpcm);
+        }
+
+        let mut time = 0;
+        for write in &self.writes {
+            let at = self.samples(write.cycle);
+            wait(&mut vgm, at.saturating_sub(time));
+            time = time.max(at);
+            vgm.extend_from_slice(&[0xB4, (write.addr - 0x4000) as u8, write.value]);
+        }
+        let total = self.samples(end).max(time);
+        wait(&mut vgm, total - time);
+        vgm.push(0x66);
+
+        let eof = vgm.len() as u32 - 4;
+        vgm[0x00..0x04].copy_from_slice(b"Vgm ");
+        vgm[0x04..0x08].copy_from_slice(&eof.to_le_bytes());
+        vgm[0x08..0x0C].copy_from_slice(&VGM_VERSION.to_le_bytes());
+        vgm[0x18..0x1C].copy_from_slice(&total.to_le_bytes());
+        // Offset of the data, relative to this field
+        vgm[0x34..0x38].copy_from_slice(&(VGM_HEADER_SIZE as u32 - 0x34).to_le_bytes());
+        vgm[0x84..0x88].copy_from_slice(&(self.frequency.round() as u32).to_le_bytes());
+        vgm
+    }
+
+    /// Format as CSV of cycle, seconds from the start, register and value
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("cycle,seconds,register,value\n");
+        for write in &self.writes {
+            let seconds = self.seconds(write.cycle);
+            writeln!(csv, "{},{:.6},{:04X},{:02X}", write.cycle, seconds, write.addr, write.value).unwrap();
+        }
+        csv
+    }
+
+    pub fn write_vgm<P: AsRef<Path>>(&self, path: P, end: u64) -> io::Result<()> {
+        std::fs::write(path, self.to_vgm(end))
+    }
+
+    pub fn write_csv<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        std::fs::write(path, self.to_csv())
+    }
+}
+
+// Wait command with the shortest encoding
+fn wait(vgm: &mut Vec<u8>, mut samples: u32) {
+    while samples > 0 {
+        let n = samples.min(0xFFFF);
+        match n {
+            735 => vgm.push(0x62),
+            882 => vgm.push(0x63),
+            1..=16 => vgm.push(0x70 + n as u8 - 1),
+            _ => {
+                vgm.push(0x61);
+                vgm.extend_from_slice(&(n as u16).to_le_bytes());
+            }
+        }
+        samples -= n;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::memory::FlatMemory;
+
+    #[test]
+    fn test_recorder() {
+        // LDA #$3F; STA $4000; STA $0200; STA $4015
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0xA9, 0x3F, 0x8D, 0x00, 0x40, 0x8D, 0x00, 0x02, 0x8D, 0x15, 0x40]);
+        let mut cpu = Cpu::with_hooks(mem, ApuRecorder::new());
+        cpu.reg_mut().pc = 0x8000;
+        cpu.run_instructions(4);
+
+        let writes = cpu.hooks_mut().take_writes();
+        assert_eq!(writes, [
+            ApuWrite { cycle: 5, addr: 0x4000, value: 0x3F },
+            ApuWrite { cycle: 13, addr: 0x4015, value: 0x3F },
+        ]);
+        assert!(cpu.hooks().writes().is_empty());
+    }
+
+    #[test]
+    fn test_vgm_and_csv() {
+        // 1 sample is 40.5 cycles at 1786050Hz
+        let mut log = ApuLog::new(1_786_050.0, 100);
+        log.extend([
+            ApuWrite { cycle: 100, addr: 0x4015, value: 0x01 },
+            ApuWrite { cycle: 100 + 81, addr: 0x4000, value: 0xBF },
+        ]);
+        let vgm = log.to_vgm(100 + 40_500 * 735 / 1000);
+
+        assert_eq!(&vgm[0..4], b"Vgm ");
+        assert_eq!(u32::from_le_bytes(vgm[4..8].try_into().unwrap()) as usize, vgm.len() - 4);
+        assert_eq!(u32::from_le_bytes(vgm[0x18..0x1C].try_into().unwrap()), 735);
+        assert_eq!(u32::from_le_bytes(vgm[0x84..0x88].try_into().unwrap()), 1_786_050);
+        assert_eq!(&vgm[VGM_HEADER_SIZE..], [
+            0xB4, 0x15, 0x01, 0x71, 0xB4, 0x00, 0xBF, 0x61, 0xDD, 0x02, 0x66,
+        ]);
+
+        let csv = log.to_csv();
+        assert_eq!(csv.lines().nth(2), Some("181,0.000045,4000,BF"));
+    }
+}
@@ -0,0 +1,16 @@
+//! Debug adapter speaking Debug Adapter Protocol over stdin and stdout
+
+use std::io;
+use std::process::ExitCode;
+
+use nes_cpu::dap::DapServer;
+
+fn main() -> ExitCode {
+    match DapServer::new().serve(io::stdin(), io::stdout()) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("nes_cpu-dap: {}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,87 @@
+//! Frame-hash regression runner
+//!
+//! Usage: nes_cpu-frames [-u] [-o png_dir] <manifest>
+//!
+//! Each test of the manifest (see `harness::frames`) is run and the hash of its
+//! last frame is compared with the expected one. Rom paths are relative to the
+//! manifest. With `-o`, the last frame of each failed test is saved as PNG in the
+//! directory. With `-u`, the manifest is printed with the actual hashes instead.
+//! Exit status is failure if any test failed.
+
+use std::path::{Path, PathBuf};
+use std::process::ExitCode;
+
+use nes_cpu::harness::frames::{frame_hash, parse_manifest};
+
+const USAGE: &str = "usage: nes_cpu-frames [-u] [-o png_dir] <manifest>";
+
+struct Options {
+    manifest: String,
+    update: bool,
+    png_dir: Option<PathBuf>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args     = std::env::args().skip(1);
+    let mut manifest = None;
+    let mut update   = false;
+    let mut png_dir  = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-u" => update = true,
+            "-o" => png_dir = Some(PathBuf::from(args.next().ok_or(USAGE)?)),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if manifest.is_none() => manifest = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    Ok(Options { manifest: manifest.ok_or(USAGE)?, update, png_dir })
+}
+
+// Return number of failed tests
+fn run(options: Options) -> Result<usize, String> {
+    let text = std::fs::read_to_string(&options.manifest)
+        .map_err(|e| format!("{}: {}", options.manifest, e))?;
+    let tests = parse_manifest(&text).map_err(|e| format!("{}: {}", options.manifest, e))?;
+    let base  = Path::new(&options.manifest).parent().unwrap_or(Path::new("."));
+
+    let mut failed = 0;
+    for mut expected in tests {
+        let test   = expected.run(base)?;
+        let actual = frame_hash(test.nes().frame());
+        if options.update {
+            expected.hash = Some(actual);
+            println!("{}", expected);
+            continue;
+        }
+        if expected.hash == Some(actual) {
+            println!("ok     {}", expected.rom.display());
+            continue;
+        }
+
+        failed += 1;
+        println!("FAILED {}: hash {:016x}", expected.rom.display(), actual);
+        if let Some(dir) = &options.png_dir {
+            let name = expected.rom.with_extension("png");
+            let path = dir.join(name.file_name().unwrap_or(name.as_os_str()));
+            test.save_png(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+            println!("       saved {}", path.display());
+        }
+    }
+    Ok(failed)
+}
+
+fn main() -> ExitCode {
+    match parse_args().and_then(run) {
+        Ok(0) => ExitCode::SUCCESS,
+        Ok(failed) => {
+            eprintln!("{} test(s) failed", failed);
+            ExitCode::FAILURE
+        }
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,106 @@
+//! Machine code monitor
+//!
+//! Usage: nes_cpu-mon [-l load_addr] [-s script] [--gdb port] <rom or binary>
+//!
+//! Commands in the script are executed first, then commands are read from
+//! stdin until `q` or end of input. With `--gdb`, GDB is waited on the local
+//! port instead, like `target remote :port`, and it exits after GDB detach.
+
+use std::io::{self, BufRead, Write};
+use std::process::ExitCode;
+
+use nes_cpu::gdb::GdbServer;
+use nes_cpu::loader;
+use nes_cpu::monitor::Monitor;
+
+const USAGE: &str = "usage: nes_cpu-mon [-l load_addr] [-s script] [--gdb port] <rom or binary>";
+
+struct Options {
+    program: String,
+    load_addr: u16,
+    script: Option<String>,
+    gdb_port: Option<u16>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args      = std::env::args().skip(1);
+    let mut program   = None;
+    let mut load_addr = 0;
+    let mut script    = None;
+    let mut gdb_port  = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-l" => {
+                let addr = args.next().ok_or(USAGE)?;
+                load_addr = u16::from_str_radix(addr.trim_start_matches('$'), 16)
+                    .map_err(|_| format!("invalid address '{}'", addr))?;
+            }
+            "-s" => script = Some(args.next().ok_or(USAGE)?),
+            "--gdb" => {
+                let port = args.next().ok_or(USAGE)?;
+                gdb_port = Some(port.parse().map_err(|_| format!("invalid port '{}'", port))?);
+            }
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ if program.is_none() => program = Some(arg),
+            _ => return Err(USAGE.to_string()),
+        }
+    }
+    Ok(Options { program: program.ok_or(USAGE)?, load_addr, script, gdb_port })
+}
+
+// Execute one line and print its output. Return false after `q`.
+fn execute(mon: &mut Monitor, line: &str) -> bool {
+    match mon.execute(line) {
+        Ok(out)    => print!("{}", out),
+        Err(error) => println!("? {}", error),
+    }
+    !mon.is_quit()
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let image = std::fs::read(&options.program)
+        .map_err(|e| format!("{}: {}", options.program, e))?;
+    let cpu = loader::load_image(&image, options.load_addr).map_err(|e| e.to_string())?;
+    let mut mon = Monitor::new(cpu);
+
+    if let Some(path) = &options.script {
+        let script = std::fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+        for line in script.lines() {
+            println!(". {}", line);
+            if !execute(&mut mon, line) {
+                return Ok(());
+            }
+        }
+    }
+
+    if let Some(port) = options.gdb_port {
+        eprintln!("waiting for gdb on port {}", port);
+        let mut server = GdbServer::new(mon.into_debugger());
+        return server.serve_tcp(("127.0.0.1", port)).map_err(|e| e.to_string());
+    }
+
+    print!("{}", mon.execute("r").map_err(|e| e.to_string())?);
+    let stdin = io::stdin();
+    loop {
+        print!(". ");
+        io::stdout().flush().map_err(|e| e.to_string())?;
+        let mut line = String::new();
+        match stdin.lock().read_line(&mut line) {
+            Ok(0) => return Ok(()),
+            Ok(_) if !execute(&mut mon, &line) => return Ok(()),
+            Ok(_) => (),
+            Err(e) => return Err(e.to_string()),
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    match parse_args().and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
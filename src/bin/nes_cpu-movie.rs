@@ -0,0 +1,112 @@
+//! Play FM2 movie on a rom
+//!
+//! Usage: nes_cpu-movie [-v] [-w] [-c] [-o png] <rom> <fm2>
+//!
+//! The movie is played from power-up in the region of its `palFlag`, and hashes of
+//! the last frame and of the whole state (cpu, memory and devices) are printed.
+//! With `-v`, the movie is played twice in lockstep and the first frame where the
+//! runs diverge is reported; exit status is failure then. With `-w`, state hash of
+//! each frame is written to `<fm2>.hashes`, and with `-c` the replay is checked
+//! against that file and the first frame that differs is reported. With `-o`, the
+//! last frame is saved as PNG.
+
+use std::process::ExitCode;
+
+use nes_cpu::harness::frames::frame_hash;
+use nes_cpu::movie::{parse_hashes, write_hashes, Movie};
+
+const USAGE: &str = "usage: nes_cpu-movie [-v] [-w] [-c] [-o png] <rom> <fm2>";
+
+struct Options {
+    rom: String,
+    movie: String,
+    verify: bool,
+    write: bool,
+    check: bool,
+    png: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args   = std::env::args().skip(1);
+    let mut files  = Vec::new();
+    let mut verify = false;
+    let mut write  = false;
+    let mut check  = false;
+    let mut png    = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-v" => verify = true,
+            "-w" => write = true,
+            "-c" => check = true,
+            "-o" => png = Some(args.next().ok_or(USAGE)?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => files.push(arg),
+        }
+    }
+    let [rom, movie] = <[String; 2]>::try_from(files).map_err(|_| USAGE)?;
+    Ok(Options { rom, movie, verify, write, check, png })
+}
+
+// Return true if check or verify failed
+fn run(options: Options) -> Result<bool, String> {
+    let rom   = std::fs::read(&options.rom).map_err(|e| format!("{}: {}", options.rom, e))?;
+    let text  = std::fs::read_to_string(&options.movie).map_err(|e| format!("{}: {}", options.movie, e))?;
+    let movie = Movie::parse(&text).map_err(|e| format!("{}: {}", options.movie, e))?;
+
+    let mut nes = movie.console(&rom).map_err(|e| format!("{}: {}", options.rom, e))?;
+    movie.play(&mut nes);
+    let state = nes.cpu().save_state().map_err(|e| e.to_string())?;
+    println!("frames {}", movie.frames.len());
+    println!("frame  {:016x}", frame_hash(nes.frame()));
+    println!("state  {:016x}", frame_hash(&state));
+
+    if let Some(path) = &options.png {
+        nes.save_png(path).map_err(|e| format!("{}: {}", path, e))?;
+    }
+
+    let hashes_path = format!("{}.hashes", options.movie);
+    if options.write {
+        let hashes = movie.hashes(&rom).map_err(|e| format!("{}: {}", options.rom, e))?;
+        std::fs::write(&hashes_path, write_hashes(&hashes)).map_err(|e| format!("{}: {}", hashes_path, e))?;
+    }
+
+    let mut failed = false;
+    if options.check {
+        let text   = std::fs::read_to_string(&hashes_path).map_err(|e| format!("{}: {}", hashes_path, e))?;
+        let hashes = parse_hashes(&text).map_err(|e| format!("{}: {}", hashes_path, e))?;
+        match movie.check(&rom, &hashes).map_err(|e| format!("{}: {}", options.rom, e))? {
+            None => println!("check ok"),
+            Some(frame) => {
+                println!("check FAILED: state differs from {} at frame {}", hashes_path, frame);
+                failed = true;
+            }
+        }
+    }
+    if options.verify {
+        match movie.verify(&rom).map_err(|e| format!("{}: {}", options.rom, e))? {
+            None => println!("verify ok"),
+            Some(divergence) => {
+                let what = match (divergence.cpu, divergence.memory) {
+                    (true, true) => "cpu and memory",
+                    (true, false) => "cpu",
+                    _ => "memory",
+                };
+                println!("verify FAILED: {} diverged at frame {}", what, divergence.frame);
+                failed = true;
+            }
+        }
+    }
+    Ok(failed)
+}
+
+fn main() -> ExitCode {
+    match parse_args().and_then(run) {
+        Ok(false) => ExitCode::SUCCESS,
+        Ok(true) => ExitCode::FAILURE,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -0,0 +1,125 @@
+//! Render a song of NSF or NSFe file to WAV
+//!
+//! Usage: nes_cpu-nsf [-s song] [-t seconds] [-r ntsc|pal|dendy] [--vgm path] [--csv path] <nsf> <wav>
+//!
+//! Song is 1-based and defaults to the start song of the file. Length defaults to
+//! the time and fade of the track in NSFe, or 2 minutes. Region defaults to NTSC
+//! unless the file is PAL only. With `--vgm` or `--csv`, writes to APU registers
+//! are also saved as VGM or CSV register log.
+
+use std::process::ExitCode;
+
+use nes_cpu::apu::DEFAULT_SAMPLE_RATE;
+use nes_cpu::apu_log::{ApuLog, ApuRecorder};
+use nes_cpu::memory::Memory;
+use nes_cpu::nsf::{Nsf, NsfPlayer};
+use nes_cpu::region::Region;
+use nes_cpu::wav::WavWriter;
+
+const USAGE: &str =
+    "usage: nes_cpu-nsf [-s song] [-t seconds] [-r ntsc|pal|dendy] [--vgm path] [--csv path] <nsf> <wav>";
+
+const DEFAULT_SECONDS: f64 = 120.0;
+
+struct Options {
+    input: String,
+    output: String,
+    song: Option<u8>,
+    seconds: Option<f64>,
+    region: Option<Region>,
+    vgm: Option<String>,
+    csv: Option<String>,
+}
+
+fn parse_args() -> Result<Options, String> {
+    let mut args    = std::env::args().skip(1);
+    let mut files   = Vec::new();
+    let mut song    = None;
+    let mut seconds = None;
+    let mut region  = None;
+    let mut vgm     = None;
+    let mut csv     = None;
+
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "-s" => {
+                let value: u8 = args.next().and_then(|s| s.parse().ok()).ok_or(USAGE)?;
+                song = Some(value.checked_sub(1).ok_or("song starts from 1")?);
+            }
+            "-t" => seconds = Some(args.next().and_then(|s| s.parse().ok()).ok_or(USAGE)?),
+            "-r" => {
+                region = Some(match args.next().as_deref() {
+                    Some("ntsc")  => Region::Ntsc,
+                    Some("pal")   => Region::Pal,
+                    Some("dendy") => Region::Dendy,
+                    _ => return Err(USAGE.to_string()),
+                });
+            }
+            "--vgm" => vgm = Some(args.next().ok_or(USAGE)?),
+            "--csv" => csv = Some(args.next().ok_or(USAGE)?),
+            "-h" | "--help" => return Err(USAGE.to_string()),
+            _ => files.push(arg),
+        }
+    }
+    let [input, output] = <[String; 2]>::try_from(files).map_err(|_| USAGE)?;
+    Ok(Options { input, output, song, seconds, region, vgm, csv })
+}
+
+fn run(options: Options) -> Result<(), String> {
+    let bytes = std::fs::read(&options.input).map_err(|e| format!("{}: {}", options.input, e))?;
+    let nsf   = Nsf::from_bytes(&bytes).map_err(|e| format!("{}: {}", options.input, e))?;
+
+    let region     = options.region.unwrap_or(nsf.region());
+    let song       = options.song.unwrap_or(nsf.start_song);
+    let track      = nsf.tracks.get(song as usize).cloned().unwrap_or_default();
+    let track_time = track.time.map(|time| (time + track.fade.unwrap_or(0)) as f64 / 1000.0);
+    let seconds    = options.seconds.or(track_time).unwrap_or(DEFAULT_SECONDS);
+
+    println!("{} - {} ({})", nsf.name, nsf.artist, nsf.copyright);
+    let label = track.label.map(|label| format!(" {}", label)).unwrap_or_default();
+    println!("song {}/{}{}, {:.1}s, {:?}", song as u16 + 1, nsf.songs, label, seconds, region);
+    if nsf.chips != 0 {
+        eprintln!("warning: expansion audio ({:#04x}) is not emulated", nsf.chips);
+    }
+
+    let mut player = NsfPlayer::with_hooks(nsf, region, ApuRecorder::new());
+    let logging    = options.vgm.is_some() || options.csv.is_some();
+    let mut log    = ApuLog::new(player.cpu().frequency(), player.cpu().cycles());
+    player.hooks_mut().take_writes();
+    if !player.start(song) {
+        eprintln!("warning: INIT did not return");
+    }
+    // DMC samples are in $C000-$FFFF after INIT
+    log.set_dpcm((0xC000..=0xFFFF).map(|addr| player.cpu().mem().peek(addr)).collect());
+    let mut wav = WavWriter::create(&options.output, DEFAULT_SAMPLE_RATE)
+        .map_err(|e| format!("{}: {}", options.output, e))?;
+    let mut left = seconds;
+    while left > 0.0 {
+        let samples = player.render(left.min(1.0));
+        left -= samples.len() as f64 / DEFAULT_SAMPLE_RATE as f64;
+        wav.write_samples(&samples).map_err(|e| format!("{}: {}", options.output, e))?;
+        let writes = player.hooks_mut().take_writes();
+        if logging {
+            log.extend(writes);
+        }
+    }
+    wav.finish().map_err(|e| format!("{}: {}", options.output, e))?;
+
+    if let Some(path) = &options.vgm {
+        log.write_vgm(path, player.cpu().cycles()).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    if let Some(path) = &options.csv {
+        log.write_csv(path).map_err(|e| format!("{}: {}", path, e))?;
+    }
+    Ok(())
+}
+
+fn main() -> ExitCode {
+    match parse_args().and_then(run) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(error) => {
+            eprintln!("{}", error);
+            ExitCode::FAILURE
+        }
+    }
+}
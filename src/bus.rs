@@ -0,0 +1,249 @@
+//! Provide memory map of NES as seen from the cpu
+
+use std::cell::Cell;
+
+use crate::apu::Apu;
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::controller::Joypad;
+use crate::memory::Memory;
+use crate::ppu::Ppu;
+use crate::region::Region;
+use crate::state::{MemoryState, StateError, StateReader, StateWriter};
+
+const RAM_SIZE:     usize = 0x0800;
+const PRG_RAM_SIZE: usize = 0x2000;
+
+/// Cycles the cpu is stopped by OAM DMA, without the alignment cycle
+const OAM_DMA_CYCLES: u64 = 513;
+
+/// Cpu bus with internal ram, PPU, APU, controllers, prg ram and NROM cartridge
+///
+/// Upper 3 bits of controller ports ($4016-$4017) are open bus: the last value
+/// on the data bus, usually $40 of the address. Test registers ($4018-$401F)
+/// are not connected: reads return 0 and writes are ignored.
+pub struct Bus {
+    ram: [u8; RAM_SIZE],
+    prg_ram: [u8; PRG_RAM_SIZE],
+    ppu: Ppu,
+    apu: Apu,
+    joypads: [Joypad; 2],
+    cart: Cartridge,
+    // Cycles to stop the cpu for DMA, taken by the system after each step
+    stall: u64,
+    // Last value read or written, and last address read
+    open_bus: Cell<u8>,
+    last_read: Cell<u16>,
+    // Number of states loaded
+    loads: u64,
+}
+
+impl Bus {
+    pub fn new(cart: Cartridge) -> Result<Bus, CartridgeError> {
+        if cart.mapper != 0 {
+            return Err(CartridgeError::UnsupportedMapper(cart.mapper));
+        }
+        let ppu = Ppu::new(cart.chr_rom.clone(), cart.mirroring, cart.region);
+        let apu = Apu::new(cart.region);
+        Ok(Bus {
+            ram: [0; RAM_SIZE],
+            prg_ram: [0; PRG_RAM_SIZE],
+            ppu,
+            apu,
+            joypads: [Joypad::new(), Joypad::new()],
+            cart,
+            stall: 0,
+            open_bus: Cell::new(0),
+            last_read: Cell::new(0),
+            loads: 0,
+        })
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        &self.ppu
+    }
+
+    pub fn ppu_mut(&mut self) -> &mut Ppu {
+        &mut self.ppu
+    }
+
+    pub fn apu(&self) -> &Apu {
+        &self.apu
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        &mut self.apu
+    }
+
+    /// Controller of port 0 ($4016) or 1 ($4017)
+    pub fn joypad(&self, port: usize) -> &Joypad {
+        &self.joypads[port]
+    }
+
+    pub fn joypad_mut(&mut self, port: usize) -> &mut Joypad {
+        &mut self.joypads[port]
+    }
+
+    /// Run APU for given cpu cycles, reading DMC samples from the bus
+    pub fn run_apu(&mut self, cycles: u64) {
+        let ntsc = self.apu.region() == Region::Ntsc;
+        let (cart, joypads, open_bus, last_read) = (&self.cart, &self.joypads, &self.open_bus, &self.last_read);
+        let stall = self.apu.run(cycles, |addr| {
+            // DMA of NTSC chip repeats the read the cpu was doing. When it is a
+            // controller, the extra read shifts out a button and it is lost.
+            // Which cycle of the instruction is hit is not tracked.
+            if ntsc {
+                match last_read.get() {
+                    0x4016 => { joypads[0].read(); }
+                    0x4017 => { joypads[1].read(); }
+                    _ => (),
+                }
+            }
+            // Samples are in $8000-$FFFF
+            let value = read_prg(cart, addr);
+            open_bus.set(value);
+            last_read.set(addr);
+            value
+        });
+        self.stall += stall;
+    }
+
+    /// Number of states loaded. The system checks it to notice that the time
+    /// of the cpu, PPU and APU was changed by `Cpu::load_state`.
+    pub fn load_count(&self) -> u64 {
+        self.loads
+    }
+
+    /// Return cycles the cpu must be stopped for DMA done since last call
+    pub fn take_stall(&mut self) -> u64 {
+        std::mem::take(&mut self.stall)
+    }
+
+    fn oam_dma(&mut self, page: u8) {
+        let base = (page as u16) << 8;
+        let data = std::array::from_fn(|i| self.read_byte(base | i as u16));
+        self.ppu.write_oam_dma(&data);
+        self.stall += OAM_DMA_CYCLES;
+    }
+
+    pub fn cartridge(&self) -> &Cartridge {
+        &self.cart
+    }
+}
+
+impl Memory for Bus {
+    fn read_byte(&self, addr: u16) -> u8 {
+        let value = match addr {
+            0x0000..=0x1FFF => self.ram[addr as usize % RAM_SIZE],
+            0x2000..=0x3FFF => self.ppu.read_register(addr),
+            0x4015 => self.apu.read_status(),
+            0x4016 => (self.open_bus.get() & 0xE0) | self.joypads[0].read(),
+            0x4017 => (self.open_bus.get() & 0xE0) | self.joypads[1].read(),
+            0x6000..=0x7FFF => self.prg_ram[addr as usize - 0x6000],
+            0x8000..=0xFFFF => read_prg(&self.cart, addr),
+            _ => 0,
+        };
+        self.open_bus.set(value);
+        self.last_read.set(addr);
+        value
+    }
+
+    // Same as `read_byte`, but leaves the devices and open bus untouched
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.ram[addr as usize % RAM_SIZE],
+            0x2000..=0x3FFF => self.ppu.peek_register(addr),
+            0x4015 => self.apu.peek_status(),
+            0x4016 => (self.open_bus.get() & 0xE0) | self.joypads[0].peek(),
+            0x4017 => (self.open_bus.get() & 0xE0) | self.joypads[1].peek(),
+            0x6000..=0x7FFF => self.prg_ram[addr as usize - 0x6000],
+            0x8000..=0xFFFF => read_prg(&self.cart, addr),
+            _ => 0,
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self.open_bus.set(value);
+        match addr {
+            0x0000..=0x1FFF => self.ram[addr as usize % RAM_SIZE] = value,
+            0x2000..=0x3FFF => self.ppu.write_register(addr, value),
+            0x4014 => self.oam_dma(value),
+            0x4016 => self.joypads.iter_mut().for_each(|joypad| joypad.write(value)),
+            0x4000..=0x4013 | 0x4015 | 0x4017 => self.apu.write_register(addr, value),
+            0x6000..=0x7FFF => self.prg_ram[addr as usize - 0x6000] = value,
+            _ => (),
+        }
+    }
+
+    fn is_io(&self, addr: u16) -> bool {
+        (0x2000..=0x401F).contains(&addr)
+    }
+
+    fn mirror_of(&self, addr: u16) -> u16 {
+        match addr {
+            0x0000..=0x1FFF => addr % RAM_SIZE as u16,
+            _ => addr,
+        }
+    }
+
+    fn state(&self) -> Option<&dyn MemoryState> {
+        Some(self)
+    }
+
+    fn state_mut(&mut self) -> Option<&mut dyn MemoryState> {
+        Some(self)
+    }
+}
+
+// Rom is not saved, state must be loaded to the bus with the same cartridge
+impl MemoryState for Bus {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.section(*b"BUS ", 3, |out| {
+            out.write_bytes(&self.ram);
+            out.write_bytes(&self.prg_ram);
+            out.write_u8(self.open_bus.get());
+            out.write_u16(self.last_read.get());
+            for joypad in &self.joypads {
+                joypad.save_state(out);
+            }
+            out.write_u64(self.stall);
+        });
+        self.ppu.save_state(out);
+        self.apu.save_state(out);
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let (version, mut bus) = input.section(*b"BUS ", 3)?;
+        bus.read_into(&mut self.ram)?;
+        bus.read_into(&mut self.prg_ram)?;
+        // Controllers are added in version 2
+        if version >= 2 {
+            self.open_bus.set(bus.read_u8()?);
+            self.last_read.set(bus.read_u16()?);
+            for joypad in &mut self.joypads {
+                joypad.load_state(&mut bus)?;
+            }
+        }
+        self.stall = match version {
+            3.. => bus.read_u64()?,
+            _ => 0,
+        };
+        // State saved before PPU was added has no PPU section
+        if input.remaining().starts_with(b"PPU ") {
+            self.ppu.load_state(input)?;
+        }
+        if input.remaining().starts_with(b"APU ") {
+            self.apu.load_state(input)?;
+        }
+        self.loads += 1;
+        Ok(())
+    }
+}
+
+// PRG ROM at $8000-$FFFF, mirrored when it is 16KiB
+fn read_prg(cart: &Cartridge, addr: u16) -> u8 {
+    let prg = &cart.prg_rom;
+    match prg.len() {
+        0 => 0,
+        len => prg[(addr as usize & 0x7FFF) % len],
+    }
+}
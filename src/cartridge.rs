@@ -0,0 +1,135 @@
+//! Provide loader of iNES and NES 2.0 rom image
+
+use std::fmt;
+
+use crate::region::Region;
+
+const HEADER_SIZE:  usize = 16;
+const TRAINER_SIZE: usize = 512;
+const PRG_BANK:     usize = 0x4000;
+const CHR_BANK:     usize = 0x2000;
+
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+pub enum Mirroring {
+    Horizontal,
+    Vertical,
+    FourScreen,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum CartridgeError {
+    /// Image does not start with "NES\x1A"
+    InvalidHeader,
+    /// Image is shorter than the size written in the header
+    Truncated,
+    /// Mapper with given number is not implemented
+    UnsupportedMapper(u16),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CartridgeError::InvalidHeader => write!(f, "invalid iNES header"),
+            CartridgeError::Truncated     => write!(f, "rom image is truncated"),
+            CartridgeError::UnsupportedMapper(n) => write!(f, "unsupported mapper: {}", n),
+        }
+    }
+}
+
+impl std::error::Error for CartridgeError {}
+
+#[derive(Clone)]
+pub struct Cartridge {
+    pub prg_rom: Vec<u8>,
+    pub chr_rom: Vec<u8>,
+    pub mapper: u16,
+    pub mirroring: Mirroring,
+    pub battery: bool,
+    pub nes2: bool,
+    /// Region from NES 2.0 timing field. NTSC for iNES image.
+    pub region: Region,
+}
+
+impl Cartridge {
+    /// Parse iNES or NES 2.0 image
+    pub fn from_bytes(bytes: &[u8]) -> Result<Cartridge, CartridgeError> {
+        if bytes.len() < HEADER_SIZE || &bytes[0..4] != b"NES\x1A" {
+            return Err(CartridgeError::InvalidHeader);
+        }
+        let flags6 = bytes[6];
+        let flags7 = bytes[7];
+        let nes2   = flags7 & 0b0000_1100 == 0b0000_1000;
+
+        let mut prg_size = bytes[4] as usize;
+        let mut chr_size = bytes[5] as usize;
+        let mut mapper   = ((flags7 & 0xF0) | (flags6 >> 4)) as u16;
+        let mut region   = Region::Ntsc;
+        if nes2 {
+            region    = Region::from_nes2_timing(bytes[12]);
+            prg_size |= ((bytes[9] & 0x0F) as usize) << 8;
+            chr_size |= ((bytes[9] >> 4)   as usize) << 8;
+            mapper   |= ((bytes[8] & 0x0F) as u16)   << 8;
+        }
+
+        let mirroring = if flags6 & 0b0000_1000 != 0 {
+            Mirroring::FourScreen
+        } else if flags6 & 0b0000_0001 != 0 {
+            Mirroring::Vertical
+        } else {
+            Mirroring::Horizontal
+        };
+
+        let prg_start = HEADER_SIZE + if flags6 & 0b0000_0100 != 0 { TRAINER_SIZE } else { 0 };
+        let chr_start = prg_start + prg_size * PRG_BANK;
+        let chr_end   = chr_start + chr_size * CHR_BANK;
+        if bytes.len() < chr_end {
+            return Err(CartridgeError::Truncated);
+        }
+
+        Ok(Cartridge {
+            prg_rom: bytes[prg_start..chr_start].to_vec(),
+            chr_rom: bytes[chr_start..chr_end].to_vec(),
+            mapper,
+            mirroring,
+            battery: flags6 & 0b0000_0010 != 0,
+            nes2,
+            region,
+        })
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// NROM image with 16KiB PRG and CHR RAM. Program is at $C000 (RESET) and
+    /// NMI handler at $C100.
+    pub(crate) fn nrom(program: &[u8], nmi: &[u8]) -> Vec<u8> {
+        let mut rom = vec![0; HEADER_SIZE + PRG_BANK];
+        rom[0..8].copy_from_slice(&[b'N', b'E', b'S', 0x1A, 1, 0, 0x01, 0x00]);
+        rom[HEADER_SIZE..HEADER_SIZE + program.len()].copy_from_slice(program);
+        rom[HEADER_SIZE + 0x100..HEADER_SIZE + 0x100 + nmi.len()].copy_from_slice(nmi);
+        rom[HEADER_SIZE + 0x3FFA..].copy_from_slice(&[0x00, 0xC1, 0x00, 0xC0, 0x00, 0xC0]);
+        rom
+    }
+
+    #[test]
+    fn test_parse_header() {
+        let mut image = vec![0; HEADER_SIZE + PRG_BANK + CHR_BANK];
+        image[0..8].copy_from_slice(&[b'N', b'E', b'S', 0x1A, 1, 1, 0x11, 0x08]);
+
+        let cart = Cartridge::from_bytes(&image).unwrap();
+        assert_eq!(cart.mapper, 1);
+        assert_eq!(cart.mirroring, Mirroring::Vertical);
+        assert_eq!(cart.prg_rom.len(), PRG_BANK);
+        assert_eq!(cart.chr_rom.len(), CHR_BANK);
+        assert!(cart.nes2);
+        assert_eq!(cart.region, Region::Ntsc);
+
+        image[12] = 0x01;
+        assert_eq!(Cartridge::from_bytes(&image).unwrap().region, Region::Pal);
+
+        assert_eq!(Cartridge::from_bytes(&image[..100]).err(), Some(CartridgeError::Truncated));
+        assert_eq!(Cartridge::from_bytes(&[0; 16]).err(), Some(CartridgeError::InvalidHeader));
+    }
+}
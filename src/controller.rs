@@ -0,0 +1,154 @@
+//! Provide standard controller (joypad) of $4016/$4017
+//!
+//! Writing 1 to bit 0 of $4016 (strobe) loads the buttons to the shift register
+//! of both controllers, continuously while it is 1. Each read returns the next
+//! button in bit 0, in the order of A, B, Select, Start, Up, Down, Left, Right,
+//! and 1 after all 8 buttons are read.
+
+use std::cell::Cell;
+use std::ops::{BitOr, BitOrAssign};
+
+use crate::state::{StateError, StateReader, StateWriter};
+
+/// Set of pressed buttons. Bits are in the order the controller reports them.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Buttons {
+    bits: u8,
+}
+
+impl BitOr for Buttons {
+    type Output = Self;
+    fn bitor(self, rhs: Self) -> Self::Output {
+        Self { bits: self.bits | rhs.bits }
+    }
+}
+
+impl BitOrAssign for Buttons {
+    fn bitor_assign(&mut self, rhs: Self) {
+        self.bits |= rhs.bits;
+    }
+}
+
+impl Buttons {
+    pub const NONE:   Self = Self { bits: 0 };
+    pub const A:      Self = Self { bits: 0b0000_0001 };
+    pub const B:      Self = Self { bits: 0b0000_0010 };
+    pub const SELECT: Self = Self { bits: 0b0000_0100 };
+    pub const START:  Self = Self { bits: 0b0000_1000 };
+    pub const UP:     Self = Self { bits: 0b0001_0000 };
+    pub const DOWN:   Self = Self { bits: 0b0010_0000 };
+    pub const LEFT:   Self = Self { bits: 0b0100_0000 };
+    pub const RIGHT:  Self = Self { bits: 0b1000_0000 };
+
+    pub const fn as_bits(&self) -> u8 {
+        self.bits
+    }
+
+    pub const fn from_bits(bits: u8) -> Buttons {
+        Buttons { bits }
+    }
+
+    pub fn set(&mut self, buttons: Self, pressed: bool) {
+        if pressed {
+            self.bits |=  buttons.bits;
+        } else {
+            self.bits &= !buttons.bits;
+        }
+    }
+
+    /// If all of given buttons are pressed, then return true
+    pub fn contains(&self, buttons: Self) -> bool {
+        self.bits & buttons.bits == buttons.bits
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Joypad {
+    buttons: Buttons,
+    strobe: bool,
+    // Shifted by reads through `&self`
+    shift: Cell<u8>,
+}
+
+impl Joypad {
+    pub fn new() -> Joypad {
+        Joypad::default()
+    }
+
+    pub fn buttons(&self) -> Buttons {
+        self.buttons
+    }
+
+    /// Set buttons held by the player. Read by the game on next strobe.
+    pub fn set_buttons(&mut self, buttons: Buttons) {
+        self.buttons = buttons;
+        if self.strobe {
+            self.shift.set(buttons.bits);
+        }
+    }
+
+    /// Write of $4016
+    pub fn write(&mut self, value: u8) {
+        self.strobe = value & 0x01 != 0;
+        if self.strobe {
+            self.shift.set(self.buttons.bits);
+        }
+    }
+
+    /// Read of $4016 or $4017. Only bit 0 is driven by the controller.
+    pub fn read(&self) -> u8 {
+        if self.strobe {
+            return self.buttons.bits & 0x01;
+        }
+        let shift = self.shift.get();
+        self.shift.set(0x80 | (shift >> 1));
+        shift & 0x01
+    }
+
+    /// Bit the next read returns, without shifting
+    pub fn peek(&self) -> u8 {
+        if self.strobe {
+            return self.buttons.bits & 0x01;
+        }
+        self.shift.get() & 0x01
+    }
+
+    pub(crate) fn save_state(&self, out: &mut StateWriter) {
+        out.write_u8(self.buttons.bits);
+        out.write_bool(self.strobe);
+        out.write_u8(self.shift.get());
+    }
+
+    pub(crate) fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        self.buttons = Buttons::from_bits(input.read_u8()?);
+        self.strobe  = input.read_bool()?;
+        self.shift.set(input.read_u8()?);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_joypad() {
+        let mut joypad = Joypad::new();
+        joypad.set_buttons(Buttons::A | Buttons::START | Buttons::RIGHT);
+
+        // Reads return A while strobe is on
+        joypad.write(1);
+        assert_eq!((joypad.read(), joypad.read()), (1, 1));
+
+        joypad.write(0);
+        let bits: Vec<u8> = (0..10).map(|_| joypad.read()).collect();
+        assert_eq!(bits, [1, 0, 0, 1, 0, 0, 0, 1, 1, 1]);
+
+        // Buttons are not seen until next strobe
+        joypad.set_buttons(Buttons::B);
+        assert_eq!(joypad.read(), 1);
+        joypad.write(1);
+        joypad.write(0);
+        assert_eq!((joypad.read(), joypad.read()), (0, 1));
+    }
+}
@@ -3,28 +3,185 @@
 mod fetch;
 mod execute;
 mod interrupt;
+mod history;
+mod state;
+mod dispatch;
+mod block;
+mod run;
+mod hooks;
 
-use crate::opcode::OPCODE_TABLE;
 use crate::register::Register;
 use crate::memory::Memory;
+use crate::region::Region;
+use interrupt::InterruptLines;
+use history::History;
 
-pub struct Cpu {
+pub use state::CpuState;
+pub use block::CodeCache;
+pub use run::RunStop;
+pub use hooks::{CpuHooks, InstructionEvent, Interrupt, NoHooks};
+
+/// Cycles taken by interrupt and reset sequence
+const INTERRUPT_CYCLE: u64 = 7;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Access {
+    Read,
+    Write,
+}
+
+/// Data read or write done by an instruction. Opcode and operand fetch is not included.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BusAccess {
+    pub addr: u16,
+    pub value: u8,
+    pub access: Access,
+}
+
+/// 6502 cpu connected to memory `M`, calling hooks `H`.
+///
+/// Memory is boxed trait object by default. Concrete memory type can be given for
+/// speed, or to access the memory as its own type.
+pub struct Cpu<M = Box<dyn Memory>, H = NoHooks> {
     reg: Register,
-    mem: Box<dyn Memory>,
+    mem: M,
+    hooks: H,
+    region: Region,
+    lines: InterruptLines,
+    cycles: u64,
+    bus_log: Option<Vec<BusAccess>>,
+    history: Option<History>,
 }
 
 impl Cpu {
     pub fn new(mem: Box<dyn Memory>) -> Cpu {
-        Cpu { reg: Register::new(), mem }
+        Cpu::with_memory(mem)
+    }
+}
+
+impl<M: Memory> Cpu<M> {
+    pub fn with_memory(mem: M) -> Cpu<M> {
+        Cpu::with_hooks(mem, NoHooks)
+    }
+}
+
+impl<M: Memory, H: CpuHooks> Cpu<M, H> {
+    pub fn with_hooks(mem: M, hooks: H) -> Cpu<M, H> {
+        Cpu {
+            reg: Register::new(),
+            mem,
+            hooks,
+            region: Region::Ntsc,
+            lines: InterruptLines::default(),
+            cycles: 0,
+            bus_log: None,
+            history: None,
+        }
+    }
+
+    pub fn reg(&self) -> &Register {
+        &self.reg
+    }
+
+    pub fn reg_mut(&mut self) -> &mut Register {
+        &mut self.reg
+    }
+
+    pub fn mem(&self) -> &M {
+        &self.mem
+    }
+
+    pub fn mem_mut(&mut self) -> &mut M {
+        &mut self.mem
+    }
+
+    pub fn hooks(&self) -> &H {
+        &self.hooks
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut H {
+        &mut self.hooks
+    }
+
+    /// Number of cycles elapsed since the cpu was created
+    pub fn cycles(&self) -> u64 {
+        self.cycles
+    }
+
+    /// Stop the cpu for given cycles, like while DMA uses the bus
+    pub fn stall(&mut self, cycles: u64) {
+        self.cycles += cycles;
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    /// Set region, which decides the master clock divider of the cpu
+    pub fn set_region(&mut self, region: Region) {
+        self.region = region;
+    }
+
+    /// Effective cpu clock in Hz
+    pub fn frequency(&self) -> f64 {
+        self.region.cpu_frequency()
+    }
+
+    /// Enable or disable recording of bus accesses done by each step
+    pub fn set_bus_log(&mut self, enabled: bool) {
+        self.bus_log = if enabled { Some(Vec::new()) } else { None };
+    }
+
+    /// Bus accesses done by last step. Empty if recording is disabled.
+    pub fn bus_log(&self) -> &[BusAccess] {
+        self.bus_log.as_deref().unwrap_or(&[])
     }
 
     pub fn step(&mut self) {
+        if self.begin_step() {
+            return;
+        }
+
         let opcode = self.fetch_opcode();
-        let info   = OPCODE_TABLE.get(&opcode).unwrap_or_else(|| {
-            panic!("Invalid opcode: 0x{:x}", opcode);
-        });
-        let addr = self.fetch_address(info);
+        Self::DISPATCH[opcode as usize](self);
+    }
+
+    // Do the work common to each step before instruction.
+    // Return true if interrupt was served instead of instruction.
+    fn begin_step(&mut self) -> bool {
+        if let Some(log) = &mut self.bus_log {
+            log.clear();
+        }
+        self.record_step();
+
+        if self.poll_interrupt() {
+            self.cycles += INTERRUPT_CYCLE;
+            return true;
+        }
+        false
+    }
+
+    fn read(&mut self, addr: u16) -> u8 {
+        let value = self.mem.read_byte(addr);
+        let value = self.hooks.read(addr, value);
+        if let Some(log) = &mut self.bus_log {
+            log.push(BusAccess { addr, value, access: Access::Read });
+        }
+        value
+    }
+
+    fn write(&mut self, addr: u16, value: u8) {
+        self.record_write(addr);
+        self.mem.write_byte(addr, value);
+        self.hooks.write(addr, value);
+        if let Some(log) = &mut self.bus_log {
+            log.push(BusAccess { addr, value, access: Access::Write });
+        }
+    }
 
-        self.execute(addr, &info.name, &info.mode);
+    fn read_word(&mut self, addr: u16) -> u16 {
+        let lsb = self.read(addr);
+        let msb = self.read(addr.wrapping_add(1));
+        u16::from_le_bytes([lsb, msb])
     }
 }
@@ -0,0 +1,423 @@
+//! Provide cache of decoded basic blocks
+//!
+//! Block is a run of instructions that ends at a jump, branch, return or break.
+//! Each instruction is decoded once into the handler of its opcode and its operand,
+//! and `Cpu::step_block` runs them without looking up the opcode. The bytes are
+//! still fetched like `Cpu::step`, for the bus and `CpuHooks::fetch`, and the block
+//! is left when they are not the decoded ones.
+//!
+//! `CodeCache` wraps the memory so that it sees every write. Blocks that contain a
+//! written address, or a mirror of it, are dropped, and all blocks are dropped when
+//! `Memory::bank_version` changes.
+
+use std::rc::Rc;
+
+use super::{Cpu, CpuHooks};
+use crate::memory::Memory;
+use crate::opcode::{lookup, Mnemonic};
+use crate::state::MemoryState;
+
+/// Max number of instructions in a block
+const MAX_BLOCK_LEN: usize = 64;
+
+/// Memory wrapper that keeps decoded blocks of the code in it
+pub struct CodeCache<M> {
+    mem: M,
+    // Indexed by start address
+    blocks: Vec<Option<Rc<Block>>>,
+    len: usize,
+    // Start addresses of the blocks that have code in each page, by `Memory::mirror_of`
+    pages: Box<[Vec<u16>; 0x100]>,
+    bank_version: u64,
+    // Set when a block is dropped by write, to stop the running block
+    invalidated: bool,
+}
+
+struct Block {
+    ops: Vec<Op>,
+    start: u16,
+    // Number of bytes of the code
+    len: u16,
+}
+
+// Handler is looked up by opcode when run, so blocks do not depend on the hooks of the cpu
+struct Op {
+    opcode: u8,
+    operand: u16,
+    operand_len: u8,
+}
+
+impl<M: Memory> CodeCache<M> {
+    pub fn new(mem: M) -> CodeCache<M> {
+        let bank_version = mem.bank_version();
+        CodeCache {
+            mem,
+            blocks: vec![None; 0x10000],
+            len: 0,
+            pages: Box::new(std::array::from_fn(|_| Vec::new())),
+            bank_version,
+            invalidated: false,
+        }
+    }
+
+    pub fn inner(&self) -> &M {
+        &self.mem
+    }
+
+    /// Cached blocks are dropped, since the memory may be changed through it
+    pub fn inner_mut(&mut self) -> &mut M {
+        self.clear();
+        &mut self.mem
+    }
+
+    pub fn into_inner(self) -> M {
+        self.mem
+    }
+
+    /// Drop all cached blocks
+    pub fn clear(&mut self) {
+        self.blocks.fill(None);
+        self.len = 0;
+        self.pages.iter_mut().for_each(Vec::clear);
+        self.bank_version = self.mem.bank_version();
+        self.invalidated  = true;
+    }
+
+    /// Number of cached blocks
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn decode(&mut self, start: u16) -> Rc<Block> {
+        let mut ops  = Vec::new();
+        let mut addr = start;
+        let mut len  = 0u16;
+        while ops.len() < MAX_BLOCK_LEN {
+            let opcode = self.mem.peek(addr);
+            let Some(info) = lookup(opcode) else { break };
+            let lsb = self.mem.peek(addr.wrapping_add(1));
+            let msb = self.mem.peek(addr.wrapping_add(2));
+            let operand_len = info.mode.operand_len();
+            let operand = match operand_len {
+                0 => 0,
+                1 => lsb as u16,
+                _ => u16::from_le_bytes([lsb, msb]),
+            };
+            ops.push(Op { opcode, operand, operand_len });
+
+            len  = len.saturating_add(info.byte as u16);
+            addr = addr.wrapping_add(info.byte as u16);
+            if ends_block(info.name) || addr < start {
+                break;
+            }
+        }
+
+        let block = Rc::new(Block { ops, start, len });
+        for page in block.pages(&self.mem) {
+            self.pages[page].push(start);
+        }
+        self.blocks[start as usize] = Some(Rc::clone(&block));
+        self.len += 1;
+        block
+    }
+
+    // Drop blocks that contain given address or its mirror
+    fn invalidate(&mut self, addr: u16) {
+        let addr = self.mem.mirror_of(addr);
+        let page = (addr >> 8) as usize;
+        if self.pages[page].is_empty() {
+            return;
+        }
+        let starts: Vec<u16> = self.pages[page].clone();
+        for start in starts {
+            let Some(block) = &self.blocks[start as usize] else { continue };
+            if !block.addrs().any(|a| self.mem.mirror_of(a) == addr) {
+                continue;
+            }
+            for page in block.pages(&self.mem) {
+                self.pages[page].retain(|s| *s != start);
+            }
+            self.blocks[start as usize] = None;
+            self.len -= 1;
+            self.invalidated = true;
+        }
+    }
+}
+
+impl Block {
+    fn addrs(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..self.len.max(1)).map(|i| self.start.wrapping_add(i))
+    }
+
+    // Pages of the code as seen by `Memory::mirror_of`, without duplicates
+    fn pages<M: Memory>(&self, mem: &M) -> Vec<usize> {
+        let mut pages: Vec<usize> = self.addrs().map(|addr| (mem.mirror_of(addr) >> 8) as usize).collect();
+        pages.sort_unstable();
+        pages.dedup();
+        pages
+    }
+}
+
+fn ends_block(name: Mnemonic) -> bool {
+    matches!(
+        name,
+        Mnemonic::Jmp | Mnemonic::Jsr | Mnemonic::Rts | Mnemonic::Rti | Mnemonic::Brk |
+        Mnemonic::Bcc | Mnemonic::Bcs | Mnemonic::Beq | Mnemonic::Bmi |
+        Mnemonic::Bne | Mnemonic::Bpl | Mnemonic::Bvc | Mnemonic::Bvs
+    )
+}
+
+impl<M: Memory> Memory for CodeCache<M> {
+    fn read_byte(&self, addr: u16) -> u8 {
+        self.mem.read_byte(addr)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        self.mem.peek(addr)
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self.mem.write_byte(addr, value);
+        self.invalidate(addr);
+    }
+
+    fn is_io(&self, addr: u16) -> bool {
+        self.mem.is_io(addr)
+    }
+
+    fn mirror_of(&self, addr: u16) -> u16 {
+        self.mem.mirror_of(addr)
+    }
+
+    fn bank_version(&self) -> u64 {
+        self.mem.bank_version()
+    }
+
+    fn state(&self) -> Option<&dyn MemoryState> {
+        self.mem.state()
+    }
+
+    // Loading state changes the memory
+    fn state_mut(&mut self) -> Option<&mut dyn MemoryState> {
+        self.clear();
+        self.mem.state_mut()
+    }
+}
+
+impl<M: Memory, H: CpuHooks> Cpu<CodeCache<M>, H> {
+    /// Execute the block at pc, decoding it if not cached yet. Return number of
+    /// steps done, and the result is the same as calling `step` for that number.
+    ///
+    /// Block is left early when interrupt is served, the code is written, the bank
+    /// is switched or the fetched bytes are not the decoded ones.
+    pub fn step_block(&mut self) -> usize {
+        if self.mem.bank_version != self.mem.mem.bank_version() {
+            self.mem.clear();
+        }
+        let block = match &self.mem.blocks[self.reg.pc as usize] {
+            Some(block) => Rc::clone(block),
+            None => self.mem.decode(self.reg.pc),
+        };
+        if block.ops.is_empty() {
+            self.step();
+            return 1;
+        }
+
+        self.mem.invalidated = false;
+        let mut steps = 0;
+        for op in &block.ops {
+            steps += 1;
+            if self.begin_step() {
+                break;
+            }
+            // Fetch as `step` does. Other bytes than decoded, like the ones replaced
+            // by the hooks, are executed by `step` instead.
+            let pc     = self.reg.pc;
+            let opcode = self.fetch_opcode();
+            if opcode != op.opcode {
+                Self::DISPATCH[opcode as usize](self);
+                break;
+            }
+            let operand = match op.operand_len {
+                0 => 0,
+                1 => self.fetch_byte() as u16,
+                _ => u16::from_le_bytes([self.fetch_byte(), self.fetch_byte()]),
+            };
+            self.mem.invalidated |= operand != op.operand;
+            self.reg.pc = pc;
+            Self::DECODED[opcode as usize](self, operand);
+            // Bank switch in the block drops it at next call
+            if self.mem.invalidated || self.mem.bank_version != self.mem.mem.bank_version() {
+                break;
+            }
+        }
+        steps
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::bus::Bus;
+    use crate::cartridge::Cartridge;
+    use crate::cartridge::test::nrom;
+    use crate::memory::FlatMemory;
+
+    // $8000: LDX #0
+    // $8002: LDA #5; STA $8003; INC $800C; CLC; ADC #1; STA $0200,X; JSR $8020; INX; BNE $8002
+    // $8016: JMP $8000
+    // $8020: CLI; INY; RTS
+    // $8030: INC $10; RTI (IRQ handler)
+    fn memory() -> FlatMemory {
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[
+            0xA2, 0x00, 0xA9, 0x05, 0x8D, 0x03, 0x80, 0xEE, 0x0C, 0x80, 0x18, 0x69, 0x01,
+            0x9D, 0x00, 0x02, 0x20, 0x20, 0x80, 0xE8, 0xD0, 0xEC, 0x4C, 0x00, 0x80,
+        ]);
+        mem.load(0x8020, &[0x58, 0xC8, 0x60]);
+        mem.load(0x8030, &[0xE6, 0x10, 0x40]);
+        mem.load(0xFFFE, &[0x30, 0x80]);
+        mem
+    }
+
+    #[test]
+    fn test_same_as_step() {
+        let mut cpu = Cpu::with_memory(memory());
+        let mut cached = Cpu::with_memory(CodeCache::new(memory()));
+        for cpu in [cpu.reg_mut(), cached.reg_mut()] {
+            cpu.pc = 0x8000;
+            cpu.s  = 0xFF;
+        }
+
+        for i in 0..2000 {
+            if i == 500 || i == 520 {
+                cpu.set_irq(i == 500);
+                cached.set_irq(i == 500);
+            }
+            for _ in 0..cached.step_block() {
+                cpu.step();
+            }
+            assert_eq!(cpu.reg(), cached.reg(), "block {}", i);
+            assert_eq!(cpu.cycles(), cached.cycles());
+        }
+        for addr in 0..=0xFFFF {
+            assert_eq!(cpu.mem().read_byte(addr), cached.mem().read_byte(addr));
+        }
+        assert!(!cached.mem().is_empty());
+        assert_ne!(cpu.mem().read_byte(0x10), 0);
+    }
+
+    // Hooks that record fetched addresses
+    #[derive(Default)]
+    struct Fetches(Vec<u16>);
+
+    impl CpuHooks for Fetches {
+        fn fetch(&mut self, addr: u16, value: u8) -> u8 {
+            self.0.push(addr);
+            value
+        }
+    }
+
+    fn bus() -> Bus {
+        // $C000: LDA $4016; STA $10; LDA $0300; JMP $C000
+        let program = [0xAD, 0x16, 0x40, 0x85, 0x10, 0xAD, 0x00, 0x03, 0x4C, 0x00, 0xC0];
+        Bus::new(Cartridge::from_bytes(&nrom(&program, &[])).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn test_same_as_step_on_bus() {
+        let mut cpu = Cpu::with_hooks(bus(), Fetches::default());
+        let mut cached = Cpu::with_hooks(CodeCache::new(bus()), Fetches::default());
+        cpu.reg_mut().pc = 0xC000;
+        cached.reg_mut().pc = 0xC000;
+
+        for _ in 0..10 {
+            for _ in 0..cached.step_block() {
+                cpu.step();
+            }
+            assert_eq!(cpu.reg(), cached.reg());
+        }
+        // Upper bits of $4016 are $40 left by the fetch of the address
+        assert_eq!(cached.mem().peek(0x10) & 0xE0, 0x40);
+        assert_eq!(cpu.hooks().0, cached.hooks().0);
+    }
+
+    #[test]
+    fn test_write_through_mirror() {
+        // $0300: INX; LDA #$CA; STA $0B00 (mirror of $0300); JMP $0300
+        let mut cached = Cpu::with_memory(CodeCache::new(bus()));
+        for (i, b) in [0xE8, 0xA9, 0xCA, 0x8D, 0x00, 0x0B, 0x4C, 0x00, 0x03].into_iter().enumerate() {
+            cached.mem_mut().write_byte(0x0300 + i as u16, b);
+        }
+        cached.reg_mut().pc = 0x0300;
+
+        assert_eq!(cached.step_block(), 3);
+        assert!(cached.mem().is_empty());
+        cached.step_block();
+        cached.step_block();
+        // INX is replaced by DEX
+        assert_eq!(cached.reg().x, 0);
+    }
+
+    // Flat memory with two banks at $9000-$9FFF, selected by writing $5000
+    struct Banked {
+        mem: FlatMemory,
+        banks: [[u8; 0x1000]; 2],
+        bank: usize,
+        version: u64,
+    }
+
+    impl Memory for Banked {
+        fn read_byte(&self, addr: u16) -> u8 {
+            match addr {
+                0x9000..=0x9FFF => self.banks[self.bank][addr as usize - 0x9000],
+                _ => self.mem.read_byte(addr),
+            }
+        }
+
+        fn write_byte(&mut self, addr: u16, value: u8) {
+            match addr {
+                0x5000 => {
+                    self.bank     = value as usize & 1;
+                    self.version += 1;
+                }
+                _ => self.mem.write_byte(addr, value),
+            }
+        }
+
+        fn bank_version(&self) -> u64 {
+            self.version
+        }
+    }
+
+    #[test]
+    fn test_bank_switch_in_block() {
+        // $8FFB: LDA #1; STA $5000, falling through to the bank at $9000
+        // $9000: INX (bank 0) or DEX (bank 1); STX $10; JMP $9001
+        let banked = || {
+            let mut mem = FlatMemory::new();
+            mem.load(0x8FFB, &[0xA9, 0x01, 0x8D, 0x00, 0x50]);
+            let mut banks = [[0; 0x1000]; 2];
+            for (bank, opcode) in banks.iter_mut().zip([0xE8, 0xCA]) {
+                bank[..6].copy_from_slice(&[opcode, 0x86, 0x10, 0x4C, 0x01, 0x90]);
+            }
+            Banked { mem, banks, bank: 0, version: 0 }
+        };
+        let mut cpu = Cpu::with_memory(banked());
+        let mut cached = Cpu::with_memory(CodeCache::new(banked()));
+        cpu.reg_mut().pc = 0x8FFB;
+        cached.reg_mut().pc = 0x8FFB;
+
+        for _ in 0..10 {
+            for _ in 0..cached.step_block() {
+                cpu.step();
+            }
+            assert_eq!(cpu.reg(), cached.reg());
+        }
+        assert_eq!(cached.mem().read_byte(0x10), 0xFF);
+    }
+}
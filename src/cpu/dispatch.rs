@@ -0,0 +1,91 @@
+//! Table of handlers that execute each opcode
+//!
+//! Handler is instantiated for each opcode from `opcode::table`, so addressing
+//! mode and mnemonic are constant in it and the matches in `fetch_address` and
+//! `execute` are removed at compile time.
+
+use super::{Cpu, CpuHooks, InstructionEvent};
+use crate::memory::Memory;
+use crate::opcode::table;
+
+// Handlers of opcode $h0 - $hF
+macro_rules! row {
+    ($exec:ident, $h:literal) => {
+        [
+            $exec::<M, H, { $h * 16 + 0x0 }>, $exec::<M, H, { $h * 16 + 0x1 }>,
+            $exec::<M, H, { $h * 16 + 0x2 }>, $exec::<M, H, { $h * 16 + 0x3 }>,
+            $exec::<M, H, { $h * 16 + 0x4 }>, $exec::<M, H, { $h * 16 + 0x5 }>,
+            $exec::<M, H, { $h * 16 + 0x6 }>, $exec::<M, H, { $h * 16 + 0x7 }>,
+            $exec::<M, H, { $h * 16 + 0x8 }>, $exec::<M, H, { $h * 16 + 0x9 }>,
+            $exec::<M, H, { $h * 16 + 0xA }>, $exec::<M, H, { $h * 16 + 0xB }>,
+            $exec::<M, H, { $h * 16 + 0xC }>, $exec::<M, H, { $h * 16 + 0xD }>,
+            $exec::<M, H, { $h * 16 + 0xE }>, $exec::<M, H, { $h * 16 + 0xF }>,
+        ]
+    };
+}
+
+type Handler<M, H> = fn(&mut Cpu<M, H>);
+type DecodedHandler<M, H> = fn(&mut Cpu<M, H>, u16);
+
+impl<M: Memory, H: CpuHooks> Cpu<M, H> {
+    /// Handler of each opcode, indexed by opcode. Opcode is already fetched when called.
+    pub(super) const DISPATCH: [Handler<M, H>; 256] = flatten([
+        row!(exec, 0x0), row!(exec, 0x1), row!(exec, 0x2), row!(exec, 0x3),
+        row!(exec, 0x4), row!(exec, 0x5), row!(exec, 0x6), row!(exec, 0x7),
+        row!(exec, 0x8), row!(exec, 0x9), row!(exec, 0xA), row!(exec, 0xB),
+        row!(exec, 0xC), row!(exec, 0xD), row!(exec, 0xE), row!(exec, 0xF),
+    ]);
+
+    /// Handler of each opcode for decoded instruction. Operand is given instead of fetched,
+    /// and pc must point the instruction when called.
+    pub(super) const DECODED: [DecodedHandler<M, H>; 256] = flatten([
+        row!(exec_decoded, 0x0), row!(exec_decoded, 0x1), row!(exec_decoded, 0x2), row!(exec_decoded, 0x3),
+        row!(exec_decoded, 0x4), row!(exec_decoded, 0x5), row!(exec_decoded, 0x6), row!(exec_decoded, 0x7),
+        row!(exec_decoded, 0x8), row!(exec_decoded, 0x9), row!(exec_decoded, 0xA), row!(exec_decoded, 0xB),
+        row!(exec_decoded, 0xC), row!(exec_decoded, 0xD), row!(exec_decoded, 0xE), row!(exec_decoded, 0xF),
+    ]);
+}
+
+fn exec<M: Memory, H: CpuHooks, const OPCODE: u8>(cpu: &mut Cpu<M, H>) {
+    let info = match const { table()[OPCODE as usize] } {
+        Some(info) => info,
+        None => panic!("Invalid opcode: 0x{:x}", OPCODE),
+    };
+    let pc     = cpu.reg.pc.wrapping_sub(1);
+    let addr   = cpu.fetch_address(info.mode);
+    let cycles = cpu.cycles;
+
+    run(cpu, InstructionEvent { pc, opcode: OPCODE, info, addr, cycles });
+}
+
+fn exec_decoded<M: Memory, H: CpuHooks, const OPCODE: u8>(cpu: &mut Cpu<M, H>, operand: u16) {
+    let info = match const { table()[OPCODE as usize] } {
+        Some(info) => info,
+        None => panic!("Invalid opcode: 0x{:x}", OPCODE),
+    };
+    let pc     = cpu.reg.pc;
+    cpu.reg.pc = cpu.reg.pc.wrapping_add(info.byte as u16);
+    let addr   = cpu.operand_address(info.mode, operand);
+    let cycles = cpu.cycles;
+
+    run(cpu, InstructionEvent { pc, opcode: OPCODE, info, addr, cycles });
+}
+
+// Execute fetched instruction between the hooks
+#[inline(always)]
+fn run<M: Memory, H: CpuHooks>(cpu: &mut Cpu<M, H>, event: InstructionEvent) {
+    cpu.hooks.before_instruction(&cpu.reg, &event);
+    cpu.execute(event.addr, event.info.name, event.info.mode);
+    cpu.cycles += event.info.cycle as u64;
+    cpu.hooks.after_instruction(&cpu.reg, &event);
+}
+
+const fn flatten<T: Copy>(rows: [[T; 16]; 16]) -> [T; 256] {
+    let mut table = [rows[0][0]; 256];
+    let mut i = 0;
+    while i < 256 {
+        table[i] = rows[i / 16][i % 16];
+        i += 1;
+    }
+    table
+}
@@ -1,13 +1,16 @@
-use super::Cpu;
+use super::{Cpu, CpuHooks, Interrupt};
+use crate::memory::Memory;
 use crate::opcode::{Mnemonic, AddressingMode};
 use crate::register::Status;
 
-impl Cpu {
-    pub fn execute(&mut self, addr: u16, name: &Mnemonic, mode: &AddressingMode) {
+impl<M: Memory, H: CpuHooks> Cpu<M, H> {
+    // Inlined so that match is removed when name and mode are constant
+    #[inline(always)]
+    pub(super) fn execute(&mut self, addr: u16, name: Mnemonic, mode: AddressingMode) {
         match name {
             Mnemonic::Adc => self.adc(addr),
             Mnemonic::And => self.and(addr),
-            Mnemonic::Asl if *mode == AddressingMode::Accumulator => self.asl_acc(),
+            Mnemonic::Asl if mode == AddressingMode::Accumulator => self.asl_acc(),
             Mnemonic::Asl => self.asl(addr),
             Mnemonic::Bcc => self.bcc(addr),
             Mnemonic::Bcs => self.bcs(addr),
@@ -38,7 +41,7 @@ impl Cpu {
             Mnemonic::Lda => self.lda(addr),
             Mnemonic::Ldx => self.ldx(addr),
             Mnemonic::Ldy => self.ldy(addr),
-            Mnemonic::Lsr if *mode == AddressingMode::Accumulator => self.lsr_acc(),
+            Mnemonic::Lsr if mode == AddressingMode::Accumulator => self.lsr_acc(),
             Mnemonic::Lsr => self.lsr(addr),
             Mnemonic::Nop => self.nop(),
             Mnemonic::Ora => self.ora(addr),
@@ -46,9 +49,9 @@ impl Cpu {
             Mnemonic::Php => self.php(),
             Mnemonic::Pla => self.pla(),
             Mnemonic::Plp => self.plp(),
-            Mnemonic::Rol if *mode == AddressingMode::Accumulator => self.rol_acc(),
+            Mnemonic::Rol if mode == AddressingMode::Accumulator => self.rol_acc(),
             Mnemonic::Rol => self.rol(addr),
-            Mnemonic::Ror if *mode == AddressingMode::Accumulator => self.ror_acc(),
+            Mnemonic::Ror if mode == AddressingMode::Accumulator => self.ror_acc(),
             Mnemonic::Ror => self.ror(addr),
             Mnemonic::Rti => self.rti(),
             Mnemonic::Rts => self.rts(),
@@ -70,7 +73,7 @@ impl Cpu {
 
     fn adc(&mut self, addr: u16) {
         let carry        = if self.reg.p.contains(Status::CARRY) { 1 } else { 0 };
-        let value_to_add = self.mem.read_byte(addr).overflowing_add(carry);
+        let value_to_add = self.read(addr).overflowing_add(carry);
         let result       = self.reg.a.overflowing_add(value_to_add.0);
         let is_carry     = value_to_add.1 | result.1;
         let is_overflow  = (self.reg.a >> 7) == (value_to_add.0 >> 7) &&
@@ -83,7 +86,7 @@ impl Cpu {
     }
 
     fn and(&mut self, addr: u16) {
-        self.reg.a &= self.mem.read_byte(addr);
+        self.reg.a &= self.read(addr);
 
         self.reg.p.update_zero_and_negative(self.reg.a);
     }
@@ -97,10 +100,10 @@ impl Cpu {
     }
 
     fn asl(&mut self, addr: u16) {
-        let value    = self.mem.read_byte(addr);
+        let value    = self.read(addr);
         let is_carry = value >> 7 == 1;
         let result   = value << 1;
-        self.mem.write_byte(addr, result);
+        self.write(addr, result);
 
         self.reg.p.set(Status::CARRY, is_carry);
         self.reg.p.update_zero_and_negative(result);
@@ -119,9 +122,11 @@ impl Cpu {
     }
 
     fn bit(&mut self, addr: u16) {
-        self.reg.p.set(Status::NEGATIVE, self.mem.read_byte(addr) & 0b1000_0000 != 0);
-        self.reg.p.set(Status::OVERFLOW, self.mem.read_byte(addr) & 0b0100_0000 != 0);
-        self.reg.p.set(Status::ZERO,     self.mem.read_byte(addr) & self.reg.a  != 0);
+        let value = self.read(addr);
+
+        self.reg.p.set(Status::NEGATIVE, value & 0b1000_0000 != 0);
+        self.reg.p.set(Status::OVERFLOW, value & 0b0100_0000 != 0);
+        self.reg.p.set(Status::ZERO,     value & self.reg.a  != 0);
     }
 
     fn bmi(&mut self, addr: u16) {
@@ -138,11 +143,12 @@ impl Cpu {
 
     fn brk(&mut self) {
         if !self.reg.p.contains(Status::INTERRUPT) {
+            self.hooks.interrupt(&self.reg, Interrupt::Brk);
             self.push_word(self.reg.pc);
             self.reg.p.insert(Status::BREAK);
             self.push_byte(self.reg.p.as_bits());
 
-            self.reg.pc = self.mem.read_word(0xFFFE);
+            self.reg.pc = self.read_word(0xFFFE);
         }
     }
 
@@ -171,29 +177,29 @@ impl Cpu {
     }
 
     fn cmp(&mut self, addr: u16) {
-        let result = self.reg.a.overflowing_add(self.mem.read_byte(addr));
+        let result = self.reg.a.overflowing_add(self.read(addr));
 
         self.reg.p.set(Status::CARRY, !result.1);
         self.reg.p.update_zero_and_negative(result.0);
     }
 
     fn cpx(&mut self, addr: u16) {
-        let result = self.reg.x.overflowing_add(self.mem.read_byte(addr));
+        let result = self.reg.x.overflowing_add(self.read(addr));
 
         self.reg.p.set(Status::CARRY, !result.1);
         self.reg.p.update_zero_and_negative(result.0);
     }
 
     fn cpy(&mut self, addr: u16) {
-        let result = self.reg.y.overflowing_add(self.mem.read_byte(addr));
+        let result = self.reg.y.overflowing_add(self.read(addr));
 
         self.reg.p.set(Status::CARRY, !result.1);
         self.reg.p.update_zero_and_negative(result.0);
     }
 
     fn dec(&mut self, addr: u16) {
-        let result = self.mem.read_byte(addr).wrapping_sub(1);
-        self.mem.write_byte(addr, result);
+        let result = self.read(addr).wrapping_sub(1);
+        self.write(addr, result);
 
         self.reg.p.update_zero_and_negative(result);
     }
@@ -211,14 +217,14 @@ impl Cpu {
     }
 
     fn eor(&mut self, addr: u16) {
-        self.reg.a ^= self.mem.read_byte(addr);
+        self.reg.a ^= self.read(addr);
 
         self.reg.p.update_zero_and_negative(self.reg.a);
     }
 
     fn inc(&mut self, addr: u16) {
-        let result = self.mem.read_byte(addr).wrapping_add(1);
-        self.mem.write_byte(addr, result);
+        let result = self.read(addr).wrapping_add(1);
+        self.write(addr, result);
 
         self.reg.p.update_zero_and_negative(result);
     } 
@@ -246,19 +252,19 @@ impl Cpu {
     }
 
     fn lda(&mut self, addr: u16) {
-        self.reg.a = self.mem.read_byte(addr);
+        self.reg.a = self.read(addr);
 
         self.reg.p.update_zero_and_negative(self.reg.a);
     }
 
     fn ldx(&mut self, addr: u16) {
-        self.reg.x = self.mem.read_byte(addr);
+        self.reg.x = self.read(addr);
 
         self.reg.p.update_zero_and_negative(self.reg.x);
     }
 
     fn ldy(&mut self, addr: u16) {
-        self.reg.x = self.mem.read_byte(addr);
+        self.reg.x = self.read(addr);
 
         self.reg.p.update_zero_and_negative(self.reg.x);
     }
@@ -272,10 +278,10 @@ impl Cpu {
     }
 
     fn lsr(&mut self, addr: u16) {
-        let value    = self.mem.read_byte(addr);
+        let value    = self.read(addr);
         let is_carry = value & 0b0000_0001 == 0b0000_0001;
         let result   = value >> 1;
-        self.mem.write_byte(addr, result);
+        self.write(addr, result);
 
         self.reg.p.set(Status::CARRY, is_carry);
         self.reg.p.update_zero_and_negative(result);
@@ -284,7 +290,7 @@ impl Cpu {
     fn nop(&mut self) {}
 
     fn ora(&mut self, addr: u16) {
-        self.reg.a |= self.mem.read_byte(addr);
+        self.reg.a |= self.read(addr);
 
         self.reg.p.update_zero_and_negative(self.reg.a);
     }
@@ -317,11 +323,11 @@ impl Cpu {
     }
 
     fn rol(&mut self, addr: u16) {
-        let value    = self.mem.read_byte(addr);
+        let value    = self.read(addr);
         let is_carry = value >> 7 == 1;
         let carry    = if self.reg.p.contains(Status::CARRY) { 1 } else { 0 };
         let result   = (value << 1) + carry;
-        self.mem.write_byte(addr, result);
+        self.write(addr, result);
 
         self.reg.p.set(Status::CARRY, is_carry);
         self.reg.p.update_zero_and_negative(result);
@@ -337,11 +343,11 @@ impl Cpu {
     }
 
     fn ror(&mut self, addr: u16) {
-        let value    = self.mem.read_byte(addr);
+        let value    = self.read(addr);
         let is_carry = value & 0b0000_0001 == 0b0000_0001;
         let carry    = if self.reg.p.contains(Status::CARRY) { 0b1000_0000 } else { 0 };
         let result   = (value >> 1) + carry;
-        self.mem.write_byte(addr, result);
+        self.write(addr, result);
 
         self.reg.p.set(Status::CARRY, is_carry);
         self.reg.p.update_zero_and_negative(result);
@@ -353,13 +359,12 @@ impl Cpu {
     }
 
     fn rts(&mut self) {
-        self.reg.p  = Status::from_bits(self.pull_byte());
         self.reg.pc = self.pull_word().wrapping_add(1);
     }
 
     fn sbc(&mut self, addr: u16) {
         let carry        = if self.reg.p.contains(Status::CARRY) { 0 } else { 1 };
-        let value_to_sub = self.mem.read_byte(addr).overflowing_add(carry);
+        let value_to_sub = self.read(addr).overflowing_add(carry);
         let result       = self.reg.a.overflowing_sub(value_to_sub.0);
         let is_carry     = !(value_to_sub.1 | result.1);
         let is_overflow  = (self.reg.a >> 7) == (value_to_sub.0 >> 7) &&
@@ -384,15 +389,15 @@ impl Cpu {
     }
 
     fn sta(&mut self, addr: u16) {
-        self.mem.write_byte(addr, self.reg.a);
+        self.write(addr, self.reg.a);
     }
 
     fn stx(&mut self, addr: u16) {
-        self.mem.write_byte(addr, self.reg.x);
+        self.write(addr, self.reg.x);
     }
 
     fn sty(&mut self, addr: u16) {
-        self.mem.write_byte(addr, self.reg.y);
+        self.write(addr, self.reg.y);
     }
 
     fn tax(&mut self) {
@@ -435,25 +440,56 @@ impl Cpu {
         }
     }
 
-    fn push_byte(&mut self, byte: u8) {
-        self.mem.write_byte(self.reg.s as u16 + 0x0100, byte);
+    pub(super) fn push_byte(&mut self, byte: u8) {
+        let addr = self.reg.s as u16 + 0x0100;
+        self.write(addr, byte);
+        self.hooks.push(addr, byte);
         self.reg.s = self.reg.s.wrapping_sub(1);
     }
 
-    fn pull_byte(&mut self) -> u8 {
+    pub(super) fn pull_byte(&mut self) -> u8 {
         self.reg.s = self.reg.s.wrapping_add(1);
-        self.mem.read_byte(self.reg.s as u16 + 0x0100)
+        let addr  = self.reg.s as u16 + 0x0100;
+        let value = self.read(addr);
+        self.hooks.pull(addr, value);
+        value
     }
 
-    fn push_word(&mut self, word: u16) {
+    pub(super) fn push_word(&mut self, word: u16) {
         let bytes = word.to_le_bytes();
         self.push_byte(bytes[0]);
         self.push_byte(bytes[1]);
     }
 
-    fn pull_word(&mut self) -> u16 {
+    pub(super) fn pull_word(&mut self) -> u16 {
         let msb = self.pull_byte();
         let lsb = self.pull_byte();
         u16::from_le_bytes([lsb, msb])
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::FlatMemory;
+
+    #[test]
+    fn test_rts_keeps_status() {
+        // JSR $8010 / RTS
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0x20, 0x10, 0x80]);
+        mem.load(0x8010, &[0x60]);
+
+        let mut cpu = Cpu::new(Box::new(mem));
+        cpu.reg_mut().pc = 0x8000;
+        cpu.reg_mut().s  = 0xFD;
+        cpu.reg_mut().p  = Status::from_bits(0xC3);
+
+        cpu.step();
+        assert_eq!(cpu.reg().pc, 0x8010);
+        cpu.step();
+        assert_eq!(cpu.reg().pc, 0x8003);
+        assert_eq!(cpu.reg().s, 0xFD);
+        assert_eq!(cpu.reg().p.as_bits(), 0xE3);
+    }
+}
@@ -1,32 +1,47 @@
-use super::Cpu;
-use crate::opcode::{OpcodeInfo, AddressingMode};
+use super::{Cpu, CpuHooks};
+use crate::memory::Memory;
+use crate::opcode::AddressingMode;
 
-impl Cpu {
-    pub fn fetch_opcode(&mut self) -> u8 {
+impl<M: Memory, H: CpuHooks> Cpu<M, H> {
+    pub(super) fn fetch_opcode(&mut self) -> u8 {
         self.fetch_byte()
     }
 
-    pub fn fetch_address(&mut self, info: &OpcodeInfo) -> u16 {
-        match info.mode {
+    // Inlined so that match is removed when mode is constant
+    #[inline(always)]
+    pub(super) fn fetch_address(&mut self, mode: AddressingMode) -> u16 {
+        let operand = match mode.operand_len() {
+            0 => 0,
+            1 => self.fetch_byte() as u16,
+            _ => self.fetch_word(),
+        };
+        self.operand_address(mode, operand)
+    }
+
+    /// Effective address of given operand. Pc must point the next instruction.
+    #[inline(always)]
+    pub(super) fn operand_address(&mut self, mode: AddressingMode, operand: u16) -> u16 {
+        match mode {
             AddressingMode::Accumulator | AddressingMode::Implied => 0,
-            AddressingMode::Absolute  => self.fetch_absolute_with_index(0),
-            AddressingMode::AbsoluteX => self.fetch_absolute_with_index(self.reg.x),
-            AddressingMode::AbsoluteY => self.fetch_absolute_with_index(self.reg.y),
-            AddressingMode::Immediate => self.fetch_immediate(),
-            AddressingMode::Indirect  => self.fetch_indirect(),
-            AddressingMode::IndirectX => self.fetch_indirect_with_index((self.reg.x, 0)),
-            AddressingMode::IndirectY => self.fetch_indirect_with_index((0, self.reg.y)),
-            AddressingMode::Relative  => self.fetch_relative(),
-            AddressingMode::ZeroPage  => self.fetch_zeropage_with_index(0),
-            AddressingMode::ZeroPageX => self.fetch_zeropage_with_index(self.reg.x),
-            AddressingMode::ZeroPageY => self.fetch_zeropage_with_index(self.reg.y),
+            AddressingMode::Absolute  => operand,
+            AddressingMode::AbsoluteX => operand.wrapping_add(self.reg.x as u16),
+            AddressingMode::AbsoluteY => operand.wrapping_add(self.reg.y as u16),
+            AddressingMode::Immediate => self.reg.pc.wrapping_sub(1),
+            AddressingMode::Indirect  => self.read_word(operand),
+            AddressingMode::IndirectX => self.indirect_with_index(operand as u8, (self.reg.x, 0)),
+            AddressingMode::IndirectY => self.indirect_with_index(operand as u8, (0, self.reg.y)),
+            AddressingMode::Relative  => self.relative(operand as u8),
+            AddressingMode::ZeroPage  => operand,
+            AddressingMode::ZeroPageX => (operand as u8).wrapping_add(self.reg.x) as u16,
+            AddressingMode::ZeroPageY => (operand as u8).wrapping_add(self.reg.y) as u16,
         }
     }
 
-    fn fetch_byte(&mut self) -> u8 {
-        let ret = self.mem.read_byte(self.reg.pc);
+    pub(super) fn fetch_byte(&mut self) -> u8 {
+        let value = self.mem.read_byte(self.reg.pc);
+        let value = self.hooks.fetch(self.reg.pc, value);
         self.reg.pc = self.reg.pc.wrapping_add(1);
-        ret
+        value
     }
 
     fn fetch_word(&mut self) -> u16 {
@@ -35,36 +50,16 @@ impl Cpu {
         u16::from_le_bytes([lsb, msb])
     }
 
-    fn fetch_absolute_with_index(&mut self, index: u8) -> u16 {
-        self.fetch_word().wrapping_add(index as u16)
-    }
-
-    fn fetch_immediate(&mut self) -> u16 {
-        let addr = self.reg.pc;
-        let _ = self.fetch_byte();
-        addr
-    }
-
-    fn fetch_indirect(&mut self) -> u16 {
-        let addr = self.fetch_word();
-        self.mem.read_word(addr)
-    }
-
-    fn fetch_indirect_with_index(&mut self, index: (u8, u8)) -> u16 {
-        let addr = self.fetch_byte().wrapping_add(index.0) as u16;
-        self.mem.read_word(addr).wrapping_add(index.1 as u16)
+    fn indirect_with_index(&mut self, operand: u8, index: (u8, u8)) -> u16 {
+        let addr = operand.wrapping_add(index.0) as u16;
+        self.read_word(addr).wrapping_add(index.1 as u16)
     }
 
-    fn fetch_relative(&mut self) -> u16 {
-        let offset = self.fetch_byte();
+    fn relative(&self, offset: u8) -> u16 {
         if offset >> 7 == 1 {
             self.reg.pc.wrapping_sub((!offset).wrapping_add(1) as u16)
         } else {
             self.reg.pc.wrapping_add(offset as u16)
         }
     }
-
-    fn fetch_zeropage_with_index(&mut self, index: u8) -> u16 {
-        self.fetch_byte().wrapping_add(index) as u16
-    }
 }
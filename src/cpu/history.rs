@@ -0,0 +1,121 @@
+use std::collections::VecDeque;
+
+use super::{Cpu, CpuHooks};
+use crate::memory::Memory;
+use super::interrupt::InterruptLines;
+use crate::register::Register;
+
+/// Undo log of the last steps, used to execute backward
+pub(super) struct History {
+    capacity: usize,
+    records: VecDeque<Record>,
+}
+
+// State before one step and old values of the memory written by the step
+struct Record {
+    reg: Register,
+    lines: InterruptLines,
+    cycles: u64,
+    writes: Vec<(u16, u8)>,
+}
+
+impl History {
+    pub(super) fn clear(&mut self) {
+        self.records.clear();
+    }
+}
+
+impl<M: Memory, H: CpuHooks> Cpu<M, H> {
+    /// Record last `capacity` steps so that they can be undone by `step_back`.
+    /// Zero disables recording and drops the recorded steps.
+    ///
+    /// Old value of written memory is read by `Memory::peek` before the write. Writes
+    /// to device registers (`Memory::is_io`) are not recorded, since writing them
+    /// again would run the device, like OAM DMA. So the state of devices is not
+    /// restored by `step_back`, only the cpu and the storage.
+    pub fn set_history(&mut self, capacity: usize) {
+        self.history = match capacity {
+            0 => None,
+            _ => Some(History { capacity, records: VecDeque::with_capacity(capacity) }),
+        };
+    }
+
+    /// Number of steps that can be undone
+    pub fn history_len(&self) -> usize {
+        self.history.as_ref().map_or(0, |h| h.records.len())
+    }
+
+    /// Undo last step. Return false if no step is recorded.
+    pub fn step_back(&mut self) -> bool {
+        let Some(record) = self.history.as_mut().and_then(|h| h.records.pop_back()) else {
+            return false;
+        };
+        for (addr, value) in record.writes.into_iter().rev() {
+            self.mem.write_byte(addr, value);
+        }
+        self.reg    = record.reg;
+        self.lines  = record.lines;
+        self.cycles = record.cycles;
+        if let Some(log) = &mut self.bus_log {
+            log.clear();
+        }
+        true
+    }
+
+    pub(super) fn record_step(&mut self) {
+        let Some(history) = &mut self.history else { return };
+        if history.records.len() == history.capacity {
+            history.records.pop_front();
+        }
+        history.records.push_back(Record {
+            reg: self.reg.clone(),
+            lines: self.lines.clone(),
+            cycles: self.cycles,
+            writes: Vec::new(),
+        });
+    }
+
+    pub(super) fn record_write(&mut self, addr: u16) {
+        if self.mem.is_io(addr) {
+            return;
+        }
+        let Some(record) = self.history.as_mut().and_then(|h| h.records.back_mut()) else {
+            return;
+        };
+        record.writes.push((addr, self.mem.peek(addr)));
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::Cpu;
+    use crate::memory::FlatMemory;
+
+    #[test]
+    fn test_step_back() {
+        // LDA #$42; STA $10; PHA; INC $10
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0xA9, 0x42, 0x85, 0x10, 0x48, 0xE6, 0x10]);
+        let mut cpu = Cpu::new(Box::new(mem));
+        cpu.reg_mut().pc = 0x8000;
+        cpu.reg_mut().s  = 0xFF;
+        cpu.set_history(3);
+
+        for _ in 0..4 {
+            cpu.step();
+        }
+        assert_eq!(cpu.history_len(), 3);
+        assert_eq!(cpu.mem().read_byte(0x10), 0x43);
+
+        assert!(cpu.step_back());
+        assert_eq!(cpu.mem().read_byte(0x10), 0x42);
+        assert!(cpu.step_back());
+        assert_eq!(cpu.mem().read_byte(0x01FF), 0x00);
+        assert_eq!(cpu.reg().s, 0xFF);
+        assert!(cpu.step_back());
+        assert_eq!(cpu.mem().read_byte(0x10), 0x00);
+        assert_eq!(cpu.reg().pc, 0x8002);
+        assert_eq!(cpu.cycles(), 2);
+        assert!(!cpu.step_back());
+    }
+}
@@ -0,0 +1,141 @@
+use crate::opcode::OpcodeInfo;
+use crate::register::Register;
+
+/// Kind of interrupt sequence
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Interrupt {
+    Reset,
+    Nmi,
+    Irq,
+    Brk,
+}
+
+/// Instruction given to `CpuHooks`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct InstructionEvent {
+    /// Address of the opcode
+    pub pc: u16,
+    pub opcode: u8,
+    pub info: OpcodeInfo,
+    /// Effective address calculated by addressing mode. 0 for implied and accumulator.
+    pub addr: u16,
+    /// Cycle counter of the cpu when the instruction started
+    pub cycles: u64,
+}
+
+/// Callbacks called by the cpu, to observe execution without changing the core.
+///
+/// All methods do nothing by default. Since the cpu is generic over hooks, calls
+/// to `NoHooks` are removed at compile time.
+#[allow(unused_variables)]
+pub trait CpuHooks {
+    /// Called after operand is fetched, before the instruction is executed
+    #[inline(always)]
+    fn before_instruction(&mut self, reg: &Register, event: &InstructionEvent) {}
+
+    #[inline(always)]
+    fn after_instruction(&mut self, reg: &Register, event: &InstructionEvent) {}
+
+    /// Called on opcode and operand bytes fetched at pc. Returned value is executed
+    /// instead of the value read.
+    #[inline(always)]
+    fn fetch(&mut self, addr: u16, value: u8) -> u8 {
+        value
+    }
+
+    /// Called on every other read: data of instructions, stack and interrupt vectors.
+    /// Returned value is given to the cpu instead of the value read, so it can be
+    /// replaced like a cheat device.
+    #[inline(always)]
+    fn read(&mut self, addr: u16, value: u8) -> u8 {
+        value
+    }
+
+    /// Called on data write by an instruction, after the memory is written
+    #[inline(always)]
+    fn write(&mut self, addr: u16, value: u8) {}
+
+    /// Called when a byte is pushed to stack, in addition to `write`
+    #[inline(always)]
+    fn push(&mut self, addr: u16, value: u8) {}
+
+    /// Called when a byte is pulled from stack, in addition to `read`
+    #[inline(always)]
+    fn pull(&mut self, addr: u16, value: u8) {}
+
+    /// Called when interrupt sequence starts, before return address is pushed.
+    /// BRK is reported only when it is taken.
+    #[inline(always)]
+    fn interrupt(&mut self, reg: &Register, kind: Interrupt) {}
+}
+
+/// Hooks that do nothing
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoHooks;
+
+impl CpuHooks for NoHooks {}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::memory::FlatMemory;
+    use crate::opcode::Mnemonic;
+
+    #[derive(Default)]
+    struct Recorder {
+        names: Vec<(u16, Mnemonic, u16)>,
+        pushes: Vec<u8>,
+        interrupts: Vec<Interrupt>,
+        fetches: Vec<u16>,
+        reads: Vec<u16>,
+    }
+
+    impl CpuHooks for Recorder {
+        fn before_instruction(&mut self, _: &Register, event: &InstructionEvent) {
+            self.names.push((event.pc, event.info.name, event.addr));
+        }
+
+        fn fetch(&mut self, addr: u16, value: u8) -> u8 {
+            self.fetches.push(addr);
+            value
+        }
+
+        // Replace value of $10 like a cheat
+        fn read(&mut self, addr: u16, value: u8) -> u8 {
+            self.reads.push(addr);
+            if addr == 0x10 { 0x42 } else { value }
+        }
+
+        fn push(&mut self, _: u16, value: u8) {
+            self.pushes.push(value);
+        }
+
+        fn interrupt(&mut self, _: &Register, kind: Interrupt) {
+            self.interrupts.push(kind);
+        }
+    }
+
+    #[test]
+    fn test_hooks() {
+        // LDA $10; PHA
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0xA5, 0x10, 0x48]);
+        let mut cpu = Cpu::with_hooks(mem, Recorder::default());
+        cpu.reg_mut().pc = 0x8000;
+        cpu.reg_mut().s  = 0xFF;
+
+        cpu.run_instructions(2);
+        cpu.set_nmi(true);
+        cpu.step();
+
+        let hooks = cpu.hooks();
+        assert_eq!(hooks.names, [(0x8000, Mnemonic::Lda, 0x10), (0x8002, Mnemonic::Pha, 0)]);
+        // Value of PHA is the replaced one, then return address and status by NMI
+        assert_eq!(hooks.pushes.len(), 4);
+        assert_eq!(hooks.pushes[0], 0x42);
+        assert_eq!(hooks.interrupts, [Interrupt::Nmi]);
+        assert_eq!(hooks.fetches, [0x8000, 0x8001, 0x8002]);
+        assert_eq!(hooks.reads, [0x10, 0xFFFA, 0xFFFB]);
+    }
+}
@@ -1,2 +1,74 @@
-// TODO:
-// - [ ] Write code to treat interrupt
+use super::{Cpu, CpuHooks, Interrupt};
+use crate::memory::Memory;
+use crate::register::Status;
+
+const NMI_VECTOR:   u16 = 0xFFFA;
+const RESET_VECTOR: u16 = 0xFFFC;
+const IRQ_VECTOR:   u16 = 0xFFFE;
+
+/// State of the interrupt lines connected to the cpu
+#[derive(Default, Clone)]
+pub(super) struct InterruptLines {
+    pub(super) nmi: bool,
+    pub(super) nmi_pending: bool,
+    pub(super) irq: bool,
+}
+
+impl<M: Memory, H: CpuHooks> Cpu<M, H> {
+    /// Set level of IRQ line. IRQ is served while line is asserted and I flag is clear.
+    pub fn set_irq(&mut self, level: bool) {
+        self.lines.irq = level;
+    }
+
+    /// Set level of NMI line. NMI is served once on rising edge.
+    pub fn set_nmi(&mut self, level: bool) {
+        if level && !self.lines.nmi {
+            self.lines.nmi_pending = true;
+        }
+        self.lines.nmi = level;
+    }
+
+    pub fn irq_line(&self) -> bool {
+        self.lines.irq
+    }
+
+    pub fn nmi_line(&self) -> bool {
+        self.lines.nmi
+    }
+
+    /// Do reset sequence: load pc from reset vector and disable interrupt
+    pub fn reset(&mut self) {
+        self.hooks.interrupt(&self.reg, Interrupt::Reset);
+        self.reg.s  = self.reg.s.wrapping_sub(3);
+        self.reg.pc = self.read_word(RESET_VECTOR);
+        self.reg.p.insert(Status::INTERRUPT);
+        self.lines.nmi_pending = false;
+        self.cycles += super::INTERRUPT_CYCLE;
+    }
+
+    /// Serve pending interrupt if exist. Return true if interrupt was served.
+    pub(super) fn poll_interrupt(&mut self) -> bool {
+        if self.lines.nmi_pending {
+            self.lines.nmi_pending = false;
+            self.interrupt(NMI_VECTOR, Interrupt::Nmi);
+            true
+        } else if self.lines.irq && !self.reg.p.contains(Status::INTERRUPT) {
+            self.interrupt(IRQ_VECTOR, Interrupt::Irq);
+            true
+        } else {
+            false
+        }
+    }
+
+    fn interrupt(&mut self, vector: u16, kind: Interrupt) {
+        self.hooks.interrupt(&self.reg, kind);
+        let mut p = Status::from_bits(self.reg.p.as_bits());
+        p.remove(Status::BREAK);
+
+        self.push_word(self.reg.pc);
+        self.push_byte(p.as_bits());
+        self.reg.p.insert(Status::INTERRUPT);
+
+        self.reg.pc = self.read_word(vector);
+    }
+}
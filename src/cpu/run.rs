@@ -0,0 +1,95 @@
+use super::{Cpu, CpuHooks};
+use crate::memory::Memory;
+
+/// Reason why a run stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RunStop {
+    /// Cycles given to `run_cycles` were run. Value is the cycles run past it,
+    /// since the last instruction is not stopped in the middle.
+    Cycles(u64),
+    /// Instructions given to `run_instructions` were executed
+    Instructions,
+    /// Condition of `run_until` or `run_until_pc` became true, or `Nes::run_frame`
+    /// completed a frame
+    Condition,
+    /// Cycle limit was reached before the condition became true
+    Timeout,
+}
+
+impl<M: Memory, H: CpuHooks> Cpu<M, H> {
+    /// Run at least given cycles
+    pub fn run_cycles(&mut self, cycles: u64) -> RunStop {
+        let end = self.cycles + cycles;
+        while self.cycles < end {
+            self.step();
+        }
+        RunStop::Cycles(self.cycles - end)
+    }
+
+    /// Execute given number of instructions. Interrupt sequence is counted as one.
+    pub fn run_instructions(&mut self, count: u64) -> RunStop {
+        for _ in 0..count {
+            self.step();
+        }
+        RunStop::Instructions
+    }
+
+    /// Run until `done` returns true, or `max_cycles` cycles are run.
+    /// `done` is checked before each instruction, including the first one.
+    pub fn run_until<F>(&mut self, max_cycles: u64, mut done: F) -> RunStop
+    where
+        F: FnMut(&Self) -> bool,
+    {
+        let end = self.cycles + max_cycles;
+        loop {
+            if done(self) {
+                return RunStop::Condition;
+            }
+            if self.cycles >= end {
+                return RunStop::Timeout;
+            }
+            self.step();
+        }
+    }
+
+    /// Run until pc becomes given address, or `max_cycles` cycles are run
+    pub fn run_until_pc(&mut self, pc: u16, max_cycles: u64) -> RunStop {
+        self.run_until(max_cycles, |cpu| cpu.reg.pc == pc)
+    }
+
+    /// Run cycles of one video frame of the region, for a cpu without PPU.
+    /// Result is the one of `run_cycles`. See `Nes::run_frame` to wait the PPU.
+    pub fn run_frame(&mut self) -> RunStop {
+        let cycles = self.region.frame_master_cycles() / self.region.cpu_divider();
+        self.run_cycles(cycles)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::FlatMemory;
+
+    #[test]
+    fn test_run() {
+        // $8000: INX; JMP $8000 (5 cycles a loop)
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0xE8, 0x4C, 0x00, 0x80]);
+        let mut cpu = Cpu::with_memory(mem);
+        cpu.reg_mut().pc = 0x8000;
+
+        assert_eq!(cpu.run_cycles(11), RunStop::Cycles(1));
+        assert_eq!(cpu.run_instructions(3), RunStop::Instructions);
+        assert_eq!(cpu.reg().pc, 0x8000);
+        assert_eq!(cpu.reg().x, 4);
+        assert_eq!(cpu.run_until(1000, |cpu| cpu.reg().x == 10), RunStop::Condition);
+        assert_eq!(cpu.run_until_pc(0x8001, 1000), RunStop::Condition);
+        assert_eq!(cpu.run_until_pc(0x9000, 1000), RunStop::Timeout);
+        assert!(cpu.cycles() >= 1000);
+
+        // 29780 cycles of NTSC frame
+        let start = cpu.cycles();
+        let RunStop::Cycles(over) = cpu.run_frame() else { panic!() };
+        assert_eq!(cpu.cycles() - start, 29780 + over);
+    }
+}
@@ -0,0 +1,186 @@
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+use super::{Cpu, CpuHooks};
+use crate::memory::Memory;
+use crate::region::Region;
+use crate::register::Status;
+use crate::state::{StateError, StateReader, StateWriter};
+
+const CPU_TAG: [u8; 4] = *b"CPU ";
+const CPU_VERSION: u16 = 2;
+
+/// Registers and part of memory, in the same shape as `initial` and `final` of
+/// SingleStepTests json
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub struct CpuState {
+    pub pc: u16,
+    pub s: u8,
+    pub a: u8,
+    pub x: u8,
+    pub y: u8,
+    pub p: Status,
+    /// Pairs of address and value
+    pub ram: Vec<(u16, u8)>,
+}
+
+impl CpuState {
+    /// Take registers and memory at given addresses
+    pub fn capture<M: Memory, H: CpuHooks>(cpu: &Cpu<M, H>, addrs: impl IntoIterator<Item = u16>) -> CpuState {
+        let reg = cpu.reg();
+        let ram = addrs.into_iter().map(|addr| (addr, cpu.mem().peek(addr))).collect();
+        CpuState { pc: reg.pc, s: reg.s, a: reg.a, x: reg.x, y: reg.y, p: reg.p, ram }
+    }
+
+    /// Set registers and memory of the cpu
+    pub fn apply<M: Memory, H: CpuHooks>(&self, cpu: &mut Cpu<M, H>) {
+        let reg = cpu.reg_mut();
+        reg.pc = self.pc;
+        reg.s  = self.s;
+        reg.a  = self.a;
+        reg.x  = self.x;
+        reg.y  = self.y;
+        reg.p  = self.p;
+        for (addr, value) in &self.ram {
+            cpu.mem_mut().write_byte(*addr, *value);
+        }
+    }
+}
+
+impl<M: Memory, H: CpuHooks> Cpu<M, H> {
+    /// Save registers, cycle counter, interrupt lines and memory in versioned format.
+    /// Memory must implement `MemoryState`.
+    ///
+    /// Instruction is executed as a whole in a step, so there is no state in the
+    /// middle of instruction to save.
+    pub fn save_state(&self) -> Result<Vec<u8>, StateError> {
+        let mem = self.mem.state().ok_or(StateError::NotSupported)?;
+
+        let mut out = StateWriter::new();
+        out.write_header();
+        out.section(CPU_TAG, CPU_VERSION, |out| {
+            out.write_u8(self.reg.a);
+            out.write_u8(self.reg.x);
+            out.write_u8(self.reg.y);
+            out.write_u16(self.reg.pc);
+            out.write_u8(self.reg.s);
+            out.write_u8(self.reg.p.as_bits());
+            out.write_u64(self.cycles);
+            out.write_bool(self.lines.nmi);
+            out.write_bool(self.lines.nmi_pending);
+            out.write_bool(self.lines.irq);
+            out.write_u8(match self.region {
+                Region::Ntsc  => 0,
+                Region::Pal   => 1,
+                Region::Dendy => 2,
+            });
+        });
+        mem.save_state(&mut out);
+        Ok(out.into_bytes())
+    }
+
+    /// Restore state saved by `save_state`. Recorded history is dropped.
+    ///
+    /// On error nothing is changed: memory is restored from a copy taken before,
+    /// if it failed in the middle of loading.
+    pub fn load_state(&mut self, state: &[u8]) -> Result<(), StateError> {
+        let mut state = StateReader::new(state);
+        state.read_header()?;
+        let (version, mut input) = state.section(CPU_TAG, CPU_VERSION)?;
+        let mut reg = self.reg.clone();
+        reg.a  = input.read_u8()?;
+        reg.x  = input.read_u8()?;
+        reg.y  = input.read_u8()?;
+        reg.pc = input.read_u16()?;
+        reg.s  = input.read_u8()?;
+        reg.p  = Status::from_bits(input.read_u8()?);
+        let cycles      = input.read_u64()?;
+        let nmi         = input.read_bool()?;
+        let nmi_pending = input.read_bool()?;
+        let irq         = input.read_bool()?;
+        // Region is added in version 2
+        let region = match version {
+            1 => self.region,
+            _ => match input.read_u8()? {
+                0 => Region::Ntsc,
+                1 => Region::Pal,
+                2 => Region::Dendy,
+                n => return Err(StateError::InvalidData(format!("region {}", n))),
+            },
+        };
+
+        let mem = self.mem.state_mut().ok_or(StateError::NotSupported)?;
+        let mut backup = StateWriter::new();
+        mem.save_state(&mut backup);
+        if let Err(error) = mem.load_state(&mut state) {
+            let backup = backup.into_bytes();
+            mem.load_state(&mut StateReader::new(&backup)).expect("backup of memory state is valid");
+            return Err(error);
+        }
+
+        self.reg    = reg;
+        self.region = region;
+        self.cycles = cycles;
+        self.lines.nmi         = nmi;
+        self.lines.nmi_pending = nmi_pending;
+        self.lines.irq         = irq;
+        if let Some(history) = &mut self.history {
+            history.clear();
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use crate::cpu::Cpu;
+    use crate::memory::FlatMemory;
+    use crate::state::StateError;
+
+    #[test]
+    fn test_save_and_load() {
+        // LDA #$42; STA $10; INX
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0xA9, 0x42, 0x85, 0x10, 0xE8]);
+        let mut cpu = Cpu::new(Box::new(mem));
+        cpu.reg_mut().pc = 0x8000;
+        cpu.step();
+        let state = cpu.save_state().unwrap();
+
+        cpu.step();
+        cpu.step();
+        cpu.set_irq(true);
+        cpu.load_state(&state).unwrap();
+        assert_eq!(cpu.reg().pc, 0x8002);
+        assert_eq!(cpu.reg().a, 0x42);
+        assert_eq!(cpu.cycles(), 2);
+        assert!(!cpu.irq_line());
+        assert_eq!(cpu.mem().read_byte(0x10), 0x00);
+
+        assert_eq!(cpu.load_state(&state[..20]), Err(StateError::UnexpectedEnd));
+
+        let mut newer = state.clone();
+        newer[4] = 9;
+        let error = cpu.load_state(&newer).err().unwrap();
+        assert_eq!(error.to_string(), "state format version 9 is newer than supported version 1");
+        assert_eq!(cpu.reg().pc, 0x8002);
+    }
+
+    #[cfg(feature = "serde")]
+    #[test]
+    fn test_cpu_state_json() {
+        use crate::cpu::CpuState;
+
+        let json = r#"{"pc":32768,"s":253,"a":0,"x":1,"y":2,"p":36,"ram":[[32768,169],[32769,66]]}"#;
+        let state: CpuState = serde_json::from_str(json).unwrap();
+        let mut cpu = Cpu::new(Box::new(FlatMemory::new()));
+        state.apply(&mut cpu);
+        cpu.step();
+
+        let state = CpuState::capture(&cpu, [0x8000, 0x8001]);
+        assert_eq!(state.a, 0x42);
+        let json = r#"{"pc":32770,"s":253,"a":66,"x":1,"y":2,"p":36,"ram":[[32768,169],[32769,66]]}"#;
+        assert_eq!(serde_json::to_string(&state).unwrap(), json);
+    }
+}
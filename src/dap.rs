@@ -0,0 +1,558 @@
+//! Provide server of Debug Adapter Protocol
+//!
+//! The program given to `launch` is an iNES rom or a raw binary. Source
+//! breakpoints and source positions of stack frame are available when ld65
+//! debug info is given by `debugInfo`, or found next to the program with
+//! `.dbg` extension.
+//!
+//! Supported `launch` arguments:
+//!
+//! - `program`: path of rom or raw binary
+//! - `loadAddress`: address to load raw binary at, and start from (default 0)
+//! - `debugInfo`: path of debug info file
+//! - `stopOnEntry`: stop before executing the first instruction
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread;
+
+use serde_json::{json, Value};
+
+use crate::dbginfo::DbgInfo;
+use crate::debugger::{BreakOn, BreakpointId, Debugger, StopReason};
+use crate::loader;
+
+/// Cycles executed between checks of incoming requests while running or stepping
+const RUN_CHUNK: u64 = 10_000;
+
+const THREAD_ID:     i64 = 1;
+const REGISTERS_REF: i64 = 1;
+const STACK_REF:     i64 = 2;
+
+/// Error reported to the client as failed response
+struct RequestError(String);
+
+impl<E: std::error::Error> From<E> for RequestError {
+    fn from(error: E) -> Self {
+        RequestError(error.to_string())
+    }
+}
+
+type Response = Result<Value, RequestError>;
+
+#[derive(Default)]
+pub struct DapServer {
+    dbg: Option<Debugger>,
+    info: Option<DbgInfo>,
+    // Directory that paths in debug info are relative to
+    info_dir: PathBuf,
+    source_bps: HashMap<String, Vec<BreakpointId>>,
+    instruction_bps: Vec<BreakpointId>,
+    stop_on_entry: bool,
+    running: bool,
+    // Running is an unfinished `next` or `stepOut`
+    stepping: bool,
+    terminated: bool,
+    seq: i64,
+    pending: VecDeque<Value>,
+}
+
+impl DapServer {
+    pub fn new() -> DapServer {
+        DapServer::default()
+    }
+
+    /// Serve until client disconnect or close the stream
+    pub fn serve<R, W>(&mut self, reader: R, mut writer: W) -> io::Result<()>
+    where
+        R: Read + Send + 'static,
+        W: Write,
+    {
+        let (tx, rx) = mpsc::channel();
+        thread::spawn(move || read_messages(reader, tx));
+
+        while !self.terminated {
+            if self.running {
+                self.run_chunk(&mut writer)?;
+                self.poll(&rx)?;
+            }
+            let message = match self.pending.pop_front() {
+                Some(message) => message,
+                None if self.running => continue,
+                None => match rx.recv() {
+                    Ok(message) => message,
+                    Err(_) => return Ok(()),
+                },
+            };
+            self.dispatch(&message, &mut writer)?;
+        }
+        Ok(())
+    }
+
+    // Queue requests arrived while running
+    fn poll(&mut self, rx: &Receiver<Value>) -> io::Result<()> {
+        loop {
+            match rx.try_recv() {
+                Ok(message) => self.pending.push_back(message),
+                Err(TryRecvError::Empty) => return Ok(()),
+                Err(TryRecvError::Disconnected) => {
+                    self.terminated = self.pending.is_empty();
+                    return Ok(());
+                }
+            }
+        }
+    }
+
+    fn run_chunk<W: Write>(&mut self, writer: &mut W) -> io::Result<()> {
+        let Some(dbg) = self.dbg.as_mut() else {
+            self.running = false;
+            return Ok(());
+        };
+        let target = dbg.cpu().cycles() + RUN_CHUNK;
+        let reason = if self.stepping { dbg.continue_step(target) } else { dbg.run_until_cycle(target) };
+        let reason = match reason {
+            StopReason::Cycle => return Ok(()),
+            StopReason::Breakpoint(id) if self.instruction_bps.contains(&id) => "instruction breakpoint",
+            StopReason::Breakpoint(_) => "breakpoint",
+            StopReason::Watchpoint(..) => "data breakpoint",
+            StopReason::Step => "step",
+        };
+        self.running  = false;
+        self.stepping = false;
+        self.stopped(writer, reason)
+    }
+
+    fn dispatch<W: Write>(&mut self, message: &Value, writer: &mut W) -> io::Result<()> {
+        if message["type"] != "request" {
+            return Ok(());
+        }
+        let command = message["command"].as_str().unwrap_or_default();
+        let args    = &message["arguments"];
+
+        let result = match command {
+            "initialize"   => Ok(self.initialize()),
+            "launch"       => self.launch(args),
+            "setBreakpoints"            => self.set_breakpoints(args),
+            "setInstructionBreakpoints" => self.set_instruction_breakpoints(args),
+            "setExceptionBreakpoints"   => Ok(json!({ "breakpoints": [] })),
+            "configurationDone" => Ok(Value::Null),
+            "threads"      => Ok(json!({ "threads": [{ "id": THREAD_ID, "name": "6502" }] })),
+            "stackTrace"   => self.stack_trace(),
+            "scopes"       => Ok(self.scopes()),
+            "variables"    => self.variables(args),
+            "readMemory"   => self.read_memory(args),
+            "continue"     => Ok(json!({ "allThreadsContinued": true })),
+            "next" | "stepIn" | "stepOut" | "pause" => Ok(Value::Null),
+            "disconnect" | "terminate" => Ok(Value::Null),
+            _ => Err(RequestError(format!("unsupported request '{}'", command))),
+        };
+        let success = result.is_ok();
+        self.respond(writer, message, command, result)?;
+
+        match command {
+            // Breakpoints are set after it, so they need the launched program
+            "launch" if success => self.event(writer, "initialized", Value::Null)?,
+            "configurationDone" if self.stop_on_entry => self.stopped(writer, "entry")?,
+            "configurationDone" | "continue" => {
+                self.running  = self.dbg.is_some();
+                self.stepping = false;
+            }
+            "next" | "stepIn" | "stepOut" => self.step(writer, command)?,
+            "pause" => {
+                self.running  = false;
+                self.stepping = false;
+                self.stopped(writer, "pause")?;
+            }
+            "disconnect" | "terminate" => {
+                self.event(writer, "terminated", Value::Null)?;
+                self.terminated = true;
+            }
+            _ => (),
+        }
+        Ok(())
+    }
+
+    fn initialize(&self) -> Value {
+        json!({
+            "supportsConfigurationDoneRequest": true,
+            "supportsInstructionBreakpoints": true,
+            "supportsReadMemoryRequest": true,
+            "supportsTerminateRequest": true,
+        })
+    }
+
+    fn launch(&mut self, args: &Value) -> Response {
+        let program = args["program"]
+            .as_str()
+            .ok_or_else(|| RequestError("'program' is required".to_string()))?;
+        let load_addr = match &args["loadAddress"] {
+            Value::Null => 0,
+            value => parse_addr(value)
+                .ok_or_else(|| RequestError("invalid 'loadAddress'".to_string()))?,
+        };
+        let cpu = loader::load_image(&std::fs::read(program)?, load_addr)?;
+
+        let info_path = match args["debugInfo"].as_str() {
+            Some(path) => Some(PathBuf::from(path)),
+            None => Some(Path::new(program).with_extension("dbg")).filter(|path| path.exists()),
+        };
+        if let Some(path) = info_path {
+            self.info = Some(DbgInfo::parse(&std::fs::read_to_string(&path)?)?);
+            self.info_dir = path.parent().map(Path::to_path_buf).unwrap_or_default();
+        }
+
+        self.dbg = Some(Debugger::new(cpu));
+        self.stop_on_entry = args["stopOnEntry"].as_bool().unwrap_or(false);
+        Ok(Value::Null)
+    }
+
+    fn set_breakpoints(&mut self, args: &Value) -> Response {
+        let dbg  = launched(&mut self.dbg)?;
+        let path = args["source"]["path"].as_str().unwrap_or_default().to_string();
+        for id in self.source_bps.remove(&path).unwrap_or_default() {
+            dbg.remove_breakpoint(id);
+        }
+
+        let mut ids     = Vec::new();
+        let mut results = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            let line  = bp["line"].as_u64().unwrap_or_default() as u32;
+            let addrs = self.info.as_ref().map(|info| info.addrs_of(&path, line)).unwrap_or_default();
+            for addr in &addrs {
+                ids.push(dbg.add_breakpoint(BreakOn::Pc(*addr)));
+            }
+            results.push(match addrs.first() {
+                Some(addr) => json!({
+                    "verified": true,
+                    "line": line,
+                    "instructionReference": format!("0x{:04X}", addr),
+                }),
+                None => json!({ "verified": false, "line": line, "message": "no code at this line" }),
+            });
+        }
+        self.source_bps.insert(path, ids);
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn set_instruction_breakpoints(&mut self, args: &Value) -> Response {
+        let dbg = launched(&mut self.dbg)?;
+        for id in self.instruction_bps.drain(..) {
+            dbg.remove_breakpoint(id);
+        }
+
+        let mut results = Vec::new();
+        for bp in args["breakpoints"].as_array().into_iter().flatten() {
+            match parse_addr(&bp["instructionReference"]) {
+                Some(addr) => {
+                    let addr = addr.wrapping_add(bp["offset"].as_i64().unwrap_or(0) as u16);
+                    self.instruction_bps.push(dbg.add_breakpoint(BreakOn::Pc(addr)));
+                    results.push(json!({ "verified": true, "instructionReference": format!("0x{:04X}", addr) }));
+                }
+                None => results.push(json!({ "verified": false, "message": "invalid address" })),
+            }
+        }
+        Ok(json!({ "breakpoints": results }))
+    }
+
+    fn stack_trace(&mut self) -> Response {
+        let pc = launched(&mut self.dbg)?.cpu().reg().pc;
+        let mut frame = json!({
+            "id": 0,
+            "name": format!("${:04X}", pc),
+            "line": 0,
+            "column": 0,
+            "instructionPointerReference": format!("0x{:04X}", pc),
+        });
+        if let Some(line) = self.info.as_ref().and_then(|info| info.line_of(pc)) {
+            let path = self.info_dir.join(&line.file);
+            frame["line"]   = json!(line.line);
+            frame["column"] = json!(1);
+            frame["source"] = json!({
+                "name": Path::new(&line.file).file_name().map(|name| name.to_string_lossy()),
+                "path": path.to_string_lossy(),
+            });
+        }
+        Ok(json!({ "stackFrames": [frame], "totalFrames": 1 }))
+    }
+
+    fn scopes(&self) -> Value {
+        json!({
+            "scopes": [
+                { "name": "Registers", "variablesReference": REGISTERS_REF, "expensive": false },
+                { "name": "Stack",     "variablesReference": STACK_REF,     "expensive": false },
+            ]
+        })
+    }
+
+    fn variables(&mut self, args: &Value) -> Response {
+        let cpu = launched(&mut self.dbg)?.cpu();
+        let reg = cpu.reg();
+        let var = |name: String, value: String| json!({ "name": name, "value": value, "variablesReference": 0 });
+
+        let vars: Vec<Value> = match args["variablesReference"].as_i64() {
+            Some(REGISTERS_REF) => vec![
+                var("A".into(),  format!("${:02X}", reg.a)),
+                var("X".into(),  format!("${:02X}", reg.x)),
+                var("Y".into(),  format!("${:02X}", reg.y)),
+                var("S".into(),  format!("${:02X}", reg.s)),
+                var("PC".into(), format!("${:04X}", reg.pc)),
+                var("P".into(),  format_status(reg.p.as_bits())),
+            ],
+            // Pushed bytes from the top of stack
+            Some(STACK_REF) => (reg.s as u16 + 1..=0xFF)
+                .map(|s| {
+                    let addr = 0x0100 + s;
+                    var(format!("${:04X}", addr), format!("${:02X}", cpu.mem().peek(addr)))
+                })
+                .collect(),
+            _ => Vec::new(),
+        };
+        Ok(json!({ "variables": vars }))
+    }
+
+    fn read_memory(&mut self, args: &Value) -> Response {
+        let mem    = launched(&mut self.dbg)?.cpu().mem();
+        let base   = parse_addr(&args["memoryReference"])
+            .ok_or_else(|| RequestError("invalid 'memoryReference'".to_string()))?;
+        let offset = args["offset"].as_i64().unwrap_or(0);
+        let count  = args["count"].as_u64().unwrap_or(0).min(0x10000) as u32;
+        let addr   = base.wrapping_add(offset as u16);
+
+        let bytes: Vec<u8> = (0..count).map(|i| mem.peek(addr.wrapping_add(i as u16))).collect();
+        Ok(json!({ "address": format!("0x{:04X}", addr), "data": encode_base64(&bytes) }))
+    }
+
+    // Step that does not finish in a chunk is continued by `run_chunk`,
+    // so that `pause` can stop it
+    fn step<W: Write>(&mut self, writer: &mut W, command: &str) -> io::Result<()> {
+        let Some(dbg) = self.dbg.as_mut() else { return Ok(()) };
+        let target = dbg.cpu().cycles() + RUN_CHUNK;
+        let reason = match command {
+            "stepIn"  => dbg.step_in(),
+            "stepOut" => dbg.step_out(target),
+            _         => dbg.step_over(target),
+        };
+        let reason = match reason {
+            StopReason::Cycle => {
+                self.running  = true;
+                self.stepping = true;
+                return Ok(());
+            }
+            StopReason::Breakpoint(_)  => "breakpoint",
+            StopReason::Watchpoint(..) => "data breakpoint",
+            _ => "step",
+        };
+        self.stopped(writer, reason)
+    }
+
+    fn stopped<W: Write>(&mut self, writer: &mut W, reason: &str) -> io::Result<()> {
+        let body = json!({ "reason": reason, "threadId": THREAD_ID, "allThreadsStopped": true });
+        self.event(writer, "stopped", body)
+    }
+
+    fn respond<W: Write>(&mut self, writer: &mut W, request: &Value, command: &str, result: Response) -> io::Result<()> {
+        let mut message = json!({
+            "type": "response",
+            "request_seq": request["seq"],
+            "command": command,
+            "success": result.is_ok(),
+        });
+        match result {
+            Ok(Value::Null) => (),
+            Ok(body) => message["body"] = body,
+            Err(RequestError(error)) => message["message"] = json!(error),
+        }
+        self.send(writer, message)
+    }
+
+    fn event<W: Write>(&mut self, writer: &mut W, event: &str, body: Value) -> io::Result<()> {
+        let mut message = json!({ "type": "event", "event": event });
+        if !body.is_null() {
+            message["body"] = body;
+        }
+        self.send(writer, message)
+    }
+
+    fn send<W: Write>(&mut self, writer: &mut W, mut message: Value) -> io::Result<()> {
+        self.seq += 1;
+        message["seq"] = json!(self.seq);
+        let body = message.to_string();
+        write!(writer, "Content-Length: {}\r\n\r\n{}", body.len(), body)?;
+        writer.flush()
+    }
+}
+
+fn launched(dbg: &mut Option<Debugger>) -> Result<&mut Debugger, RequestError> {
+    dbg.as_mut().ok_or_else(|| RequestError("program is not launched".to_string()))
+}
+
+// Split stream into messages framed by Content-Length header
+fn read_messages<R: Read>(reader: R, tx: Sender<Value>) {
+    let mut reader = BufReader::new(reader);
+    loop {
+        let mut length = None;
+        loop {
+            let mut header = String::new();
+            match reader.read_line(&mut header) {
+                Ok(0) | Err(_) => return,
+                Ok(_) => (),
+            }
+            let header = header.trim();
+            if header.is_empty() {
+                break;
+            }
+            if let Some(value) = header.strip_prefix("Content-Length:") {
+                length = value.trim().parse::<usize>().ok();
+            }
+        }
+        let Some(length) = length else { continue };
+        let mut body = vec![0; length];
+        if reader.read_exact(&mut body).is_err() {
+            return;
+        }
+        if let Ok(message) = serde_json::from_slice(&body) {
+            if tx.send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+// Accept number, or string like "0x8000", "$8000" and "32768"
+fn parse_addr(value: &Value) -> Option<u16> {
+    if let Some(n) = value.as_u64() {
+        return u16::try_from(n).ok();
+    }
+    let text = value.as_str()?.trim();
+    match text.strip_prefix("0x").or_else(|| text.strip_prefix('$')) {
+        Some(hex) => u16::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn format_status(p: u8) -> String {
+    let flags: String = "NV-BDIZC"
+        .chars()
+        .enumerate()
+        .map(|(i, c)| if p & (0x80 >> i) != 0 { c } else { c.to_ascii_lowercase() })
+        .collect();
+    format!("${:02X} {}", p, flags)
+}
+
+fn encode_base64(bytes: &[u8]) -> String {
+    const TABLE: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk.iter().enumerate().fold(0u32, |acc, (i, b)| acc | (*b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(TABLE[(n >> (18 - 6 * i) & 0x3F) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::io::Cursor;
+
+    fn frame(message: Value) -> String {
+        let body = message.to_string();
+        format!("Content-Length: {}\r\n\r\n{}", body.len(), body)
+    }
+
+    fn parse_output(output: &[u8]) -> Vec<Value> {
+        let text = String::from_utf8_lossy(output);
+        text.split("Content-Length: ")
+            .skip(1)
+            .map(|m| serde_json::from_str(m.split_once("\r\n\r\n").unwrap().1).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn test_session() {
+        // $0200: LDA #$42; PHA; JMP $0203
+        let path = std::env::temp_dir().join(format!("nes_cpu_dap_{}.bin", std::process::id()));
+        std::fs::write(&path, [0xA9, 0x42, 0x48, 0x4C, 0x03, 0x02]).unwrap();
+
+        let requests = [
+            json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": {} }),
+            json!({ "seq": 2, "type": "request", "command": "launch",
+                    "arguments": { "program": path, "loadAddress": "0x0200" } }),
+            json!({ "seq": 3, "type": "request", "command": "setInstructionBreakpoints",
+                    "arguments": { "breakpoints": [{ "instructionReference": "$0203" }] } }),
+            json!({ "seq": 4, "type": "request", "command": "configurationDone" }),
+            json!({ "seq": 5, "type": "request", "command": "variables",
+                    "arguments": { "variablesReference": STACK_REF } }),
+            json!({ "seq": 6, "type": "request", "command": "readMemory",
+                    "arguments": { "memoryReference": "0x0200", "count": 2 } }),
+            json!({ "seq": 7, "type": "request", "command": "readMemory",
+                    "arguments": { "memoryReference": "0x0000", "count": 0x20000 } }),
+            json!({ "seq": 8, "type": "request", "command": "disconnect" }),
+        ];
+        let input: String = requests.into_iter().map(frame).collect();
+        let mut output = Vec::new();
+        DapServer::new().serve(Cursor::new(input.into_bytes()), &mut output).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        let messages = parse_output(&output);
+        let find = |command: &str| {
+            messages.iter().find(|m| m["command"] == command).unwrap_or_else(|| panic!("{}", command))
+        };
+        assert_eq!(find("launch")["success"], true);
+        // Initialized only after launch, so that breakpoints can be set
+        let position = |key: &str, value: &str| messages.iter().position(|m| m[key] == value).unwrap();
+        assert!(position("event", "initialized") > position("command", "launch"));
+        assert_eq!(find("setInstructionBreakpoints")["body"]["breakpoints"][0]["verified"], true);
+        assert!(messages.iter().any(|m| m["event"] == "stopped" && m["body"]["reason"] == "instruction breakpoint"));
+        assert_eq!(find("variables")["body"]["variables"][0], json!({
+            "name": "$01FD", "value": "$42", "variablesReference": 0,
+        }));
+        assert_eq!(find("readMemory")["body"]["data"], "qUI=");
+        // Whole address space at most
+        let all = messages.iter().filter(|m| m["command"] == "readMemory").nth(1).unwrap();
+        assert_eq!(all["body"]["data"].as_str().unwrap().len(), 0x10000 / 3 * 4 + 4);
+    }
+
+    #[test]
+    fn test_step_out_pause() {
+        // $0200: JMP $0200
+        let path = std::env::temp_dir().join(format!("nes_cpu_dap_pause_{}.bin", std::process::id()));
+        std::fs::write(&path, [0x4C, 0x00, 0x02]).unwrap();
+
+        let requests = [
+            json!({ "seq": 1, "type": "request", "command": "initialize", "arguments": {} }),
+            json!({ "seq": 2, "type": "request", "command": "launch",
+                    "arguments": { "program": path, "loadAddress": "0x0200", "stopOnEntry": true } }),
+            json!({ "seq": 3, "type": "request", "command": "configurationDone" }),
+            json!({ "seq": 4, "type": "request", "command": "stepOut" }),
+            json!({ "seq": 5, "type": "request", "command": "pause" }),
+            json!({ "seq": 6, "type": "request", "command": "disconnect" }),
+        ];
+        let input: String = requests.into_iter().map(frame).collect();
+        let mut output = Vec::new();
+        DapServer::new().serve(Cursor::new(input.into_bytes()), &mut output).unwrap();
+        std::fs::remove_file(&path).unwrap();
+
+        // Step out at top level never returns, but pause stops it
+        let reasons: Vec<_> = parse_output(&output)
+            .into_iter()
+            .filter(|m| m["event"] == "stopped")
+            .map(|m| m["body"]["reason"].clone())
+            .collect();
+        assert_eq!(reasons, ["entry", "pause"]);
+    }
+
+    #[test]
+    fn test_encode_base64() {
+        assert_eq!(encode_base64(b"Man"), "TWFu");
+        assert_eq!(encode_base64(b"Ma"),  "TWE=");
+        assert_eq!(encode_base64(b"M"),   "TQ==");
+    }
+}
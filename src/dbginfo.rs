@@ -0,0 +1,184 @@
+//! Provide reader of debug info file written by ld65 (`--dbgfile`)
+//!
+//! Only the records needed to map address to source line are read: `file`,
+//! `line`, `span` and `seg`. Address of a span is the start of its segment plus
+//! the start of the span.
+
+use std::collections::HashMap;
+use std::fmt;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct DbgInfoError {
+    /// Line number (1-origin) of the record that could not be read
+    pub line: usize,
+    pub message: String,
+}
+
+impl fmt::Display for DbgInfoError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for DbgInfoError {}
+
+/// Position in assembly or C source
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SourceLine {
+    pub file: String,
+    pub line: u32,
+}
+
+#[derive(Default)]
+pub struct DbgInfo {
+    files: HashMap<u32, String>,
+    // Sorted by address
+    lines: Vec<(u16, SourceLine)>,
+}
+
+struct Span {
+    seg: u32,
+    start: u32,
+}
+
+impl DbgInfo {
+    pub fn parse(text: &str) -> Result<DbgInfo, DbgInfoError> {
+        let mut files = HashMap::new();
+        let mut segs  = HashMap::new();
+        let mut spans = HashMap::new();
+        let mut lines = Vec::new();
+
+        for (n, record) in text.lines().enumerate() {
+            let error = |message: &str| DbgInfoError { line: n + 1, message: message.to_string() };
+            let Some((kind, fields)) = record.split_once(char::is_whitespace) else { continue };
+            let fields = parse_fields(fields);
+            let get = |key: &str| fields.get(key).ok_or_else(|| error(&format!("missing '{}'", key)));
+            let num = |key: &str| get(key).and_then(|v| parse_number(v).ok_or_else(|| error(&format!("invalid '{}'", key))));
+
+            match kind {
+                "file" => {
+                    files.insert(num("id")?, get("name")?.trim_matches('"').to_string());
+                }
+                "seg" => {
+                    segs.insert(num("id")?, num("start")?);
+                }
+                "span" => {
+                    spans.insert(num("id")?, Span { seg: num("seg")?, start: num("start")? });
+                }
+                // Type 0 is assembly source and 1 is external source like C. Lines of
+                // macro expansion (type=2) point inside macro definitions.
+                "line" if fields.get("type").is_none_or(|t| *t != "2") => {
+                    if let Some(span) = fields.get("span") {
+                        let ids: Result<Vec<u32>, _> = span
+                            .split('+')
+                            .map(|id| parse_number(id).ok_or_else(|| error("invalid 'span'")))
+                            .collect();
+                        lines.push((num("file")?, num("line")?, ids?));
+                    }
+                }
+                _ => (),
+            }
+        }
+
+        let mut info = DbgInfo { files, lines: Vec::new() };
+        for (file, line, ids) in lines {
+            let Some(name) = info.files.get(&file) else { continue };
+            for id in ids {
+                let Some(span) = spans.get(&id) else { continue };
+                let Some(seg)  = segs.get(&span.seg) else { continue };
+                let addr = (seg + span.start) as u16;
+                info.lines.push((addr, SourceLine { file: name.clone(), line }));
+            }
+        }
+        info.lines.sort_by_key(|(addr, _)| *addr);
+        Ok(info)
+    }
+
+    /// Source line of the instruction at given address
+    pub fn line_of(&self, addr: u16) -> Option<&SourceLine> {
+        let i = self.lines.partition_point(|(a, _)| *a < addr);
+        self.lines.get(i).filter(|(a, _)| *a == addr).map(|(_, line)| line)
+    }
+
+    /// Addresses generated from given line. File is matched by its path suffix.
+    pub fn addrs_of(&self, file: &str, line: u32) -> Vec<u16> {
+        self.lines
+            .iter()
+            .filter(|(_, l)| l.line == line && same_file(&l.file, file))
+            .map(|(addr, _)| *addr)
+            .collect()
+    }
+
+    pub fn files(&self) -> impl Iterator<Item = &str> {
+        self.files.values().map(String::as_str)
+    }
+}
+
+// Split `key=value,key="value",...`. Comma inside quote is not splitted.
+fn parse_fields(text: &str) -> HashMap<&str, &str> {
+    let mut fields = HashMap::new();
+    let mut quoted = false;
+    let mut start  = 0;
+    for (i, c) in text.char_indices().chain([(text.len(), ',')]) {
+        match c {
+            '"' => quoted = !quoted,
+            ',' if !quoted => {
+                if let Some((key, value)) = text[start..i].split_once('=') {
+                    fields.insert(key.trim(), value.trim());
+                }
+                start = i + 1;
+            }
+            _ => (),
+        }
+    }
+    fields
+}
+
+fn parse_number(text: &str) -> Option<u32> {
+    match text.strip_prefix("0x") {
+        Some(hex) => u32::from_str_radix(hex, 16).ok(),
+        None => text.parse().ok(),
+    }
+}
+
+fn same_file(a: &str, b: &str) -> bool {
+    let a = a.replace('\\', "/");
+    let b = b.replace('\\', "/");
+    a == b || a.ends_with(&format!("/{}", b)) || b.ends_with(&format!("/{}", a))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const DBG: &str = r#"version	major=2,minor=0
+file	id=0,name="src/main.s",size=120,mtime=0x5F000000,mod=0
+file	id=1,name="src/game.c",size=80,mtime=0x5F000000,mod=0
+seg	id=0,name="CODE",start=0x008000,size=0x0010,addrsize=absolute,type=ro
+span	id=0,seg=0,start=0,size=2
+span	id=1,seg=0,start=2,size=3
+span	id=2,seg=0,start=5,size=3
+line	id=0,file=0,line=4,span=0
+line	id=1,file=0,line=5,span=1
+line	id=2,file=0,line=9,type=2,span=1
+line	id=3,file=1,line=12,type=1,span=2
+"#;
+
+    #[test]
+    fn test_parse() {
+        let info = DbgInfo::parse(DBG).unwrap();
+
+        let line = SourceLine { file: "src/main.s".to_string(), line: 5 };
+        assert_eq!(info.line_of(0x8002), Some(&line));
+        assert_eq!(info.line_of(0x8001), None);
+        assert_eq!(info.addrs_of("/home/user/game/src/main.s", 4), vec![0x8000]);
+        assert!(info.addrs_of("main.s", 9).is_empty());
+        assert_eq!(info.addrs_of("game.c", 12), vec![0x8005]);
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = DbgInfo::parse("span\tid=0,seg=x,start=0").err().unwrap();
+        assert_eq!(error, DbgInfoError { line: 1, message: "invalid 'seg'".to_string() });
+    }
+}
@@ -0,0 +1,497 @@
+//! Provide debugger that control execution of the cpu
+
+mod condition;
+
+use std::ops::RangeInclusive;
+
+use crate::cpu::{Access, BusAccess, Cpu, CpuHooks, NoHooks};
+use crate::memory::Memory;
+use crate::opcode::{Mnemonic, lookup};
+
+pub use condition::{Condition, ParseError, ParseErrorKind};
+
+const JSR: u8 = 0x20;
+const RTI: u8 = 0x40;
+const RTS: u8 = 0x60;
+
+/// Condition to stop before executing an instruction
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BreakOn {
+    Pc(u16),
+    Opcode(u8),
+    Mnemonic(Mnemonic),
+}
+
+/// Breakpoint that may stop only when condition holds and hit count reached threshold
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Breakpoint {
+    pub on: BreakOn,
+    pub condition: Option<Condition>,
+    /// Number of hits needed before stop. Hits with false condition are not counted.
+    pub threshold: u64,
+    pub hits: u64,
+}
+
+impl Breakpoint {
+    pub fn new(on: BreakOn) -> Breakpoint {
+        Breakpoint { on, condition: None, threshold: 1, hits: 0 }
+    }
+
+    /// Stop only when given expression evaluates to true. See `Condition` for syntax.
+    pub fn with_condition(mut self, source: &str) -> Result<Self, ParseError> {
+        self.condition = Some(Condition::parse(source)?);
+        Ok(self)
+    }
+
+    /// Stop at given hit and later
+    pub fn with_threshold(mut self, threshold: u64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    fn matches<M: Memory, H: CpuHooks>(&self, cpu: &Cpu<M, H>, opcode: u8, name: Option<Mnemonic>) -> bool {
+        let hit = match self.on {
+            BreakOn::Pc(addr)     => addr == cpu.reg().pc,
+            BreakOn::Opcode(byte) => byte == opcode,
+            BreakOn::Mnemonic(m)  => Some(m) == name,
+        };
+        hit && self.condition.as_ref().is_none_or(|cond| cond.eval(cpu))
+    }
+}
+
+impl From<BreakOn> for Breakpoint {
+    fn from(on: BreakOn) -> Self {
+        Breakpoint::new(on)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WatchKind {
+    Read,
+    Write,
+    ReadWrite,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Watchpoint {
+    pub range: RangeInclusive<u16>,
+    pub kind: WatchKind,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BreakpointId(usize);
+
+impl BreakpointId {
+    /// Number of the breakpoint, counted from 0 in order of addition
+    pub fn index(&self) -> usize {
+        self.0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct WatchpointId(usize);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum StopReason {
+    /// Requested step was finished
+    Step,
+    /// Breakpoint was hit. The instruction at pc is not executed yet.
+    Breakpoint(BreakpointId),
+    /// Last instruction accessed watched address
+    Watchpoint(WatchpointId, BusAccess),
+    /// Cycle counter reached the cycle given to `run_until_cycle` or the step
+    Cycle,
+}
+
+// Unfinished step_over or step_out
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    Over { ret: u16, s: u8 },
+    Out { s: u8 },
+}
+
+pub struct Debugger<M = Box<dyn Memory>, H = NoHooks> {
+    cpu: Cpu<M, H>,
+    breakpoints: Vec<Option<Breakpoint>>,
+    watchpoints: Vec<Option<Watchpoint>>,
+    // Pc where the last run stopped by breakpoint
+    stopped_at: Option<u16>,
+    step: Option<Step>,
+}
+
+impl<M: Memory, H: CpuHooks> Debugger<M, H> {
+    pub fn new(mut cpu: Cpu<M, H>) -> Debugger<M, H> {
+        cpu.set_bus_log(true);
+        Debugger {
+            cpu,
+            breakpoints: Vec::new(),
+            watchpoints: Vec::new(),
+            stopped_at: None,
+            step: None,
+        }
+    }
+
+    pub fn cpu(&self) -> &Cpu<M, H> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu<M, H> {
+        &mut self.cpu
+    }
+
+    pub fn into_cpu(mut self) -> Cpu<M, H> {
+        self.cpu.set_bus_log(false);
+        self.cpu
+    }
+
+    pub fn add_breakpoint(&mut self, bp: impl Into<Breakpoint>) -> BreakpointId {
+        self.breakpoints.push(Some(bp.into()));
+        BreakpointId(self.breakpoints.len() - 1)
+    }
+
+    /// Remove breakpoint. Return false if it was already removed.
+    pub fn remove_breakpoint(&mut self, id: BreakpointId) -> bool {
+        self.breakpoints.get_mut(id.0).and_then(Option::take).is_some()
+    }
+
+    pub fn breakpoint(&self, id: BreakpointId) -> Option<&Breakpoint> {
+        self.breakpoints.get(id.0).and_then(Option::as_ref)
+    }
+
+    pub fn breakpoints(&self) -> impl Iterator<Item = (BreakpointId, &Breakpoint)> {
+        self.breakpoints
+            .iter()
+            .enumerate()
+            .filter_map(|(i, bp)| Some((BreakpointId(i), bp.as_ref()?)))
+    }
+
+    pub fn add_watchpoint(&mut self, range: RangeInclusive<u16>, kind: WatchKind) -> WatchpointId {
+        self.watchpoints.push(Some(Watchpoint { range, kind }));
+        WatchpointId(self.watchpoints.len() - 1)
+    }
+
+    /// Remove watchpoint. Return false if it was already removed.
+    pub fn remove_watchpoint(&mut self, id: WatchpointId) -> bool {
+        self.watchpoints.get_mut(id.0).and_then(Option::take).is_some()
+    }
+
+    pub fn watchpoint(&self, id: WatchpointId) -> Option<&Watchpoint> {
+        self.watchpoints.get(id.0).and_then(Option::as_ref)
+    }
+
+    /// Execute one instruction. Breakpoint at current pc is ignored.
+    pub fn step_in(&mut self) -> StopReason {
+        self.step = None;
+        self.stopped_at = Some(self.cpu.reg().pc);
+        self.run_with(|_, _| Some(StopReason::Step))
+    }
+
+    /// Execute one instruction. Subroutine called by JSR is executed as one step,
+    /// but it stops when cycle counter reaches given cycle.
+    /// After `StopReason::Cycle`, `continue_step` resumes the step.
+    pub fn step_over(&mut self, cycle: u64) -> StopReason {
+        if self.next_opcode() != JSR {
+            return self.step_in();
+        }
+        let reg = self.cpu.reg();
+        self.step = Some(Step::Over { ret: reg.pc.wrapping_add(3), s: reg.s });
+        self.stopped_at = Some(reg.pc);
+        self.continue_step(cycle)
+    }
+
+    /// Run until current subroutine returns, or until cycle counter reaches given cycle.
+    /// After `StopReason::Cycle`, `continue_step` resumes the step.
+    pub fn step_out(&mut self, cycle: u64) -> StopReason {
+        let reg = self.cpu.reg();
+        self.step = Some(Step::Out { s: reg.s });
+        self.stopped_at = Some(reg.pc);
+        self.continue_step(cycle)
+    }
+
+    /// Resume `step_over` or `step_out` stopped by the cycle limit.
+    /// Return `StopReason::Step` at once if no step is unfinished.
+    pub fn continue_step(&mut self, cycle: u64) -> StopReason {
+        let Some(step) = self.step else { return StopReason::Step };
+        if self.cpu.cycles() >= cycle {
+            return StopReason::Cycle;
+        }
+        let reason = self.run_with(|cpu, opcode| {
+            let done = match step {
+                // Returned to the address after the JSR. Interrupt served before
+                // the JSR returns to the JSR itself.
+                Step::Over { ret, s } => cpu.reg().pc == ret && cpu.reg().s >= s,
+                // Return address is pulled above the starting stack pointer.
+                // Pulls of pushed data and returns of nested calls stay below it.
+                Step::Out { s } => matches!(opcode, RTS | RTI) && cpu.reg().s > s,
+            };
+            if done {
+                Some(StopReason::Step)
+            } else {
+                (cpu.cycles() >= cycle).then_some(StopReason::Cycle)
+            }
+        });
+        if reason != StopReason::Cycle {
+            self.step = None;
+        }
+        reason
+    }
+
+    /// Run until breakpoint or watchpoint is hit
+    pub fn run(&mut self) -> StopReason {
+        self.step = None;
+        self.run_with(|_, _| None)
+    }
+
+    /// Run until cycle counter reaches given cycle
+    pub fn run_until_cycle(&mut self, cycle: u64) -> StopReason {
+        self.step = None;
+        if self.cpu.cycles() >= cycle {
+            return StopReason::Cycle;
+        }
+        self.run_with(|cpu, _| (cpu.cycles() >= cycle).then_some(StopReason::Cycle))
+    }
+
+    /// Undo last instruction. Return false if no instruction is recorded.
+    /// Recording must be enabled by `Cpu::set_history`. Device registers are not restored.
+    pub fn step_back(&mut self) -> bool {
+        let undone = self.cpu.step_back();
+        if undone {
+            self.stopped_at = Some(self.cpu.reg().pc);
+        }
+        undone
+    }
+
+    /// Undo instructions until the state before an instruction matching given breakpoint.
+    /// Return false if recorded history ran out before it. Hit count of the breakpoint is ignored.
+    pub fn run_back_until(&mut self, bp: impl Into<Breakpoint>) -> bool {
+        let bp = bp.into();
+        while self.step_back() {
+            let opcode = self.next_opcode();
+            let name   = lookup(opcode).map(|info| info.name);
+            if bp.matches(&self.cpu, opcode, name) {
+                return true;
+            }
+        }
+        false
+    }
+
+    // Step until `done` return reason. It gets the opcode at pc before the step.
+    // Breakpoint at the starting pc is ignored if the last run stopped there,
+    // so that run can resume from it.
+    fn run_with<F>(&mut self, mut done: F) -> StopReason
+    where
+        F: FnMut(&Cpu<M, H>, u8) -> Option<StopReason>,
+    {
+        let mut check = self.stopped_at.take() != Some(self.cpu.reg().pc);
+        loop {
+            if check {
+                if let Some(id) = self.hit_breakpoint() {
+                    self.stopped_at = Some(self.cpu.reg().pc);
+                    return StopReason::Breakpoint(id);
+                }
+            }
+            check = true;
+
+            let opcode = self.next_opcode();
+            self.cpu.step();
+
+            if let Some(reason) = self.hit_watchpoint() {
+                return reason;
+            }
+            if let Some(reason) = done(&self.cpu, opcode) {
+                return reason;
+            }
+        }
+    }
+
+    fn next_opcode(&self) -> u8 {
+        self.cpu.mem().peek(self.cpu.reg().pc)
+    }
+
+    // Count hits of all matching breakpoints, then return first one reached its threshold
+    fn hit_breakpoint(&mut self) -> Option<BreakpointId> {
+        let opcode = self.next_opcode();
+        let name   = lookup(opcode).map(|info| info.name);
+
+        let mut stop = None;
+        for (i, bp) in self.breakpoints.iter_mut().enumerate() {
+            let Some(bp) = bp else { continue };
+            if bp.matches(&self.cpu, opcode, name) {
+                bp.hits += 1;
+                if bp.hits >= bp.threshold && stop.is_none() {
+                    stop = Some(BreakpointId(i));
+                }
+            }
+        }
+        stop
+    }
+
+    fn hit_watchpoint(&self) -> Option<StopReason> {
+        self.cpu.bus_log().iter().find_map(|access| {
+            self.watchpoints.iter().enumerate().find_map(|(i, wp)| {
+                let wp = wp.as_ref()?;
+                let kind = match access.access {
+                    Access::Read  => wp.kind != WatchKind::Write,
+                    Access::Write => wp.kind != WatchKind::Read,
+                };
+                (kind && wp.range.contains(&access.addr))
+                    .then_some(StopReason::Watchpoint(WatchpointId(i), *access))
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::FlatMemory;
+
+    // $8000: JSR $8010; STA $0300; JMP $8006
+    // $8010: LDA #$42; JSR $8020; RTS
+    // $8020: INX; RTS
+    // $8030: INY; RTI (NMI handler)
+    fn debugger() -> Debugger<FlatMemory> {
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0x20, 0x10, 0x80, 0x8D, 0x00, 0x03, 0x4C, 0x06, 0x80]);
+        mem.load(0x8010, &[0xA9, 0x42, 0x20, 0x20, 0x80, 0x60]);
+        mem.load(0x8020, &[0xE8, 0x60]);
+        mem.load(0x8030, &[0xC8, 0x40]);
+        mem.load(0xFFFA, &[0x30, 0x80]);
+
+        let mut cpu = Cpu::with_memory(mem);
+        cpu.reg_mut().pc = 0x8000;
+        cpu.reg_mut().s  = 0xFF;
+        Debugger::new(cpu)
+    }
+
+    #[test]
+    fn test_step() {
+        let mut dbg = debugger();
+
+        assert_eq!(dbg.step_in(), StopReason::Step);
+        assert_eq!(dbg.cpu().reg().pc, 0x8010);
+        assert_eq!(dbg.step_in(), StopReason::Step);
+        assert_eq!(dbg.step_over(u64::MAX), StopReason::Step);
+        assert_eq!(dbg.cpu().reg().pc, 0x8015);
+        assert_eq!(dbg.cpu().reg().x, 1);
+        assert_eq!(dbg.step_out(u64::MAX), StopReason::Step);
+        assert_eq!(dbg.cpu().reg().pc, 0x8003);
+    }
+
+    #[test]
+    fn test_step_out_with_nmi() {
+        let mut dbg = debugger();
+        for _ in 0..4 {
+            dbg.step_in();
+        }
+        assert_eq!(dbg.cpu().reg().pc, 0x8021);
+
+        // NMI is served where RTS is next, then the RTS returns
+        dbg.cpu_mut().set_nmi(true);
+        assert_eq!(dbg.step_out(u64::MAX), StopReason::Step);
+        assert_eq!(dbg.cpu().reg().pc, 0x8015);
+        assert_eq!(dbg.cpu().reg().y, 1);
+
+        // NMI at JSR is stepped over with the subroutine
+        dbg.cpu_mut().set_nmi(false);
+        dbg.cpu_mut().reg_mut().pc = 0x8012;
+        dbg.cpu_mut().set_nmi(true);
+        assert_eq!(dbg.step_over(u64::MAX), StopReason::Step);
+        assert_eq!(dbg.cpu().reg().pc, 0x8015);
+        assert_eq!(dbg.cpu().reg().y, 2);
+        assert_eq!(dbg.cpu().reg().s, 0xFD);
+    }
+
+    #[test]
+    fn test_step_out_with_push() {
+        let mut dbg = debugger();
+        // $8010: PHA; PLA; RTS
+        dbg.cpu_mut().mem_mut().load(0x8010, &[0x48, 0x68, 0x60]);
+        dbg.step_in();
+
+        // PLA pulls back to the starting stack pointer, but RTS is the return
+        assert_eq!(dbg.step_out(u64::MAX), StopReason::Step);
+        assert_eq!(dbg.cpu().reg().pc, 0x8003);
+        assert_eq!(dbg.cpu().reg().s, 0xFF);
+    }
+
+    #[test]
+    fn test_step_cycle_limit() {
+        let mut dbg = debugger();
+
+        // Nothing returns at top level, so the step stops by the limit
+        assert_eq!(dbg.step_out(100), StopReason::Cycle);
+        assert!(dbg.cpu().cycles() >= 100);
+        assert_eq!(dbg.continue_step(200), StopReason::Cycle);
+        assert!(dbg.cpu().cycles() >= 200);
+
+        // Step over the subroutine is resumed until it returns
+        dbg.cpu_mut().reg_mut().pc = 0x8000;
+        let cycle = dbg.cpu().cycles();
+        assert_eq!(dbg.step_over(cycle + 8), StopReason::Cycle);
+        assert_eq!(dbg.continue_step(u64::MAX), StopReason::Step);
+        assert_eq!(dbg.cpu().reg().pc, 0x8003);
+        assert_eq!(dbg.continue_step(u64::MAX), StopReason::Step);
+        assert_eq!(dbg.cpu().reg().pc, 0x8003);
+    }
+
+    #[test]
+    fn test_breakpoint() {
+        let mut dbg = debugger();
+        let inx = dbg.add_breakpoint(BreakOn::Mnemonic(Mnemonic::Inx));
+        let sta = dbg.add_breakpoint(BreakOn::Pc(0x8003));
+
+        assert_eq!(dbg.run(), StopReason::Breakpoint(inx));
+        assert_eq!(dbg.cpu().reg().pc, 0x8020);
+        assert_eq!(dbg.run(), StopReason::Breakpoint(sta));
+        assert!(dbg.remove_breakpoint(sta));
+        assert!(!dbg.remove_breakpoint(sta));
+    }
+
+    #[test]
+    fn test_conditional_breakpoint() {
+        let mut dbg = debugger();
+        let bp = Breakpoint::new(BreakOn::Pc(0x8006))
+            .with_condition("A == $42 && [$0300] == $42")
+            .unwrap()
+            .with_threshold(3);
+        let id = dbg.add_breakpoint(bp);
+
+        assert_eq!(dbg.run(), StopReason::Breakpoint(id));
+        assert_eq!(dbg.breakpoint(id).unwrap().hits, 3);
+        assert!(Breakpoint::new(BreakOn::Pc(0)).with_condition("A ==").is_err());
+    }
+
+    #[test]
+    fn test_watchpoint() {
+        let mut dbg = debugger();
+        let id = dbg.add_watchpoint(0x0300..=0x03FF, WatchKind::Write);
+
+        let access = BusAccess { addr: 0x0300, value: 0x42, access: Access::Write };
+        assert_eq!(dbg.run(), StopReason::Watchpoint(id, access));
+        assert_eq!(dbg.cpu().reg().pc, 0x8006);
+    }
+
+    #[test]
+    fn test_run_back_until() {
+        let mut dbg = debugger();
+        dbg.cpu_mut().set_history(100);
+        dbg.run_until_cycle(60);
+
+        assert!(dbg.run_back_until(BreakOn::Pc(0x8006)));
+        assert_eq!(dbg.cpu().mem().read_byte(0x0300), 0x42);
+        assert!(dbg.run_back_until(BreakOn::Mnemonic(Mnemonic::Sta)));
+        assert_eq!(dbg.cpu().mem().read_byte(0x0300), 0x00);
+        assert_eq!(dbg.cpu().reg().pc, 0x8003);
+        assert!(!dbg.run_back_until(BreakOn::Pc(0x8006)));
+        assert_eq!(dbg.cpu().cycles(), 0);
+    }
+
+    #[test]
+    fn test_run_until_cycle() {
+        let mut dbg = debugger();
+
+        assert_eq!(dbg.run_until_cycle(10), StopReason::Cycle);
+        assert!(dbg.cpu().cycles() >= 10);
+    }
+}
@@ -0,0 +1,334 @@
+//! Provide condition expression of breakpoint
+//!
+//! ```text
+//! or      := and ("||" and)*
+//! and     := compare ("&&" compare)*
+//! compare := unary (("==" | "!=" | "<" | "<=" | ">" | ">=") unary)?
+//! unary   := "!" unary | primary
+//! primary := number | register | flag | "[" or "]" | "(" or ")"
+//! ```
+//!
+//! Registers are `A`, `X`, `Y`, `S`, `P` and `PC`, flags are `C`, `Z`, `I`, `D`,
+//! `B`, `V` and `N`. Numbers are written as `$FF`, `0xFF`, `%1010` or `255`.
+//! `[addr]` read a byte from memory. Names are case insensitive.
+
+use std::fmt;
+
+use crate::cpu::{Cpu, CpuHooks};
+use crate::memory::Memory;
+use crate::register::Status;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseErrorKind {
+    UnexpectedChar(char),
+    UnexpectedEnd,
+    UnknownName(String),
+    InvalidNumber(String),
+    /// Given token was expected
+    Expected(&'static str),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    /// Byte offset in the source where error was found
+    pub pos: usize,
+    pub kind: ParseErrorKind,
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.kind {
+            ParseErrorKind::UnexpectedChar(c) => write!(f, "unexpected character '{}'", c)?,
+            ParseErrorKind::UnexpectedEnd     => write!(f, "unexpected end of expression")?,
+            ParseErrorKind::UnknownName(s)    => write!(f, "unknown name '{}'", s)?,
+            ParseErrorKind::InvalidNumber(s)  => write!(f, "invalid number '{}'", s)?,
+            ParseErrorKind::Expected(s)       => write!(f, "expected '{}'", s)?,
+        }
+        write!(f, " at {}", self.pos)
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Reg {
+    A, X, Y, S, P, Pc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum BinOp {
+    Or, And, Eq, Ne, Lt, Le, Gt, Ge,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Expr {
+    Num(u16),
+    Reg(Reg),
+    Flag(Status),
+    Mem(Box<Expr>),
+    Not(Box<Expr>),
+    Bin(BinOp, Box<Expr>, Box<Expr>),
+}
+
+impl Expr {
+    fn eval<M: Memory, H: CpuHooks>(&self, cpu: &Cpu<M, H>) -> u16 {
+        let reg = cpu.reg();
+        match self {
+            Expr::Num(n) => *n,
+            Expr::Reg(Reg::A)  => reg.a as u16,
+            Expr::Reg(Reg::X)  => reg.x as u16,
+            Expr::Reg(Reg::Y)  => reg.y as u16,
+            Expr::Reg(Reg::S)  => reg.s as u16,
+            Expr::Reg(Reg::P)  => reg.p.as_bits() as u16,
+            Expr::Reg(Reg::Pc) => reg.pc,
+            Expr::Flag(flag) => reg.p.contains(*flag) as u16,
+            Expr::Mem(addr)  => cpu.mem().peek(addr.eval(cpu)) as u16,
+            Expr::Not(e)     => (e.eval(cpu) == 0) as u16,
+            Expr::Bin(op, lhs, rhs) => {
+                let lhs = lhs.eval(cpu);
+                // Short circuit so that memory is not read needlessly
+                match op {
+                    BinOp::Or  if lhs != 0 => return 1,
+                    BinOp::And if lhs == 0 => return 0,
+                    _ => (),
+                }
+                let rhs = rhs.eval(cpu);
+                let result = match op {
+                    BinOp::Or | BinOp::And => rhs != 0,
+                    BinOp::Eq => lhs == rhs,
+                    BinOp::Ne => lhs != rhs,
+                    BinOp::Lt => lhs <  rhs,
+                    BinOp::Le => lhs <= rhs,
+                    BinOp::Gt => lhs >  rhs,
+                    BinOp::Ge => lhs >= rhs,
+                };
+                result as u16
+            }
+        }
+    }
+}
+
+/// Parsed condition expression
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Condition {
+    source: String,
+    expr: Expr,
+}
+
+impl Condition {
+    pub fn parse(source: &str) -> Result<Condition, ParseError> {
+        let mut parser = Parser { src: source, pos: 0 };
+        let expr = parser.or()?;
+        parser.skip_space();
+        match parser.peek() {
+            None    => Ok(Condition { source: source.to_string(), expr }),
+            Some(c) => Err(parser.error(ParseErrorKind::UnexpectedChar(c))),
+        }
+    }
+
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    /// Evaluate the condition against current state of the cpu
+    pub fn eval<M: Memory, H: CpuHooks>(&self, cpu: &Cpu<M, H>) -> bool {
+        self.expr.eval(cpu) != 0
+    }
+}
+
+struct Parser<'a> {
+    src: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn or(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.and()?;
+        while self.eat("||") {
+            lhs = Expr::Bin(BinOp::Or, Box::new(lhs), Box::new(self.and()?));
+        }
+        Ok(lhs)
+    }
+
+    fn and(&mut self) -> Result<Expr, ParseError> {
+        let mut lhs = self.compare()?;
+        while self.eat("&&") {
+            lhs = Expr::Bin(BinOp::And, Box::new(lhs), Box::new(self.compare()?));
+        }
+        Ok(lhs)
+    }
+
+    fn compare(&mut self) -> Result<Expr, ParseError> {
+        let lhs = self.unary()?;
+        let ops = [
+            ("==", BinOp::Eq), ("!=", BinOp::Ne), ("<=", BinOp::Le),
+            (">=", BinOp::Ge), ("<",  BinOp::Lt), (">",  BinOp::Gt),
+        ];
+        for (token, op) in ops {
+            if self.eat(token) {
+                return Ok(Expr::Bin(op, Box::new(lhs), Box::new(self.unary()?)));
+            }
+        }
+        Ok(lhs)
+    }
+
+    fn unary(&mut self) -> Result<Expr, ParseError> {
+        if self.eat("!") {
+            Ok(Expr::Not(Box::new(self.unary()?)))
+        } else {
+            self.primary()
+        }
+    }
+
+    fn primary(&mut self) -> Result<Expr, ParseError> {
+        self.skip_space();
+        match self.peek() {
+            Some('[') => {
+                self.pos += 1;
+                let addr = self.or()?;
+                self.expect("]")?;
+                Ok(Expr::Mem(Box::new(addr)))
+            }
+            Some('(') => {
+                self.pos += 1;
+                let expr = self.or()?;
+                self.expect(")")?;
+                Ok(expr)
+            }
+            Some('$' | '%' | '0'..='9') => self.number(),
+            Some(c) if c.is_ascii_alphabetic() => self.name(),
+            Some(c) => Err(self.error(ParseErrorKind::UnexpectedChar(c))),
+            None    => Err(self.error(ParseErrorKind::UnexpectedEnd)),
+        }
+    }
+
+    fn number(&mut self) -> Result<Expr, ParseError> {
+        let start = self.pos;
+        let word  = self.word();
+        let (digits, radix) = if let Some(hex) = word.strip_prefix('$') {
+            (hex, 16)
+        } else if let Some(hex) = word.strip_prefix("0x") {
+            (hex, 16)
+        } else if let Some(bin) = word.strip_prefix('%') {
+            (bin, 2)
+        } else {
+            (word, 10)
+        };
+        u16::from_str_radix(digits, radix).map(Expr::Num).map_err(|_| ParseError {
+            pos: start,
+            kind: ParseErrorKind::InvalidNumber(word.to_string()),
+        })
+    }
+
+    fn name(&mut self) -> Result<Expr, ParseError> {
+        let start = self.pos;
+        let word  = self.word();
+        let expr = match word.to_ascii_uppercase().as_str() {
+            "A"  => Expr::Reg(Reg::A),
+            "X"  => Expr::Reg(Reg::X),
+            "Y"  => Expr::Reg(Reg::Y),
+            "S"  => Expr::Reg(Reg::S),
+            "P"  => Expr::Reg(Reg::P),
+            "PC" => Expr::Reg(Reg::Pc),
+            "C"  => Expr::Flag(Status::CARRY),
+            "Z"  => Expr::Flag(Status::ZERO),
+            "I"  => Expr::Flag(Status::INTERRUPT),
+            "D"  => Expr::Flag(Status::DECIMAL),
+            "B"  => Expr::Flag(Status::BREAK),
+            "V"  => Expr::Flag(Status::OVERFLOW),
+            "N"  => Expr::Flag(Status::NEGATIVE),
+            _ => {
+                return Err(ParseError {
+                    pos: start,
+                    kind: ParseErrorKind::UnknownName(word.to_string()),
+                })
+            }
+        };
+        Ok(expr)
+    }
+
+    // Take alphanumeric characters with prefix of number
+    fn word(&mut self) -> &'a str {
+        let start = self.pos;
+        let rest  = &self.src[start..];
+        let len   = rest
+            .char_indices()
+            .find(|&(i, c)| !(c.is_ascii_alphanumeric() || (i == 0 && (c == '$' || c == '%'))))
+            .map_or(rest.len(), |(i, _)| i);
+        self.pos += len;
+        &self.src[start..self.pos]
+    }
+
+    fn expect(&mut self, token: &'static str) -> Result<(), ParseError> {
+        if self.eat(token) {
+            Ok(())
+        } else {
+            Err(self.error(ParseErrorKind::Expected(token)))
+        }
+    }
+
+    fn eat(&mut self, token: &str) -> bool {
+        self.skip_space();
+        if self.src[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            true
+        } else {
+            false
+        }
+    }
+
+    fn skip_space(&mut self) {
+        while let Some(c) = self.peek().filter(|c| c.is_whitespace()) {
+            self.pos += c.len_utf8();
+        }
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.src[self.pos..].chars().next()
+    }
+
+    fn error(&self, kind: ParseErrorKind) -> ParseError {
+        ParseError { pos: self.pos, kind }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::{FlatMemory, Memory};
+
+    #[test]
+    fn test_eval() {
+        let mut mem = FlatMemory::new();
+        mem.write_byte(0x0300, 5);
+        let mut cpu = Cpu::new(Box::new(mem));
+        cpu.reg_mut().a = 0x40;
+
+        let cond = Condition::parse("A == $40 && [$0300] > 3 && !C").unwrap();
+        assert!(cond.eval(&cpu));
+
+        cpu.reg_mut().p.insert(Status::CARRY);
+        assert!(!cond.eval(&cpu));
+        assert!(Condition::parse("c || (x != 0)").unwrap().eval(&cpu));
+        assert!(Condition::parse("[pc] == 0 && a == %01000000").unwrap().eval(&cpu));
+    }
+
+    #[test]
+    fn test_parse_error() {
+        let error = |src| Condition::parse(src).unwrap_err();
+
+        assert_eq!(error("A == "), ParseError { pos: 5, kind: ParseErrorKind::UnexpectedEnd });
+        assert_eq!(error("Q == 1").kind, ParseErrorKind::UnknownName("Q".into()));
+        assert_eq!(error("[$10").kind, ParseErrorKind::Expected("]"));
+        assert_eq!(error("$1G").kind, ParseErrorKind::InvalidNumber("$1G".into()));
+        assert_eq!(error("A == 1 1").kind, ParseErrorKind::UnexpectedChar('1'));
+        assert_eq!(error("A ==\u{a0}é").kind, ParseErrorKind::UnexpectedChar('é'));
+    }
+
+    #[test]
+    fn test_unicode_space() {
+        let mut cpu = Cpu::new(Box::new(FlatMemory::new()));
+        cpu.reg_mut().a = 1;
+        assert!(Condition::parse("A ==\u{a0}1\u{3000}").unwrap().eval(&cpu));
+    }
+}
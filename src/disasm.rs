@@ -0,0 +1,107 @@
+//! Provide disassembler of 6502 instructions
+
+use std::fmt;
+
+use crate::memory::Memory;
+use crate::opcode::{AddressingMode, Mnemonic, lookup};
+
+/// Disassembled instruction
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Instruction {
+    pub addr: u16,
+    pub bytes: Vec<u8>,
+    /// None if opcode is invalid
+    pub name: Option<Mnemonic>,
+    /// Operand in assembler syntax, like `($10),Y`
+    pub operand: String,
+}
+
+impl Instruction {
+    pub fn len(&self) -> u16 {
+        self.bytes.len() as u16
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.bytes.is_empty()
+    }
+
+    /// Address of the next instruction
+    pub fn next_addr(&self) -> u16 {
+        self.addr.wrapping_add(self.len())
+    }
+}
+
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let bytes: Vec<String> = self.bytes.iter().map(|b| format!("{:02X}", b)).collect();
+        write!(f, "{:04X}  {:<8}  ", self.addr, bytes.join(" "))?;
+        match self.name {
+            Some(name) if self.operand.is_empty() => write!(f, "{}", mnemonic(name))?,
+            Some(name) => write!(f, "{} {}", mnemonic(name), self.operand)?,
+            None => write!(f, ".byte ${:02X}", self.bytes[0])?,
+        }
+        Ok(())
+    }
+}
+
+/// Upper case name of the mnemonic, like `LDA`
+pub fn mnemonic(name: Mnemonic) -> String {
+    format!("{:?}", name).to_uppercase()
+}
+
+/// Disassemble one instruction at given address
+pub fn disassemble(mem: &dyn Memory, addr: u16) -> Instruction {
+    let opcode = mem.peek(addr);
+    let info = match lookup(opcode) {
+        Some(info) => info,
+        None => return Instruction { addr, bytes: vec![opcode], name: None, operand: String::new() },
+    };
+
+    let bytes: Vec<u8> = (0..info.byte as u16).map(|i| mem.peek(addr.wrapping_add(i))).collect();
+    let byte = bytes.get(1).copied().unwrap_or_default();
+    let word = u16::from_le_bytes([byte, bytes.get(2).copied().unwrap_or_default()]);
+
+    let operand = match info.mode {
+        AddressingMode::Implied     => String::new(),
+        AddressingMode::Accumulator => "A".to_string(),
+        AddressingMode::Immediate   => format!("#${:02X}", byte),
+        AddressingMode::ZeroPage    => format!("${:02X}", byte),
+        AddressingMode::ZeroPageX   => format!("${:02X},X", byte),
+        AddressingMode::ZeroPageY   => format!("${:02X},Y", byte),
+        AddressingMode::Absolute    => format!("${:04X}", word),
+        AddressingMode::AbsoluteX   => format!("${:04X},X", word),
+        AddressingMode::AbsoluteY   => format!("${:04X},Y", word),
+        AddressingMode::Indirect    => format!("(${:04X})", word),
+        AddressingMode::IndirectX   => format!("(${:02X},X)", byte),
+        AddressingMode::IndirectY   => format!("(${:02X}),Y", byte),
+        AddressingMode::Relative    => {
+            let target = addr.wrapping_add(2).wrapping_add(byte as i8 as u16);
+            format!("${:04X}", target)
+        }
+    };
+    Instruction { addr, bytes, name: Some(info.name), operand }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::FlatMemory;
+
+    #[test]
+    fn test_disassemble() {
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0xB1, 0x10, 0xD0, 0xFC, 0x6C, 0x34, 0x12, 0x02]);
+
+        let text: Vec<String> = [0x8000, 0x8002, 0x8004, 0x8007]
+            .iter()
+            .map(|addr| disassemble(&mem, *addr).to_string())
+            .collect();
+        assert_eq!(text, [
+            "8000  B1 10     LDA ($10),Y",
+            "8002  D0 FC     BNE $8000",
+            "8004  6C 34 12  JMP ($1234)",
+            "8007  02        .byte $02",
+        ]);
+        assert_eq!(disassemble(&mem, 0x8004).next_addr(), 0x8007);
+    }
+}
@@ -0,0 +1,496 @@
+//! Provide stub of GDB remote serial protocol
+//!
+//! Registers are numbered as in `target.xml`: a, x, y, s, p and pc. While the
+//! target is running, execution is done in chunks of cycles so that interrupt
+//! request (Ctrl-C) from GDB can be noticed. Ctrl-C while stopped is ignored.
+
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufReader, BufWriter, Read, Write};
+use std::net::{Shutdown, TcpListener, ToSocketAddrs};
+use std::sync::mpsc::{self, Receiver, Sender, TryRecvError};
+use std::thread::{self, JoinHandle};
+
+use crate::debugger::{BreakOn, BreakpointId, Debugger, StopReason, WatchKind, WatchpointId};
+use crate::register::Status;
+
+/// Target description sent to GDB through qXfer:features:read
+pub const TARGET_XML: &str = include_str!("gdb/target.xml");
+
+/// Cycles executed between checks of interrupt request
+const RUN_CHUNK: u64 = 10_000;
+
+const SIGINT:  u8 = 2;
+const SIGTRAP: u8 = 5;
+
+enum Event {
+    Packet(String),
+    /// Packet with wrong checksum
+    Corrupt,
+    Interrupt,
+}
+
+pub struct GdbServer {
+    dbg: Debugger,
+    breakpoints: HashMap<u16, BreakpointId>,
+    watchpoints: HashMap<(char, u16, u16), WatchpointId>,
+    pending: VecDeque<Event>,
+    no_ack: bool,
+}
+
+impl GdbServer {
+    pub fn new(dbg: Debugger) -> GdbServer {
+        GdbServer {
+            dbg,
+            breakpoints: HashMap::new(),
+            watchpoints: HashMap::new(),
+            pending: VecDeque::new(),
+            no_ack: false,
+        }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.dbg
+    }
+
+    pub fn into_debugger(self) -> Debugger {
+        self.dbg
+    }
+
+    /// Wait for one connection on given address and serve it
+    pub fn serve_tcp(&mut self, addr: impl ToSocketAddrs) -> io::Result<()> {
+        let listener = TcpListener::bind(addr)?;
+        let (stream, _) = listener.accept()?;
+        stream.set_nodelay(true)?;
+        let reader = stream.try_clone()?;
+        let closer = stream.try_clone()?;
+        let (result, thread) = self.serve_with(reader, stream);
+        // Reading is stopped at once, even if GDB keeps the connection
+        let _ = closer.shutdown(Shutdown::Read);
+        thread.join().expect("reader of packets does not panic");
+        result
+    }
+
+    /// Serve GDB connected to stdin and stdout, like `target remote | nes_cpu`.
+    /// The call returns at detach without waiting the end of stdin.
+    pub fn serve_stdio(&mut self) -> io::Result<()> {
+        // Read of stdin can't be stopped, so the reader is left. It ends at
+        // the next packet since nobody receives it, or at the exit.
+        let (result, _reader) = self.serve_with(io::stdin(), io::stdout());
+        result
+    }
+
+    /// Serve until GDB detach, kill or close the connection. The reader is read
+    /// by a thread, and the call returns after the reader reaches its end.
+    pub fn serve<R, W>(&mut self, reader: R, writer: W) -> io::Result<()>
+    where
+        R: Read + Send + 'static,
+        W: Write,
+    {
+        let (result, thread) = self.serve_with(reader, writer);
+        thread.join().expect("reader of packets does not panic");
+        result
+    }
+
+    // Serve with the reader read by a thread. Caller ends the reader and joins it.
+    fn serve_with<R, W>(&mut self, reader: R, writer: W) -> (io::Result<()>, JoinHandle<()>)
+    where
+        R: Read + Send + 'static,
+        W: Write,
+    {
+        let (tx, rx) = mpsc::channel();
+        let thread = thread::spawn(move || read_events(reader, tx));
+        (self.serve_events(&rx, writer), thread)
+    }
+
+    fn serve_events<W: Write>(&mut self, rx: &Receiver<Event>, writer: W) -> io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        loop {
+            let event = match self.pending.pop_front() {
+                Some(event) => event,
+                None => match rx.recv() {
+                    Ok(event) => event,
+                    Err(_) => return Ok(()),
+                },
+            };
+            let packet = match event {
+                Event::Packet(packet) => packet,
+                Event::Corrupt => {
+                    writer.write_all(b"-")?;
+                    writer.flush()?;
+                    continue;
+                }
+                // Target is already stopped
+                Event::Interrupt => continue,
+            };
+            if !self.no_ack {
+                writer.write_all(b"+")?;
+            }
+
+            match packet.as_bytes().first() {
+                Some(b'k') => return writer.flush(),
+                Some(b'D') => {
+                    write_packet(&mut writer, "OK")?;
+                    return Ok(());
+                }
+                _ => (),
+            }
+            let reply = self.handle(&packet, rx);
+            write_packet(&mut writer, &reply)?;
+        }
+    }
+
+    fn handle(&mut self, packet: &str, rx: &Receiver<Event>) -> String {
+        let (cmd, args) = match packet.get(..1) {
+            Some(cmd) => (cmd, &packet[1..]),
+            None => return String::new(),
+        };
+        match cmd {
+            "?" => format!("S{:02x}", SIGTRAP),
+            "g" => self.read_registers(),
+            "G" => self.write_registers(args),
+            "p" => self.read_register(args),
+            "P" => self.write_register(args),
+            "m" => self.read_memory(args),
+            "M" => self.write_memory(args),
+            "s" => {
+                let reason = self.dbg.step_in();
+                self.stop_reply(reason)
+            }
+            "c" => self.resume(rx),
+            "Z" => self.insert_point(args),
+            "z" => self.remove_point(args),
+            "H" => "OK".to_string(),
+            "q" | "Q" => self.query(packet),
+            _ => String::new(),
+        }
+    }
+
+    fn query(&mut self, packet: &str) -> String {
+        if packet.starts_with("qSupported") {
+            "PacketSize=1000;qXfer:features:read+;swbreak+;QStartNoAckMode+".to_string()
+        } else if let Some(args) = packet.strip_prefix("qXfer:features:read:target.xml:") {
+            read_xfer(TARGET_XML, args).unwrap_or_else(|| "E01".to_string())
+        } else if packet == "QStartNoAckMode" {
+            self.no_ack = true;
+            "OK".to_string()
+        } else if packet == "qAttached" {
+            "1".to_string()
+        } else if packet == "qC" {
+            "QC1".to_string()
+        } else if packet == "qfThreadInfo" {
+            "m1".to_string()
+        } else if packet == "qsThreadInfo" {
+            "l".to_string()
+        } else {
+            String::new()
+        }
+    }
+
+    // Run in chunks until stop reason other than the end of chunk, or interrupt
+    fn resume(&mut self, rx: &Receiver<Event>) -> String {
+        loop {
+            let target = self.dbg.cpu().cycles() + RUN_CHUNK;
+            match self.dbg.run_until_cycle(target) {
+                StopReason::Cycle => (),
+                reason => return self.stop_reply(reason),
+            }
+            loop {
+                match rx.try_recv() {
+                    Ok(Event::Interrupt) => return format!("S{:02x}", SIGINT),
+                    Ok(event) => self.pending.push_back(event),
+                    Err(TryRecvError::Empty) => break,
+                    Err(TryRecvError::Disconnected) => return format!("S{:02x}", SIGINT),
+                }
+            }
+        }
+    }
+
+    fn stop_reply(&self, reason: StopReason) -> String {
+        match reason {
+            StopReason::Breakpoint(_) => format!("T{:02x}swbreak:;", SIGTRAP),
+            StopReason::Watchpoint(id, access) => {
+                let kind = match self.dbg.watchpoint(id).map(|wp| wp.kind) {
+                    Some(WatchKind::Read)  => "rwatch",
+                    Some(WatchKind::Write) => "watch",
+                    _ => "awatch",
+                };
+                format!("T{:02x}{}:{:04x};", SIGTRAP, kind, access.addr)
+            }
+            StopReason::Step | StopReason::Cycle => format!("S{:02x}", SIGTRAP),
+        }
+    }
+
+    fn read_registers(&self) -> String {
+        (0..6).map(|n| self.register(n).unwrap_or_default()).collect()
+    }
+
+    fn write_registers(&mut self, args: &str) -> String {
+        let bytes = match decode_hex(args) {
+            Some(bytes) if bytes.len() >= 7 => bytes,
+            _ => return "E01".to_string(),
+        };
+        let reg = self.dbg.cpu_mut().reg_mut();
+        reg.a  = bytes[0];
+        reg.x  = bytes[1];
+        reg.y  = bytes[2];
+        reg.s  = bytes[3];
+        reg.p  = Status::from_bits(bytes[4]);
+        reg.pc = u16::from_le_bytes([bytes[5], bytes[6]]);
+        "OK".to_string()
+    }
+
+    fn read_register(&self, args: &str) -> String {
+        usize::from_str_radix(args, 16)
+            .ok()
+            .and_then(|n| self.register(n))
+            .unwrap_or_else(|| "E01".to_string())
+    }
+
+    fn write_register(&mut self, args: &str) -> String {
+        let parsed = args.split_once('=').and_then(|(n, value)| {
+            Some((usize::from_str_radix(n, 16).ok()?, decode_hex(value)?))
+        });
+        let (n, bytes) = match parsed {
+            Some((n, bytes)) if !bytes.is_empty() => (n, bytes),
+            _ => return "E01".to_string(),
+        };
+        let reg = self.dbg.cpu_mut().reg_mut();
+        match n {
+            0 => reg.a = bytes[0],
+            1 => reg.x = bytes[0],
+            2 => reg.y = bytes[0],
+            3 => reg.s = bytes[0],
+            4 => reg.p = Status::from_bits(bytes[0]),
+            5 if bytes.len() >= 2 => reg.pc = u16::from_le_bytes([bytes[0], bytes[1]]),
+            _ => return "E01".to_string(),
+        }
+        "OK".to_string()
+    }
+
+    // Register in target byte order (little endian)
+    fn register(&self, n: usize) -> Option<String> {
+        let reg = self.dbg.cpu().reg();
+        match n {
+            0 => Some(format!("{:02x}", reg.a)),
+            1 => Some(format!("{:02x}", reg.x)),
+            2 => Some(format!("{:02x}", reg.y)),
+            3 => Some(format!("{:02x}", reg.s)),
+            4 => Some(format!("{:02x}", reg.p.as_bits())),
+            5 => Some(encode_hex(&reg.pc.to_le_bytes())),
+            _ => None,
+        }
+    }
+
+    fn read_memory(&self, args: &str) -> String {
+        let (addr, len) = match parse_addr_len(args) {
+            Some(range) => range,
+            None => return "E01".to_string(),
+        };
+        let mem = self.dbg.cpu().mem();
+        (0..len)
+            .map(|i| format!("{:02x}", mem.peek(addr.wrapping_add(i))))
+            .collect()
+    }
+
+    fn write_memory(&mut self, args: &str) -> String {
+        let parsed = args.split_once(':').and_then(|(range, data)| {
+            Some((parse_addr_len(range)?, decode_hex(data)?))
+        });
+        let (addr, bytes) = match parsed {
+            Some(((addr, len), bytes)) if bytes.len() == len as usize => (addr, bytes),
+            _ => return "E01".to_string(),
+        };
+        let mem = self.dbg.cpu_mut().mem_mut();
+        for (i, byte) in bytes.into_iter().enumerate() {
+            mem.write_byte(addr.wrapping_add(i as u16), byte);
+        }
+        "OK".to_string()
+    }
+
+    // Z0/Z1: breakpoint, Z2: write watchpoint, Z3: read watchpoint, Z4: access watchpoint
+    fn insert_point(&mut self, args: &str) -> String {
+        let (kind, addr, len) = match parse_point(args) {
+            Some(point) => point,
+            None => return "E01".to_string(),
+        };
+        match kind {
+            '0' | '1' => {
+                if !self.breakpoints.contains_key(&addr) {
+                    let id = self.dbg.add_breakpoint(BreakOn::Pc(addr));
+                    self.breakpoints.insert(addr, id);
+                }
+            }
+            '2' | '3' | '4' => {
+                let watch = match kind {
+                    '2' => WatchKind::Write,
+                    '3' => WatchKind::Read,
+                    _   => WatchKind::ReadWrite,
+                };
+                let end = addr.wrapping_add(len.max(1) - 1);
+                let id  = self.dbg.add_watchpoint(addr..=end, watch);
+                if let Some(old) = self.watchpoints.insert((kind, addr, len), id) {
+                    self.dbg.remove_watchpoint(old);
+                }
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+
+    fn remove_point(&mut self, args: &str) -> String {
+        let (kind, addr, len) = match parse_point(args) {
+            Some(point) => point,
+            None => return "E01".to_string(),
+        };
+        match kind {
+            '0' | '1' => {
+                if let Some(id) = self.breakpoints.remove(&addr) {
+                    self.dbg.remove_breakpoint(id);
+                }
+            }
+            '2' | '3' | '4' => {
+                if let Some(id) = self.watchpoints.remove(&(kind, addr, len)) {
+                    self.dbg.remove_watchpoint(id);
+                }
+            }
+            _ => return String::new(),
+        }
+        "OK".to_string()
+    }
+}
+
+// Split byte stream into packets and interrupt requests
+fn read_events<R: Read>(reader: R, tx: Sender<Event>) {
+    let mut bytes = BufReader::new(reader).bytes().map_while(Result::ok);
+    while let Some(byte) = bytes.next() {
+        let event = match byte {
+            0x03 => Event::Interrupt,
+            b'$' => {
+                let data: Vec<u8> = bytes.by_ref().take_while(|&b| b != b'#').collect();
+                let sum: String   = bytes.by_ref().take(2).map(|b| b as char).collect();
+                let expect = data.iter().fold(0u8, |acc, b| acc.wrapping_add(*b));
+                if u8::from_str_radix(&sum, 16) == Ok(expect) {
+                    Event::Packet(String::from_utf8_lossy(&data).into_owned())
+                } else {
+                    Event::Corrupt
+                }
+            }
+            // Acks and garbage between packets
+            _ => continue,
+        };
+        if tx.send(event).is_err() {
+            return;
+        }
+    }
+}
+
+fn write_packet<W: Write>(writer: &mut W, data: &str) -> io::Result<()> {
+    let sum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+    write!(writer, "${}#{:02x}", data, sum)?;
+    writer.flush()
+}
+
+// Answer "offset,length" of qXfer read
+fn read_xfer(data: &str, args: &str) -> Option<String> {
+    let (offset, len) = args.split_once(',')?;
+    let offset = usize::from_str_radix(offset, 16).ok()?.min(data.len());
+    let len    = usize::from_str_radix(len, 16).ok()?;
+    let end    = (offset + len).min(data.len());
+    let mark   = if end == data.len() { 'l' } else { 'm' };
+    Some(format!("{}{}", mark, &data[offset..end]))
+}
+
+fn parse_addr_len(args: &str) -> Option<(u16, u16)> {
+    let (addr, len) = args.split_once(',')?;
+    Some((u16::from_str_radix(addr, 16).ok()?, u16::from_str_radix(len, 16).ok()?))
+}
+
+fn parse_point(args: &str) -> Option<(char, u16, u16)> {
+    let mut fields = args.split(',');
+    let kind = fields.next()?.chars().next()?;
+    let addr = u16::from_str_radix(fields.next()?, 16).ok()?;
+    let len  = u16::from_str_radix(fields.next()?, 16).ok()?;
+    Some((kind, addr, len))
+}
+
+fn encode_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
+
+fn decode_hex(hex: &str) -> Option<Vec<u8>> {
+    if !hex.len().is_multiple_of(2) {
+        return None;
+    }
+    (0..hex.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cpu::Cpu;
+    use crate::memory::FlatMemory;
+    use std::io::Cursor;
+
+    fn packet(data: &str) -> String {
+        let sum = data.bytes().fold(0u8, |acc, b| acc.wrapping_add(b));
+        format!("${}#{:02x}", data, sum)
+    }
+
+    // Send given packets in NoAck mode and return replies. "\x03" is sent as Ctrl-C.
+    fn session(packets: &[&str]) -> Vec<String> {
+        // $8000: LDA #$42; STA $0300; JMP $8005
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0xA9, 0x42, 0x8D, 0x00, 0x03, 0x4C, 0x05, 0x80]);
+        let mut cpu = Cpu::new(Box::new(mem));
+        cpu.reg_mut().pc = 0x8000;
+
+        let mut input = packet("QStartNoAckMode");
+        for p in packets {
+            match *p {
+                "\x03" => input.push('\x03'),
+                _ => input.push_str(&packet(p)),
+            }
+        }
+        let mut output = Vec::new();
+        let mut server = GdbServer::new(Debugger::new(cpu));
+        server.serve(Cursor::new(input.into_bytes()), &mut output).unwrap();
+
+        String::from_utf8(output).unwrap()
+            .trim_start_matches('+')
+            .split('$')
+            .skip(2)
+            .map(|p| p.split('#').next().unwrap().to_string())
+            .collect()
+    }
+
+    #[test]
+    fn test_registers_and_memory() {
+        let replies = session(&["p5", "s", "g", "M300,2:abcd", "m2ff,3", "P0=07", "p0"]);
+        assert_eq!(replies, [
+            "0080", "S05", "42000000200280", "OK", "00abcd", "OK", "07",
+        ]);
+    }
+
+    #[test]
+    fn test_breakpoint_and_watchpoint() {
+        let replies = session(&["Z2,300,1", "c", "z2,300,1", "Z0,8005,1", "c", "c"]);
+        assert_eq!(replies, ["OK", "T05watch:0300;", "OK", "OK", "T05swbreak:;", "T05swbreak:;"]);
+    }
+
+    #[test]
+    fn test_interrupt() {
+        // Ctrl-C is answered only while running
+        let replies = session(&["\x03", "p5", "c", "\x03"]);
+        assert_eq!(replies, ["0080", "S02"]);
+    }
+
+    #[test]
+    fn test_target_xml() {
+        let replies = session(&["qXfer:features:read:target.xml:0,5"]);
+        assert_eq!(replies, ["m<?xml"]);
+        assert_eq!(read_xfer("abc", "1,10"), Some("lbc".to_string()));
+    }
+}
@@ -0,0 +1,5 @@
+//! Provide harnesses that run well-known 6502 test programs and test roms
+
+pub mod klaus;
+pub mod blargg;
+pub mod frames;
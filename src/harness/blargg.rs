@@ -0,0 +1,177 @@
+//! Runner for blargg's test roms that report through $6000
+//!
+//! Once the signature $DE $B0 $61 is written at $6001, $6000 holds the status
+//! of the test: $80 while running, $81 when the rom asks for reset, and the
+//! result code otherwise (0 means passed). A zero terminated message is
+//! written from $6004.
+//!
+//! Only NROM (mapper 0) images can be run, since `Bus` has no other mapper. The
+//! full images of instr_test-v5, instr_timing, cpu_interrupts_v2 and instr_misc
+//! are MMC1, and `BlarggTest::new` rejects them with `UnsupportedMapper`.
+
+use crate::bus::Bus;
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::cpu::Cpu;
+
+const STATUS_ADDR:    u16 = 0x6000;
+const SIGNATURE_ADDR: u16 = 0x6001;
+const MESSAGE_ADDR:   u16 = 0x6004;
+const SIGNATURE: [u8; 3]  = [0xDE, 0xB0, 0x61];
+
+const STATUS_RUNNING: u8 = 0x80;
+const STATUS_RESET:   u8 = 0x81;
+
+/// Instructions to wait before serving reset request (about 100ms)
+const RESET_DELAY: u64 = 60_000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    Passed,
+    /// Rom reported given result code
+    Failed(u8),
+    /// Step limit was reached before rom reported result
+    Timeout,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct BlarggResult {
+    pub outcome: Outcome,
+    pub message: String,
+}
+
+pub struct BlarggTest {
+    cpu: Cpu,
+    reset_delay: u64,
+}
+
+impl BlarggTest {
+    /// Load iNES image of NROM and do power-up reset
+    pub fn new(rom: &[u8]) -> Result<BlarggTest, CartridgeError> {
+        let bus = Bus::new(Cartridge::from_bytes(rom)?)?;
+        let mut cpu = Cpu::new(Box::new(bus));
+        cpu.reset();
+
+        Ok(BlarggTest { cpu, reset_delay: RESET_DELAY })
+    }
+
+    /// Wait given number of instructions before serving reset request
+    pub fn with_reset_delay(mut self, steps: u64) -> Self {
+        self.reset_delay = steps;
+        self
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// Run until rom report result, or until `max_steps` instructions were executed
+    pub fn run(&mut self, max_steps: u64) -> BlarggResult {
+        let mut reset_wait = None;
+
+        for _ in 0..max_steps {
+            self.cpu.step();
+
+            match self.status() {
+                Some(STATUS_RUNNING) | None => reset_wait = None,
+                Some(STATUS_RESET) => match reset_wait {
+                    None => reset_wait = Some(self.reset_delay),
+                    Some(0) => {
+                        self.cpu.reset();
+                        reset_wait = None;
+                    }
+                    Some(n) => reset_wait = Some(n - 1),
+                },
+                Some(0) => return self.result(Outcome::Passed),
+                Some(code) => return self.result(Outcome::Failed(code)),
+            }
+        }
+        self.result(Outcome::Timeout)
+    }
+
+    /// Return status byte if signature was written
+    pub fn status(&self) -> Option<u8> {
+        let mem = self.cpu.mem();
+        let signature = [
+            mem.peek(SIGNATURE_ADDR),
+            mem.peek(SIGNATURE_ADDR + 1),
+            mem.peek(SIGNATURE_ADDR + 2),
+        ];
+        if signature == SIGNATURE {
+            Some(mem.peek(STATUS_ADDR))
+        } else {
+            None
+        }
+    }
+
+    /// Read text written from $6004
+    pub fn message(&self) -> String {
+        let mem = self.cpu.mem();
+        (MESSAGE_ADDR..0x8000)
+            .map(|addr| mem.peek(addr))
+            .take_while(|&c| c != 0)
+            .map(|c| c as char)
+            .collect()
+    }
+
+    fn result(&self, outcome: Outcome) -> BlarggResult {
+        BlarggResult { outcome, message: self.message() }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom;
+
+    // Mark running, write signature, message "ok" and given status
+    fn report(status: u8) -> Vec<u8> {
+        vec![
+            0xA9, 0x80, 0x8D, 0x00, 0x60, // LDA #$80; STA $6000
+            0xA9, 0xDE, 0x8D, 0x01, 0x60, // LDA #$DE; STA $6001
+            0xA9, 0xB0, 0x8D, 0x02, 0x60, // LDA #$B0; STA $6002
+            0xA9, 0x61, 0x8D, 0x03, 0x60, // LDA #$61; STA $6003
+            0xA9, b'o', 0x8D, 0x04, 0x60, // LDA #'o'; STA $6004
+            0xA9, b'k', 0x8D, 0x05, 0x60, // LDA #'k'; STA $6005
+            0xA9, status, 0x8D, 0x00, 0x60, // LDA #status; STA $6000
+        ]
+    }
+
+    #[test]
+    fn test_result() {
+        let mut program = report(3);
+        program.extend([0x4C, 0x23, 0x80]); // JMP $8023
+
+        let mut test = BlarggTest::new(&nrom(&program, &[])).unwrap();
+        let result = test.run(1000);
+        assert_eq!(result, BlarggResult { outcome: Outcome::Failed(3), message: "ok".into() });
+    }
+
+    #[test]
+    fn test_reset_request() {
+        // First boot requests reset, second boot reports success
+        let mut program = vec![
+            0xAD, 0x10, 0x60, // LDA $6010
+            0xD0, 0x29,       // BNE +41
+            0xEE, 0x10, 0x60, // INC $6010
+        ];
+        program.extend(report(STATUS_RESET));
+        // Program at $C000 is mirrored at $8000
+        program.extend([0x4C, 0x2B, 0x80]); // JMP $802B
+        program.extend(report(0));
+        program.extend([0x4C, 0x51, 0x80]); // JMP $8051
+
+        let mut test = BlarggTest::new(&nrom(&program, &[])).unwrap().with_reset_delay(10);
+        assert_eq!(test.run(1000).outcome, Outcome::Passed);
+    }
+
+    #[test]
+    fn test_mmc1_rejected() {
+        let mut image = nrom(&[0x4C, 0x00, 0x80], &[]);
+        image[6] = 0x10;
+        assert_eq!(BlarggTest::new(&image).err(), Some(CartridgeError::UnsupportedMapper(1)));
+    }
+}
@@ -0,0 +1,252 @@
+//! Regression harness that compares hash of rendered frames
+//!
+//! A rom is run for a number of frames with scripted actions, and the hash of the
+//! last frame is compared with the expected one. Expectations are written in a
+//! manifest, one test per line:
+//!
+//! ```text
+//! # rom               frames  hash              actions
+//! sprite_hit/01.nes   60      0123456789abcdef
+//! vbl_nmi/02.nes      120     -                 reset@30
+//! nmi_sync/03.nes     30      -                 buttons=0:08@10 buttons=0:00@11
+//! ```
+//!
+//! Hash `-` means not known yet; such test always fails and reports the hash.
+
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use crate::cartridge::CartridgeError;
+use crate::controller::Buttons;
+use crate::memory::Memory;
+use crate::nes::Nes;
+
+const FNV_OFFSET: u64 = 0xCBF2_9CE4_8422_2325;
+const FNV_PRIME:  u64 = 0x0000_0100_0000_01B3;
+
+/// Stable hash of a frame (64bit FNV-1a)
+pub fn frame_hash(frame: &[u8]) -> u64 {
+    frame.iter().fold(FNV_OFFSET, |hash, &b| (hash ^ b as u64).wrapping_mul(FNV_PRIME))
+}
+
+/// Action done by the script before the frame is run
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Action {
+    /// Press the reset button
+    Reset,
+    /// Write a byte to cpu bus
+    Write(u16, u8),
+    /// Set buttons of the controller at port 0 or 1, held until set again
+    Buttons(usize, Buttons),
+}
+
+impl Action {
+    /// Parse `reset@<frame>`, `write=<addr>:<value>@<frame>` or
+    /// `buttons=<port>:<bits>@<frame>` (hex numbers, A is bit 0)
+    pub fn parse(text: &str) -> Result<(u64, Action), String> {
+        let error = || format!("invalid action '{}'", text);
+        let (action, frame) = text.split_once('@').ok_or_else(error)?;
+        let frame = frame.parse().map_err(|_| error())?;
+
+        let action = match action.split_once('=') {
+            None if action == "reset" => Action::Reset,
+            Some(("write", arg)) => {
+                let (addr, value) = arg.split_once(':').ok_or_else(error)?;
+                let addr  = u16::from_str_radix(addr.trim_start_matches('$'), 16).map_err(|_| error())?;
+                let value = u8::from_str_radix(value.trim_start_matches('$'), 16).map_err(|_| error())?;
+                Action::Write(addr, value)
+            }
+            Some(("buttons", arg)) => {
+                let (port, bits) = arg.split_once(':').ok_or_else(error)?;
+                let port = match port {
+                    "0" => 0,
+                    "1" => 1,
+                    _ => return Err(error()),
+                };
+                let bits = u8::from_str_radix(bits, 16).map_err(|_| error())?;
+                Action::Buttons(port, Buttons::from_bits(bits))
+            }
+            _ => return Err(error()),
+        };
+        Ok((frame, action))
+    }
+
+    fn apply(&self, nes: &mut Nes) {
+        match *self {
+            Action::Reset => nes.reset(),
+            Action::Write(addr, value) => nes.cpu_mut().mem_mut().write_byte(addr, value),
+            Action::Buttons(port, buttons) => nes.set_buttons(port, buttons),
+        }
+    }
+}
+
+impl fmt::Display for Action {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Reset => write!(f, "reset"),
+            Action::Write(addr, value) => write!(f, "write={:04x}:{:02x}", addr, value),
+            Action::Buttons(port, buttons) => write!(f, "buttons={}:{:02x}", port, buttons.as_bits()),
+        }
+    }
+}
+
+/// Runner of a rom with scripted actions
+pub struct FrameTest {
+    nes: Nes,
+    // Sorted by frame
+    script: Vec<(u64, Action)>,
+}
+
+impl FrameTest {
+    pub fn new(rom: &[u8]) -> Result<FrameTest, CartridgeError> {
+        Ok(FrameTest { nes: Nes::from_bytes(rom)?, script: Vec::new() })
+    }
+
+    /// Do action when `frame` frames were completed, before the next frame is run
+    pub fn at(mut self, frame: u64, action: Action) -> Self {
+        let index = self.script.partition_point(|(f, _)| *f <= frame);
+        self.script.insert(index, (frame, action));
+        self
+    }
+
+    pub fn nes(&self) -> &Nes {
+        &self.nes
+    }
+
+    pub fn nes_mut(&mut self) -> &mut Nes {
+        &mut self.nes
+    }
+
+    /// Run until `frames` frames are completed from power-up, and return hash of the last one
+    pub fn run(&mut self, frames: u64) -> u64 {
+        while self.nes.ppu().frame_count() < frames {
+            let frame = self.nes.ppu().frame_count();
+            for (_, action) in self.script.iter().filter(|(f, _)| *f == frame) {
+                action.apply(&mut self.nes);
+            }
+            self.nes.run_frame();
+        }
+        frame_hash(self.nes.frame())
+    }
+
+    /// Save the last frame as PNG
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        self.nes.save_png(path)
+    }
+}
+
+/// A line of manifest
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expectation {
+    pub rom: PathBuf,
+    pub frames: u64,
+    pub hash: Option<u64>,
+    pub script: Vec<(u64, Action)>,
+}
+
+impl Expectation {
+    /// Run the test. Rom path is relative to `base`.
+    pub fn run(&self, base: &Path) -> Result<FrameTest, String> {
+        let path = base.join(&self.rom);
+        let rom  = std::fs::read(&path).map_err(|e| format!("{}: {}", path.display(), e))?;
+        let mut test = FrameTest::new(&rom).map_err(|e| format!("{}: {}", path.display(), e))?;
+        for (frame, action) in &self.script {
+            test = test.at(*frame, action.clone());
+        }
+        test.run(self.frames);
+        Ok(test)
+    }
+}
+
+impl fmt::Display for Expectation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.rom.display(), self.frames)?;
+        match self.hash {
+            Some(hash) => write!(f, " {:016x}", hash)?,
+            None => write!(f, " -")?,
+        }
+        for (frame, action) in &self.script {
+            write!(f, " {}@{}", action, frame)?;
+        }
+        Ok(())
+    }
+}
+
+/// Parse manifest. Empty lines and lines starting with `#` are skipped.
+pub fn parse_manifest(text: &str) -> Result<Vec<Expectation>, String> {
+    let mut tests = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let error = |msg: String| format!("line {}: {}", n + 1, msg);
+        let mut words = line.split_whitespace();
+        let (Some(rom), Some(frames), Some(hash)) = (words.next(), words.next(), words.next()) else {
+            return Err(error("expected '<rom> <frames> <hash>'".to_string()));
+        };
+        let frames = frames.parse().map_err(|_| error(format!("invalid frames '{}'", frames)))?;
+        let hash = match hash {
+            "-" => None,
+            _ => Some(u64::from_str_radix(hash, 16).map_err(|_| error(format!("invalid hash '{}'", hash)))?),
+        };
+        let script = words.map(Action::parse).collect::<Result<_, _>>().map_err(error)?;
+        tests.push(Expectation { rom: PathBuf::from(rom), frames, hash, script });
+    }
+    Ok(tests)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom;
+    use crate::ppu::{HEIGHT, PALETTE, WIDTH};
+
+    // Rom that sets backdrop to the color at $00, written by the script.
+    // Rendering is disabled, so the frame is filled with the backdrop color.
+    fn rom() -> Vec<u8> {
+        let program = [
+            0x2C, 0x02, 0x20, 0x10, 0xFB, // wait vblank
+            0xA9, 0x3F, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20, // $2006 = $3F00
+            0xA5, 0x00, 0x8D, 0x07, 0x20, // $2007 = [$00]
+            0x4C, 0x00, 0xC0,
+        ];
+        nrom(&program, &[])
+    }
+
+    #[test]
+    fn test_frame_hash() {
+        let run = |value| FrameTest::new(&rom()).unwrap().at(2, Action::Write(0x00, value)).run(5);
+        let hash = run(0x16);
+        assert_eq!(hash, run(0x16));
+        assert_ne!(hash, run(0x21));
+
+        let red: Vec<u8> = std::iter::repeat_n(PALETTE[0x16], WIDTH * HEIGHT).flatten().collect();
+        assert_eq!(hash, frame_hash(&red));
+
+        // Buttons are held from the frame
+        let mut test = FrameTest::new(&rom()).unwrap().at(2, Action::Buttons(0, Buttons::UP));
+        test.run(2);
+        assert_eq!(test.nes().bus().joypad(0).buttons(), Buttons::NONE);
+        test.run(4);
+        assert_eq!(test.nes().bus().joypad(0).buttons(), Buttons::UP);
+    }
+
+    #[test]
+    fn test_manifest() {
+        let text = "# comment\n\na.nes 60 00000000000000ff reset@30 write=6000:80@2 buttons=1:09@5\nb.nes 10 -\n";
+        let tests = parse_manifest(text).unwrap();
+        assert_eq!(tests[0].hash, Some(0xFF));
+        assert_eq!(tests[0].script, [
+            (30, Action::Reset),
+            (2, Action::Write(0x6000, 0x80)),
+            (5, Action::Buttons(1, Buttons::A | Buttons::START)),
+        ]);
+        assert_eq!(tests[1].to_string(), "b.nes 10 -");
+        assert_eq!(tests[0].to_string(), text.lines().nth(2).unwrap());
+        assert!(parse_manifest("a.nes 60").is_err());
+        assert!(parse_manifest("a.nes 60 - jump@3").is_err());
+        assert!(parse_manifest("a.nes 60 - buttons=2:01@3").is_err());
+    }
+}
@@ -0,0 +1,145 @@
+//! Harness for Klaus Dormann's 6502_functional_test and 6502_interrupt_test
+//!
+//! Both tests are assembled into a flat 64KiB image and start at $0400. A failed
+//! check ends in a "trap", a branch or jump to itself, so the harness stops as soon
+//! as an instruction leaves pc unchanged. The test number of the failed check is
+//! the byte stored at `test_case` ($0200 in the default configuration).
+
+use crate::cpu::Cpu;
+use crate::memory::FlatMemory;
+
+/// Address the tests start executing from
+pub const START_ADDR: u16 = 0x0400;
+
+/// Address of `test_case` in the default configuration
+pub const TEST_CASE_ADDR: u16 = 0x0200;
+
+/// Address of the feedback register used by 6502_interrupt_test
+pub const FEEDBACK_ADDR: u16 = 0xBFFC;
+
+const FEEDBACK_IRQ: u8 = 0b0000_0001;
+const FEEDBACK_NMI: u8 = 0b0000_0010;
+
+/// Result of running a test binary
+#[derive(Debug, PartialEq, Eq)]
+pub enum Outcome {
+    /// Trapped at the success address
+    Success,
+    /// Trapped at other address. `test_case` is the number of the failed test.
+    Trap { addr: u16, test_case: u8 },
+    /// Step limit was reached without trap
+    Timeout { pc: u16 },
+}
+
+pub struct KlausTest {
+    cpu: Cpu,
+    success: Option<u16>,
+    feedback: Option<u16>,
+    test_case: u16,
+}
+
+impl KlausTest {
+    /// Load given image at `load_addr` and set pc to $0400
+    pub fn new(image: &[u8], load_addr: u16) -> KlausTest {
+        let mut mem = FlatMemory::new();
+        mem.load(load_addr, image);
+
+        let mut cpu = Cpu::new(Box::new(mem));
+        cpu.reg_mut().pc = START_ADDR;
+        cpu.reg_mut().s  = 0xFF;
+
+        KlausTest { cpu, success: None, feedback: None, test_case: TEST_CASE_ADDR }
+    }
+
+    /// Report trap at given address as success. The address is `success` in the listing.
+    pub fn with_success(mut self, addr: u16) -> Self {
+        self.success = Some(addr);
+        self
+    }
+
+    /// Drive IRQ (bit 0) and NMI (bit 1) from the register at given address
+    pub fn with_feedback(mut self, addr: u16) -> Self {
+        self.feedback = Some(addr);
+        self
+    }
+
+    /// Read the failed test number from given address instead of $0200
+    pub fn with_test_case(mut self, addr: u16) -> Self {
+        self.test_case = addr;
+        self
+    }
+
+    pub fn cpu(&self) -> &Cpu {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu {
+        &mut self.cpu
+    }
+
+    /// Run until trap, or until `max_steps` instructions were executed
+    pub fn run(&mut self, max_steps: u64) -> Outcome {
+        for _ in 0..max_steps {
+            let pc = self.cpu.reg().pc;
+            self.cpu.step();
+
+            if let Some(addr) = self.feedback {
+                let value = self.cpu.mem().peek(addr);
+                self.cpu.set_irq(value & FEEDBACK_IRQ != 0);
+                self.cpu.set_nmi(value & FEEDBACK_NMI != 0);
+            }
+
+            if self.cpu.reg().pc == pc {
+                return self.trap(pc);
+            }
+        }
+        Outcome::Timeout { pc: self.cpu.reg().pc }
+    }
+
+    fn trap(&self, addr: u16) -> Outcome {
+        if self.success == Some(addr) {
+            Outcome::Success
+        } else {
+            Outcome::Trap { addr, test_case: self.cpu.mem().peek(self.test_case) }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn image(program: &[u8]) -> Vec<u8> {
+        let mut image = vec![0; 0x10000];
+        image[START_ADDR as usize..START_ADDR as usize + program.len()].copy_from_slice(program);
+        image
+    }
+
+    #[test]
+    fn test_trap() {
+        // LDA #$05; STA $0200; JMP $0405
+        let image = image(&[0xA9, 0x05, 0x8D, 0x00, 0x02, 0x4C, 0x05, 0x04]);
+
+        let mut test = KlausTest::new(&image, 0x0000);
+        assert_eq!(test.run(100), Outcome::Trap { addr: 0x0405, test_case: 5 });
+
+        let mut test = KlausTest::new(&image, 0x0000).with_success(0x0405);
+        assert_eq!(test.run(100), Outcome::Success);
+    }
+
+    #[test]
+    fn test_feedback_irq() {
+        // CLI; LDA #$01; STA $BFFC; JMP $0406
+        let mut image = image(&[0x58, 0xA9, 0x01, 0x8D, 0xFC, 0xBF, 0x4C, 0x06, 0x04]);
+        // IRQ handler: JMP $0500
+        image[0x0500..0x0503].copy_from_slice(&[0x4C, 0x00, 0x05]);
+        image[0xFFFE..0x10000].copy_from_slice(&[0x00, 0x05]);
+
+        let mut test = KlausTest::new(&image, 0x0000);
+        assert_eq!(test.run(100), Outcome::Trap { addr: 0x0406, test_case: 0 });
+
+        let mut test = KlausTest::new(&image, 0x0000).with_feedback(FEEDBACK_ADDR);
+        assert_eq!(test.run(100), Outcome::Trap { addr: 0x0500, test_case: 0 });
+        assert!(test.cpu().irq_line());
+    }
+}
@@ -1,5 +1,27 @@
 pub mod memory;
 pub mod cpu;
-
-mod opcode;
-mod register;
+pub mod register;
+pub mod cartridge;
+pub mod bus;
+pub mod controller;
+pub mod opcode;
+pub mod harness;
+pub mod debugger;
+pub mod gdb;
+pub mod dbginfo;
+pub mod loader;
+pub mod disasm;
+pub mod monitor;
+pub mod state;
+pub mod rewind;
+pub mod region;
+pub mod ppu;
+pub mod nes;
+pub mod png;
+pub mod apu;
+pub mod wav;
+pub mod apu_log;
+pub mod movie;
+pub mod nsf;
+#[cfg(feature = "dap")]
+pub mod dap;
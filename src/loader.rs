@@ -0,0 +1,32 @@
+//! Provide loader that build a cpu from rom or raw binary
+
+use crate::bus::Bus;
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::cpu::Cpu;
+use crate::memory::FlatMemory;
+
+/// Return true if given image starts with iNES header
+pub fn is_ines(bytes: &[u8]) -> bool {
+    bytes.starts_with(b"NES\x1A")
+}
+
+/// Build cpu from iNES image, or raw binary loaded at `load_addr`.
+///
+/// Rom is started from reset vector. Raw binary is started from `load_addr`.
+pub fn load_image(bytes: &[u8], load_addr: u16) -> Result<Cpu, CartridgeError> {
+    if is_ines(bytes) {
+        let cart   = Cartridge::from_bytes(bytes)?;
+        let region = cart.region;
+        let mut cpu = Cpu::new(Box::new(Bus::new(cart)?));
+        cpu.set_region(region);
+        cpu.reset();
+        Ok(cpu)
+    } else {
+        let mut mem = FlatMemory::new();
+        mem.load(load_addr, bytes);
+        let mut cpu = Cpu::new(Box::new(mem));
+        cpu.reg_mut().pc = load_addr;
+        cpu.reg_mut().s  = 0xFD;
+        Ok(cpu)
+    }
+}
@@ -1,5 +1,7 @@
 //! Provide trait that represent memoory
 
+use crate::state::{MemoryState, StateError, StateReader, StateWriter};
+
 /// An trait that represent memory
 pub trait Memory {
     /// Read 8bit value from given address
@@ -10,8 +12,8 @@ pub trait Memory {
 
     /// Read 16bit value from given address
     fn read_word(&self, addr: u16) -> u16 {
-        let lsb = self.read_byte(addr);
-        let msb = self.read_byte(addr);
+        let lsb = self.read_byte(addr.wrapping_add(0));
+        let msb = self.read_byte(addr.wrapping_add(1));
         u16::from_le_bytes([lsb, msb])
     }
 
@@ -21,4 +23,140 @@ pub trait Memory {
         self.write_byte(addr.wrapping_add(0), bytes[0]);
         self.write_byte(addr.wrapping_add(1), bytes[1]);
     }
+
+    /// Read 8bit value from given address without any side effect of the read,
+    /// like clearing a flag of a device register. Used by debugger and other tools.
+    /// Memory whose read has side effect must override it.
+    fn peek(&self, addr: u16) -> u8 {
+        self.read_byte(addr)
+    }
+
+    /// Read 16bit value from given address by `peek`
+    fn peek_word(&self, addr: u16) -> u16 {
+        let lsb = self.peek(addr.wrapping_add(0));
+        let msb = self.peek(addr.wrapping_add(1));
+        u16::from_le_bytes([lsb, msb])
+    }
+
+    /// Whether given address is a register of a device, not a storage. Writing it
+    /// again does not restore the state of the device.
+    fn is_io(&self, _addr: u16) -> bool {
+        false
+    }
+
+    /// Address that given address is a mirror of, the same for all mirrors of a
+    /// storage. Code cache uses it to see writes to the code through a mirror.
+    fn mirror_of(&self, addr: u16) -> u16 {
+        addr
+    }
+
+    /// Number that changes when mapping of the address space changes, like bank switch.
+    /// Code cached from the memory is dropped when it changes.
+    fn bank_version(&self) -> u64 {
+        0
+    }
+
+    /// Save state of the memory. None if the memory does not support it.
+    fn state(&self) -> Option<&dyn MemoryState> {
+        None
+    }
+
+    fn state_mut(&mut self) -> Option<&mut dyn MemoryState> {
+        None
+    }
+}
+
+impl<M: Memory + ?Sized> Memory for Box<M> {
+    fn read_byte(&self, addr: u16) -> u8 {
+        (**self).read_byte(addr)
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        (**self).write_byte(addr, value)
+    }
+
+    fn read_word(&self, addr: u16) -> u16 {
+        (**self).read_word(addr)
+    }
+
+    fn write_word(&mut self, addr: u16, value: u16) {
+        (**self).write_word(addr, value)
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        (**self).peek(addr)
+    }
+
+    fn is_io(&self, addr: u16) -> bool {
+        (**self).is_io(addr)
+    }
+
+    fn mirror_of(&self, addr: u16) -> u16 {
+        (**self).mirror_of(addr)
+    }
+
+    fn bank_version(&self) -> u64 {
+        (**self).bank_version()
+    }
+
+    fn state(&self) -> Option<&dyn MemoryState> {
+        (**self).state()
+    }
+
+    fn state_mut(&mut self) -> Option<&mut dyn MemoryState> {
+        (**self).state_mut()
+    }
+}
+
+/// 64KiB of plain RAM without any mirroring or device
+pub struct FlatMemory {
+    data: Box<[u8; 0x10000]>,
+}
+
+impl FlatMemory {
+    pub fn new() -> FlatMemory {
+        FlatMemory { data: Box::new([0; 0x10000]) }
+    }
+
+    /// Copy given bytes into memory, starting at given address
+    pub fn load(&mut self, addr: u16, bytes: &[u8]) {
+        for (i, byte) in bytes.iter().enumerate() {
+            self.data[addr.wrapping_add(i as u16) as usize] = *byte;
+        }
+    }
+}
+
+impl Default for FlatMemory {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Memory for FlatMemory {
+    fn read_byte(&self, addr: u16) -> u8 {
+        self.data[addr as usize]
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        self.data[addr as usize] = value;
+    }
+
+    fn state(&self) -> Option<&dyn MemoryState> {
+        Some(self)
+    }
+
+    fn state_mut(&mut self) -> Option<&mut dyn MemoryState> {
+        Some(self)
+    }
+}
+
+impl MemoryState for FlatMemory {
+    fn save_state(&self, out: &mut StateWriter) {
+        out.section(*b"RAM ", 1, |out| out.write_bytes(self.data.as_slice()));
+    }
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let (_, mut ram) = input.section(*b"RAM ", 1)?;
+        ram.read_into(self.data.as_mut_slice())
+    }
 }
@@ -0,0 +1,438 @@
+//! Provide machine code monitor
+//!
+//! Numbers are hexadecimal, with or without `$`. Commands:
+//!
+//! ```text
+//! r                    show registers
+//! r <reg> <value>      set register (a, x, y, s, p, pc)
+//! m [start [end]]      dump memory
+//! > <addr> <byte>...   write bytes to memory
+//! d [start [end]]      disassemble
+//! b                    list breakpoints
+//! b <addr> [cond]      add breakpoint, with optional condition like `a == $40`
+//! bc <n>               clear breakpoint
+//! t [count]            trace (step into) given number of instructions
+//! p                    step over. Stop after 100000 cycles like `g`
+//! g [addr [cycles]]    go until breakpoint, from given address if any. Stop
+//!                      after given cycles, 100000 by default
+//! f <start> <end> <byte>...     fill memory with byte pattern
+//! c <start> <end> <dest>        compare memory with memory at dest
+//! h <start> <end> <byte>...     hunt byte sequence
+//! q                    quit
+//! ```
+
+use std::fmt::{self, Write};
+
+use crate::cpu::Cpu;
+use crate::debugger::{BreakOn, Breakpoint, Debugger, StopReason};
+use crate::disasm::disassemble;
+use crate::register::Status;
+
+const DUMP_LEN:   u32 = 0x80;
+const DISASM_LEN: usize = 16;
+/// Cycles `g` and `p` run at most without breakpoint hit, about 0.6s of NTSC
+const GO_CYCLES:  u64 = 0x10_0000;
+
+#[derive(Debug, PartialEq, Eq)]
+pub struct MonitorError(String);
+
+impl fmt::Display for MonitorError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl std::error::Error for MonitorError {}
+
+type Result<T> = std::result::Result<T, MonitorError>;
+
+fn error<T>(message: impl Into<String>) -> Result<T> {
+    Err(MonitorError(message.into()))
+}
+
+pub struct Monitor {
+    dbg: Debugger,
+    next_dump: u16,
+    next_disasm: Option<u16>,
+    quit: bool,
+}
+
+impl Monitor {
+    pub fn new(cpu: Cpu) -> Monitor {
+        Monitor { dbg: Debugger::new(cpu), next_dump: 0, next_disasm: None, quit: false }
+    }
+
+    pub fn debugger(&self) -> &Debugger {
+        &self.dbg
+    }
+
+    pub fn into_debugger(self) -> Debugger {
+        self.dbg
+    }
+
+    /// True after `q` command
+    pub fn is_quit(&self) -> bool {
+        self.quit
+    }
+
+    /// Execute one command line and return its output
+    pub fn execute(&mut self, line: &str) -> Result<String> {
+        let line = line.trim();
+        let (cmd, rest) = match line.find(|c: char| c.is_whitespace() || c == '$') {
+            Some(i) => (&line[..i], line[i..].trim()),
+            None    => (line, ""),
+        };
+        // Allow `>addr` without space
+        let (cmd, rest) = match cmd.strip_prefix('>') {
+            Some(addr) if !addr.is_empty() => (">", &line[1..]),
+            _ => (cmd, rest),
+        };
+        let args: Vec<&str> = rest.split_whitespace().collect();
+
+        match cmd.to_ascii_lowercase().as_str() {
+            "" => Ok(String::new()),
+            "r"  => self.registers(&args),
+            "m"  => self.dump(&args),
+            ">"  => self.edit(&args),
+            "d"  => self.disassemble(&args),
+            "b"  => self.breakpoint(rest),
+            "bc" => self.clear_breakpoint(&args),
+            "t"  => self.trace(&args),
+            "p"  => {
+                let reason = self.dbg.step_over(self.dbg.cpu().cycles() + GO_CYCLES);
+                Ok(self.stopped_within(reason, GO_CYCLES))
+            }
+            "g"  => self.go(&args),
+            "f"  => self.fill(&args),
+            "c"  => self.compare(&args),
+            "h"  => self.hunt(&args),
+            "q"  => {
+                self.quit = true;
+                Ok(String::new())
+            }
+            _ => error(format!("unknown command '{}'", cmd)),
+        }
+    }
+
+    fn registers(&mut self, args: &[&str]) -> Result<String> {
+        match args {
+            [] => Ok(self.status_line()),
+            [name, value] => {
+                let value = parse_num(value)?;
+                let reg = self.dbg.cpu_mut().reg_mut();
+                match name.to_ascii_lowercase().as_str() {
+                    "a"  => reg.a  = byte(value)?,
+                    "x"  => reg.x  = byte(value)?,
+                    "y"  => reg.y  = byte(value)?,
+                    "s"  => reg.s  = byte(value)?,
+                    "p"  => reg.p  = Status::from_bits(byte(value)?),
+                    "pc" => reg.pc = value,
+                    _ => return error(format!("unknown register '{}'", name)),
+                }
+                self.next_disasm = None;
+                Ok(self.status_line())
+            }
+            _ => error("usage: r [reg value]"),
+        }
+    }
+
+    fn dump(&mut self, args: &[&str]) -> Result<String> {
+        let (start, end) = self.range(args, self.next_dump, DUMP_LEN)?;
+        let mem = self.dbg.cpu().mem();
+        let mut out = String::new();
+
+        let mut addr = start as u32;
+        while addr <= end as u32 {
+            let line_end = (addr | 0x0F).min(end as u32);
+            let bytes: Vec<u8> = (addr..=line_end).map(|a| mem.peek(a as u16)).collect();
+            let hex: Vec<String> = bytes.iter().map(|b| format!("{:02X}", b)).collect();
+            let text: String = bytes
+                .iter()
+                .map(|&b| if b.is_ascii_graphic() { b as char } else { '.' })
+                .collect();
+            writeln!(out, "{:04X}  {:<47}  {}", addr, hex.join(" "), text).unwrap();
+            addr = line_end + 1;
+        }
+        self.next_dump = addr as u16;
+        Ok(out)
+    }
+
+    fn edit(&mut self, args: &[&str]) -> Result<String> {
+        let (addr, bytes) = match args {
+            [addr, bytes @ ..] if !bytes.is_empty() => (parse_num(addr)?, parse_bytes(bytes)?),
+            _ => return error("usage: > addr byte..."),
+        };
+        let mem = self.dbg.cpu_mut().mem_mut();
+        for (i, b) in bytes.iter().enumerate() {
+            mem.write_byte(addr.wrapping_add(i as u16), *b);
+        }
+        Ok(String::new())
+    }
+
+    fn disassemble(&mut self, args: &[&str]) -> Result<String> {
+        let pc = self.dbg.cpu().reg().pc;
+        let (mut addr, end) = match args {
+            []           => (self.next_disasm.unwrap_or(pc), None),
+            [start]      => (parse_num(start)?, None),
+            [start, end] => (parse_num(start)?, Some(parse_num(end)?)),
+            _ => return error("usage: d [start [end]]"),
+        };
+        let mem = self.dbg.cpu().mem();
+        let mut out = String::new();
+        for n in 0.. {
+            match end {
+                Some(end) if addr > end => break,
+                None if n == DISASM_LEN => break,
+                _ => (),
+            }
+            let inst = disassemble(mem, addr);
+            writeln!(out, "{}", inst).unwrap();
+            if inst.next_addr() < addr {
+                break;
+            }
+            addr = inst.next_addr();
+        }
+        self.next_disasm = Some(addr);
+        Ok(out)
+    }
+
+    fn breakpoint(&mut self, rest: &str) -> Result<String> {
+        if rest.is_empty() {
+            let mut out = String::new();
+            for (id, bp) in self.dbg.breakpoints() {
+                let on = match bp.on {
+                    BreakOn::Pc(addr)     => format!("${:04X}", addr),
+                    BreakOn::Opcode(op)   => format!("opcode ${:02X}", op),
+                    BreakOn::Mnemonic(m)  => crate::disasm::mnemonic(m),
+                };
+                let cond = bp.condition.as_ref().map_or(String::new(), |c| format!(" if {}", c.source()));
+                writeln!(out, "{}: {}{} (hits {})", id.index(), on, cond, bp.hits).unwrap();
+            }
+            return Ok(out);
+        }
+
+        let (addr, cond) = rest.split_once(char::is_whitespace).unwrap_or((rest, ""));
+        let mut bp = Breakpoint::new(BreakOn::Pc(parse_num(addr)?));
+        if !cond.trim().is_empty() {
+            bp = bp.with_condition(cond.trim()).map_err(|e| MonitorError(e.to_string()))?;
+        }
+        let id = self.dbg.add_breakpoint(bp);
+        Ok(format!("breakpoint {}\n", id.index()))
+    }
+
+    fn clear_breakpoint(&mut self, args: &[&str]) -> Result<String> {
+        let [n] = args else { return error("usage: bc n") };
+        let n: usize = n.parse().map_err(|_| MonitorError(format!("invalid number '{}'", n)))?;
+        let id = self.dbg.breakpoints().map(|(id, _)| id).find(|id| id.index() == n);
+        match id {
+            Some(id) => {
+                self.dbg.remove_breakpoint(id);
+                Ok(String::new())
+            }
+            None => error(format!("no breakpoint {}", n)),
+        }
+    }
+
+    fn trace(&mut self, args: &[&str]) -> Result<String> {
+        let count = match args {
+            []      => 1,
+            [count] => parse_num(count)?,
+            _ => return error("usage: t [count]"),
+        };
+        let mut out = String::new();
+        for _ in 0..count {
+            match self.dbg.step_in() {
+                StopReason::Step => writeln!(out, "{}", self.status_line().trim_end()).unwrap(),
+                reason => {
+                    out.push_str(&self.stopped(reason));
+                    return Ok(out);
+                }
+            }
+        }
+        self.next_disasm = None;
+        Ok(out)
+    }
+
+    fn go(&mut self, args: &[&str]) -> Result<String> {
+        let cycles = match args {
+            []     => GO_CYCLES,
+            [addr] => {
+                self.dbg.cpu_mut().reg_mut().pc = parse_num(addr)?;
+                GO_CYCLES
+            }
+            [addr, cycles] => {
+                self.dbg.cpu_mut().reg_mut().pc = parse_num(addr)?;
+                parse_cycles(cycles)?
+            }
+            _ => return error("usage: g [addr [cycles]]"),
+        };
+        let end = self.dbg.cpu().cycles() + cycles;
+        let reason = self.dbg.run_until_cycle(end);
+        Ok(self.stopped_within(reason, cycles))
+    }
+
+    fn fill(&mut self, args: &[&str]) -> Result<String> {
+        let (start, end, bytes) = match args {
+            [start, end, bytes @ ..] if !bytes.is_empty() => {
+                (parse_num(start)?, parse_num(end)?, parse_bytes(bytes)?)
+            }
+            _ => return error("usage: f start end byte..."),
+        };
+        let mem = self.dbg.cpu_mut().mem_mut();
+        for (addr, b) in (start..=end).zip(bytes.iter().cycle()) {
+            mem.write_byte(addr, *b);
+        }
+        Ok(String::new())
+    }
+
+    fn compare(&mut self, args: &[&str]) -> Result<String> {
+        let [start, end, dest] = args else { return error("usage: c start end dest") };
+        let (start, end, dest) = (parse_num(start)?, parse_num(end)?, parse_num(dest)?);
+        let mem = self.dbg.cpu().mem();
+        let mut out = String::new();
+        for addr in start..=end {
+            let other = dest.wrapping_add(addr - start);
+            let (a, b) = (mem.peek(addr), mem.peek(other));
+            if a != b {
+                writeln!(out, "{:04X} {:02X}  {:04X} {:02X}", addr, a, other, b).unwrap();
+            }
+        }
+        Ok(out)
+    }
+
+    fn hunt(&mut self, args: &[&str]) -> Result<String> {
+        let (start, end, bytes) = match args {
+            [start, end, bytes @ ..] if !bytes.is_empty() => {
+                (parse_num(start)?, parse_num(end)?, parse_bytes(bytes)?)
+            }
+            _ => return error("usage: h start end byte..."),
+        };
+        let mem = self.dbg.cpu().mem();
+        let found: Vec<String> = (start..=end)
+            .filter(|&addr| {
+                bytes.iter().enumerate().all(|(i, b)| mem.peek(addr.wrapping_add(i as u16)) == *b)
+            })
+            .map(|addr| format!("{:04X}", addr))
+            .collect();
+        Ok(if found.is_empty() { String::new() } else { found.join(" ") + "\n" })
+    }
+
+    fn stopped(&mut self, reason: StopReason) -> String {
+        self.next_disasm = None;
+        let head = match reason {
+            StopReason::Breakpoint(id) => format!("breakpoint {}\n", id.index()),
+            StopReason::Watchpoint(_, access) => format!("watchpoint ${:04X}\n", access.addr),
+            StopReason::Step | StopReason::Cycle => String::new(),
+        };
+        head + &self.status_line()
+    }
+
+    // Tell the limit if run stopped by it
+    fn stopped_within(&mut self, reason: StopReason, cycles: u64) -> String {
+        match reason {
+            StopReason::Cycle => format!("stopped after {:X} cycles\n", cycles) + &self.stopped(reason),
+            reason => self.stopped(reason),
+        }
+    }
+
+    // Registers and the next instruction
+    fn status_line(&self) -> String {
+        let cpu = self.dbg.cpu();
+        let reg = cpu.reg();
+        let p   = reg.p.as_bits();
+        let flags: String = "NV-BDIZC"
+            .chars()
+            .enumerate()
+            .map(|(i, c)| if p & (0x80 >> i) != 0 { c } else { c.to_ascii_lowercase() })
+            .collect();
+        format!(
+            "PC={:04X} A={:02X} X={:02X} Y={:02X} S={:02X} P={:02X} {}  {}\n",
+            reg.pc, reg.a, reg.x, reg.y, reg.s, p, flags,
+            disassemble(cpu.mem(), reg.pc).to_string()[6..].trim_start(),
+        )
+    }
+
+    // Inclusive range given by args, or `len` bytes from `default`
+    fn range(&self, args: &[&str], default: u16, len: u32) -> Result<(u16, u16)> {
+        let start = match args.first() {
+            Some(start) => parse_num(start)?,
+            None => default,
+        };
+        let end = match args.get(1) {
+            Some(end) => parse_num(end)?,
+            None => (start as u32 + len - 1).min(0xFFFF) as u16,
+        };
+        if end < start {
+            return error("end is before start");
+        }
+        Ok((start, end))
+    }
+}
+
+fn parse_num(text: &str) -> Result<u16> {
+    let hex = text.trim_start_matches('$');
+    u16::from_str_radix(hex, 16).or_else(|_| error(format!("invalid number '{}'", text)))
+}
+
+fn parse_cycles(text: &str) -> Result<u64> {
+    let hex = text.trim_start_matches('$');
+    u64::from_str_radix(hex, 16).or_else(|_| error(format!("invalid number '{}'", text)))
+}
+
+fn byte(value: u16) -> Result<u8> {
+    u8::try_from(value).or_else(|_| error(format!("${:X} is not a byte", value)))
+}
+
+fn parse_bytes(args: &[&str]) -> Result<Vec<u8>> {
+    args.iter().map(|arg| byte(parse_num(arg)?)).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::FlatMemory;
+
+    fn monitor() -> Monitor {
+        let mut cpu = Cpu::new(Box::new(FlatMemory::new()));
+        cpu.reg_mut().pc = 0x0200;
+        Monitor::new(cpu)
+    }
+
+    #[test]
+    fn test_memory_commands() {
+        let mut mon = monitor();
+
+        mon.execute("> 200 a9 42 48").unwrap();
+        mon.execute("f 300 307 de ad").unwrap();
+        assert_eq!(mon.execute("m 300 307").unwrap(), format!("0300  {:<47}  ........\n", "DE AD DE AD DE AD DE AD"));
+        assert_eq!(mon.execute("h 0 ffff de ad de").unwrap(), "0300 0302 0304\n");
+        assert_eq!(mon.execute("c 300 301 302").unwrap(), "");
+        assert_eq!(mon.execute("c 300 301 303").unwrap(), "0300 DE  0303 AD\n0301 AD  0304 DE\n");
+        assert_eq!(mon.execute("d 200 202").unwrap(), "0200  A9 42     LDA #$42\n0202  48        PHA\n");
+    }
+
+    #[test]
+    fn test_execution_commands() {
+        let mut mon = monitor();
+
+        // LDA #$42; PHA; JMP $0202
+        mon.execute(">200 a9 42 48 4c 02 02").unwrap();
+        assert_eq!(mon.execute("b 202 a == $42").unwrap(), "breakpoint 0\n");
+        assert!(mon.execute("g").unwrap().starts_with("breakpoint 0\nPC=0202 A=42"));
+        assert!(mon.execute("t").unwrap().starts_with("PC=0203"));
+        assert_eq!(mon.execute("b").unwrap(), "0: $0202 if a == $42 (hits 1)\n");
+        mon.execute("bc 0").unwrap();
+        assert_eq!(mon.execute("b").unwrap(), "");
+        // Loop without breakpoint stops by the limit
+        assert!(mon.execute("g 202 100").unwrap().starts_with("stopped after 100 cycles\nPC=0202"));
+        assert!(mon.execute("g 202 100 1").is_err());
+        // JSR $0213; JMP $0213 never returns, so `p` stops by the limit too
+        mon.execute(">210 20 13 02 4c 13 02").unwrap();
+        mon.execute("r pc 210").unwrap();
+        assert!(mon.execute("p").unwrap().starts_with("stopped after 100000 cycles\nPC=0213"));
+        assert!(mon.execute("r pc 1234").unwrap().starts_with("PC=1234"));
+        assert!(mon.execute("x").is_err());
+        mon.execute("q").unwrap();
+        assert!(mon.is_quit());
+    }
+}
@@ -0,0 +1,387 @@
+//! Provide movies of controller input in FM2 format of FCEUX
+//!
+//! FM2 is text: header lines of `key value`, then a line per frame like
+//! `|0|RLDUTSBA|........||`, which is commands, port 0 and port 1 (and port 2
+//! for Famicom expansion, not supported). Commands are bits: 1 is soft reset and
+//! 2 is power, done before the frame is run. Only standard controllers and text
+//! format are supported. Checksum of the rom is kept but not checked, since it
+//! needs MD5.
+//!
+//! To find desync against an earlier run, hashes of the whole state after each
+//! frame are kept in a sidecar text file, one hex hash per line (see
+//! `Movie::hashes` and `Movie::check`).
+
+use std::fmt;
+
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::controller::Buttons;
+use crate::harness::frames::frame_hash;
+use crate::nes::Nes;
+use crate::region::Region;
+
+const FM2_VERSION: &str = "3";
+
+/// Button characters of FM2, from bit 7 to bit 0 of `Buttons`
+const BUTTON_CHARS: [char; 8] = ['R', 'L', 'D', 'U', 'T', 'S', 'B', 'A'];
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum MovieError {
+    /// Binary input log is not supported
+    Binary,
+    UnsupportedVersion(String),
+    /// Device other than standard controller, like zapper or four score
+    UnsupportedDevice(String),
+    /// Input line that can not be parsed, 1-based
+    InvalidLine(usize),
+    /// Line of state hashes that can not be parsed, 1-based
+    InvalidHash(usize),
+}
+
+impl fmt::Display for MovieError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MovieError::Binary => write!(f, "binary FM2 is not supported"),
+            MovieError::UnsupportedVersion(version) => write!(f, "unsupported FM2 version {}", version),
+            MovieError::UnsupportedDevice(device) => write!(f, "unsupported input device: {}", device),
+            MovieError::InvalidLine(line) => write!(f, "line {}: invalid input", line),
+            MovieError::InvalidHash(line) => write!(f, "line {}: invalid state hash", line),
+        }
+    }
+}
+
+impl std::error::Error for MovieError {}
+
+/// Input of a frame
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct FrameInput {
+    pub commands: u8,
+    pub buttons: [Buttons; 2],
+}
+
+impl FrameInput {
+    /// Command to press the reset button
+    pub const RESET: u8 = 0x01;
+    /// Command to turn the power off and on
+    pub const POWER: u8 = 0x02;
+
+    pub fn new(buttons: [Buttons; 2]) -> FrameInput {
+        FrameInput { commands: 0, buttons }
+    }
+
+    /// Do commands and run a frame with the buttons
+    pub fn apply(&self, nes: &mut Nes) {
+        if self.commands & FrameInput::POWER != 0 {
+            nes.power();
+        }
+        if self.commands & FrameInput::RESET != 0 {
+            nes.reset();
+        }
+        nes.set_buttons(0, self.buttons[0]);
+        nes.set_buttons(1, self.buttons[1]);
+        nes.run_frame();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Movie {
+    // Header in the order of the file. Keys like `comment` may appear many times.
+    header: Vec<(String, String)>,
+    // Controller is connected to port 0 and 1
+    ports: [bool; 2],
+    pub frames: Vec<FrameInput>,
+}
+
+impl Movie {
+    /// Empty movie with controllers at both ports
+    pub fn new(rom_filename: &str, pal: bool) -> Movie {
+        let header = [
+            ("version", FM2_VERSION),
+            ("emuVersion", "0"),
+            ("rerecordCount", "0"),
+            ("palFlag", if pal { "1" } else { "0" }),
+            ("romFilename", rom_filename),
+            ("fourscore", "0"),
+            ("microphone", "0"),
+            ("port0", "1"),
+            ("port1", "1"),
+            ("port2", "0"),
+            ("FDS", "0"),
+            ("NewPPU", "0"),
+        ];
+        let header = header.iter().map(|(k, v)| (k.to_string(), v.to_string())).collect();
+        Movie { header, ports: [true, true], frames: Vec::new() }
+    }
+
+    pub fn parse(text: &str) -> Result<Movie, MovieError> {
+        let mut header = Vec::new();
+        let mut frames = Vec::new();
+        let mut ports  = [false, false];
+
+        for (n, line) in text.lines().enumerate() {
+            let line = line.trim_end();
+            if line.starts_with('|') {
+                frames.push(parse_frame(line, ports).ok_or(MovieError::InvalidLine(n + 1))?);
+                continue;
+            }
+            let Some((key, value)) = line.split_once(' ').or((!line.is_empty()).then_some((line, ""))) else {
+                continue;
+            };
+            match (key, value) {
+                ("version", _) if value != FM2_VERSION => return Err(MovieError::UnsupportedVersion(value.to_string())),
+                ("binary", "1") => return Err(MovieError::Binary),
+                ("fourscore", "1") => return Err(MovieError::UnsupportedDevice("four score".to_string())),
+                ("port0" | "port1", _) => {
+                    let port = (key == "port1") as usize;
+                    ports[port] = match value {
+                        "0" => false,
+                        "1" => true,
+                        _ => return Err(MovieError::UnsupportedDevice(format!("{} {}", key, value))),
+                    };
+                }
+                _ => (),
+            }
+            header.push((key.to_string(), value.to_string()));
+        }
+        Ok(Movie { header, ports, frames })
+    }
+
+    /// First value of the header
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.header.iter().find(|(k, _)| k == key).map(|(_, v)| v.as_str())
+    }
+
+    /// Set value of the header, replacing the first one
+    pub fn set(&mut self, key: &str, value: &str) {
+        match self.header.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_string(),
+            None => self.header.push((key.to_string(), value.to_string())),
+        }
+    }
+
+    pub fn pal(&self) -> bool {
+        self.get("palFlag") == Some("1")
+    }
+
+    /// Add the input and run the frame with it
+    pub fn record(&mut self, nes: &mut Nes, input: FrameInput) {
+        input.apply(nes);
+        self.frames.push(input);
+    }
+
+    /// Console to play the movie on: the rom powered up in the region of `palFlag`
+    pub fn console(&self, rom: &[u8]) -> Result<Nes, CartridgeError> {
+        let mut cart = Cartridge::from_bytes(rom)?;
+        cart.region = if self.pal() { Region::Pal } else { Region::Ntsc };
+        Nes::new(cart)
+    }
+
+    /// Run all frames on the console
+    pub fn play(&self, nes: &mut Nes) {
+        for input in &self.frames {
+            input.apply(nes);
+        }
+    }
+
+    /// Play the movie from power-up and return `state_hash` after each frame,
+    /// to be saved with the movie by `write_hashes`
+    pub fn hashes(&self, rom: &[u8]) -> Result<Vec<u64>, CartridgeError> {
+        let mut nes = self.console(rom)?;
+        Ok(self.frames.iter().map(|input| {
+            input.apply(&mut nes);
+            state_hash(&nes)
+        }).collect())
+    }
+
+    /// Play the movie from power-up and compare the state after each frame with
+    /// hashes of an earlier run. Return the first frame that differs, or None.
+    /// Frames after the last hash are not compared.
+    pub fn check(&self, rom: &[u8], hashes: &[u64]) -> Result<Option<usize>, CartridgeError> {
+        let mut nes = self.console(rom)?;
+        for (frame, (input, hash)) in self.frames.iter().zip(hashes).enumerate() {
+            input.apply(&mut nes);
+            if state_hash(&nes) != *hash {
+                return Ok(Some(frame));
+            }
+        }
+        Ok(None)
+    }
+
+    /// Play the movie twice from power-up in lockstep, and compare cpu and memory
+    /// after each frame. Return the first frame they differ, or None.
+    ///
+    /// This finds only nondeterminism inside the process. Use `check` to compare
+    /// with another run.
+    pub fn verify(&self, rom: &[u8]) -> Result<Option<Divergence>, CartridgeError> {
+        let mut runs = [self.console(rom)?, self.console(rom)?];
+        for (frame, input) in self.frames.iter().enumerate() {
+            for nes in &mut runs {
+                input.apply(nes);
+            }
+            let [a, b] = &runs;
+            let cpu    = a.cpu().reg() != b.cpu().reg() || a.cpu().cycles() != b.cpu().cycles();
+            let memory = a.cpu().save_state().ok() != b.cpu().save_state().ok();
+            if cpu || memory {
+                return Ok(Some(Divergence { frame, cpu, memory }));
+            }
+        }
+        Ok(None)
+    }
+}
+
+/// Frame where two runs of a movie differ
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Divergence {
+    /// Index of the frame, 0-based
+    pub frame: usize,
+    /// Registers or cycle counter differ
+    pub cpu: bool,
+    /// State of memory and devices differ
+    pub memory: bool,
+}
+
+impl fmt::Display for Movie {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (key, value) in &self.header {
+            writeln!(f, "{} {}", key, value)?;
+        }
+        for input in &self.frames {
+            write!(f, "|{}|", input.commands)?;
+            for (port, buttons) in input.buttons.iter().enumerate() {
+                if self.ports[port] {
+                    for (i, c) in BUTTON_CHARS.iter().enumerate() {
+                        let pressed = buttons.as_bits() & (0x80 >> i) != 0;
+                        write!(f, "{}", if pressed { *c } else { '.' })?;
+                    }
+                }
+                write!(f, "|")?;
+            }
+            writeln!(f, "|")?;
+        }
+        Ok(())
+    }
+}
+
+/// Hash of the whole state of the console: cpu, memory and devices
+pub fn state_hash(nes: &Nes) -> u64 {
+    frame_hash(&nes.cpu().save_state().expect("bus supports save state"))
+}
+
+/// Text of the state hashes file, one hash per line
+pub fn write_hashes(hashes: &[u64]) -> String {
+    hashes.iter().map(|hash| format!("{:016x}\n", hash)).collect()
+}
+
+pub fn parse_hashes(text: &str) -> Result<Vec<u64>, MovieError> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(n, line)| u64::from_str_radix(line.trim(), 16).map_err(|_| MovieError::InvalidHash(n + 1)))
+        .collect()
+}
+
+// Parse `|commands|port0|port1|port2|`
+fn parse_frame(line: &str, ports: [bool; 2]) -> Option<FrameInput> {
+    let mut fields = line.strip_prefix('|')?.split('|');
+    let commands   = fields.next()?.trim().parse().ok()?;
+    let mut buttons = [Buttons::NONE; 2];
+    for (port, connected) in ports.iter().enumerate() {
+        let field = fields.next()?;
+        if !connected {
+            continue;
+        }
+        if field.chars().count() != 8 {
+            return None;
+        }
+        // Any character other than '.' and ' ' is pressed
+        let bits = field.chars().enumerate()
+            .filter(|(_, c)| *c != '.' && *c != ' ')
+            .fold(0, |bits, (i, _)| bits | (0x80 >> i));
+        buttons[port] = Buttons::from_bits(bits);
+    }
+    Some(FrameInput { commands, buttons })
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::cartridge::test::nrom;
+    use crate::memory::Memory;
+
+    // NROM that copies controller 0 to $0300 + frame counter every NMI
+    fn rom() -> Vec<u8> {
+        let program = [
+            0x2C, 0x02, 0x20, 0x10, 0xFB, 0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x0A, 0xC0,
+        ];
+        // Strobe, shift 8 buttons into $01, store to $0300,X and increment $00
+        let nmi = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40,
+            0xA2, 0x08, 0xAD, 0x16, 0x40, 0x4A, 0x26, 0x01, 0xCA, 0xD0, 0xF7,
+            0xA6, 0x00, 0xA5, 0x01, 0x9D, 0x00, 0x03, 0xE6, 0x00, 0x40,
+        ];
+        nrom(&program, &nmi)
+    }
+
+    #[test]
+    fn test_parse() {
+        let text = "version 3\nromFilename test\nport0 1\nport1 0\ncomment author someone\n\
+                    |0|R......A|||\n|1|..D.T...|||\n";
+        let movie = Movie::parse(text).unwrap();
+        assert_eq!(movie.get("comment"), Some("author someone"));
+        assert_eq!(movie.frames.len(), 2);
+        assert_eq!(movie.frames[0].buttons[0], Buttons::RIGHT | Buttons::A);
+        assert_eq!(movie.frames[1].commands, FrameInput::RESET);
+        assert_eq!(movie.frames[1].buttons[0], Buttons::DOWN | Buttons::START);
+        assert_eq!(movie.to_string(), text);
+
+        assert_eq!(Movie::parse("binary 1"), Err(MovieError::Binary));
+        assert_eq!(Movie::parse("port0 1\n|0|RL|||"), Err(MovieError::InvalidLine(2)));
+    }
+
+    #[test]
+    fn test_record_and_play() {
+        let rom = rom();
+        let mut nes   = Nes::from_bytes(&rom).unwrap();
+        let mut movie = Movie::new("test.nes", false);
+        for frame in 0..10 {
+            let mut input = FrameInput::new([Buttons::from_bits(frame * 3), Buttons::NONE]);
+            if frame == 7 {
+                input.commands = FrameInput::POWER;
+            }
+            movie.record(&mut nes, input);
+        }
+
+        let movie = Movie::parse(&movie.to_string()).unwrap();
+        let mut replay = movie.console(&rom).unwrap();
+        movie.play(&mut replay);
+        assert_eq!(replay.cpu().reg(), nes.cpu().reg());
+        assert_eq!(replay.cpu().save_state().unwrap(), nes.cpu().save_state().unwrap());
+        // After power at frame 7, NMI runs once in frame 9 and reads its buttons
+        // (27) with A at bit 7
+        assert_eq!(replay.bus().read_byte(0x00), 1);
+        assert_eq!(replay.bus().read_byte(0x0300), 27u8.reverse_bits());
+
+        assert_eq!(movie.verify(&rom), Ok(None));
+    }
+
+    #[test]
+    fn test_check_hashes() {
+        let rom = rom();
+        let mut movie = Movie::new("test.nes", false);
+        movie.frames = (0..6).map(|frame| FrameInput::new([Buttons::from_bits(frame), Buttons::NONE])).collect();
+        let hashes = parse_hashes(&write_hashes(&movie.hashes(&rom).unwrap())).unwrap();
+        assert_eq!(hashes.len(), 6);
+        assert_eq!(movie.check(&rom, &hashes), Ok(None));
+
+        // Input changed at frame 3 is read by NMI of the same frame
+        movie.frames[3].buttons[0] = Buttons::START;
+        assert_eq!(movie.check(&rom, &hashes), Ok(Some(3)));
+        assert_eq!(parse_hashes("12\nzz\n"), Err(MovieError::InvalidHash(2)));
+    }
+
+    #[test]
+    fn test_pal_flag() {
+        let rom = rom();
+        assert_eq!(Movie::new("test.nes", true).console(&rom).unwrap().region(), Region::Pal);
+        assert_eq!(Movie::new("test.nes", false).console(&rom).unwrap().region(), Region::Ntsc);
+    }
+}
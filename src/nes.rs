@@ -0,0 +1,367 @@
+//! Provide the whole console: cpu and the bus with PPU and APU, clocked together
+//!
+//! PPU and APU are caught up to the cpu after each instruction, in master clock
+//! cycles of the region. The cycle counter of the cpu is the clock of the whole
+//! system: master time is `cpu.cycles() * Region::cpu_divider`, so it follows the
+//! region of the cpu. NMI output of the PPU is connected to the NMI line of the
+//! cpu, and IRQ output of the APU to the IRQ line, after each catch-up.
+
+use std::io;
+use std::path::Path;
+
+use crate::apu::Apu;
+use crate::bus::Bus;
+use crate::cartridge::{Cartridge, CartridgeError};
+use crate::controller::Buttons;
+use crate::cpu::{Cpu, CpuHooks, NoHooks, RunStop};
+use crate::png;
+use crate::ppu::{Ppu, HEIGHT, WIDTH};
+use crate::region::Region;
+
+/// Console whose cpu calls hooks `H`, like `ApuRecorder`
+pub struct Nes<H = NoHooks> {
+    cpu: Cpu<Bus, H>,
+    // Master time the PPU was run to
+    ppu_time: u64,
+    // Cpu cycles the APU was run to
+    apu_cycles: u64,
+    // Load count of the bus the times above follow
+    loads: u64,
+}
+
+impl Nes {
+    /// Build console with the cartridge and do power-up reset
+    pub fn new(cart: Cartridge) -> Result<Nes, CartridgeError> {
+        Nes::with_hooks(cart, NoHooks)
+    }
+
+    /// Build console from iNES image
+    pub fn from_bytes(rom: &[u8]) -> Result<Nes, CartridgeError> {
+        Nes::new(Cartridge::from_bytes(rom)?)
+    }
+}
+
+impl<H: CpuHooks> Nes<H> {
+    /// Build console with the cartridge and hooks called by the cpu
+    pub fn with_hooks(cart: Cartridge, hooks: H) -> Result<Nes<H>, CartridgeError> {
+        let region  = cart.region;
+        let mut cpu = Cpu::with_hooks(Bus::new(cart)?, hooks);
+        cpu.set_region(region);
+        cpu.reg_mut().s = 0;
+        cpu.reset();
+        Ok(Nes { cpu, ppu_time: 0, apu_cycles: 0, loads: 0 })
+    }
+
+    pub fn cpu(&self) -> &Cpu<Bus, H> {
+        &self.cpu
+    }
+
+    pub fn cpu_mut(&mut self) -> &mut Cpu<Bus, H> {
+        &mut self.cpu
+    }
+
+    pub fn hooks(&self) -> &H {
+        self.cpu.hooks()
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut H {
+        self.cpu.hooks_mut()
+    }
+
+    pub fn bus(&self) -> &Bus {
+        self.cpu.mem()
+    }
+
+    pub fn ppu(&self) -> &Ppu {
+        self.cpu.mem().ppu()
+    }
+
+    pub fn apu(&self) -> &Apu {
+        self.cpu.mem().apu()
+    }
+
+    pub fn apu_mut(&mut self) -> &mut Apu {
+        self.cpu.mem_mut().apu_mut()
+    }
+
+    pub fn region(&self) -> Region {
+        self.cpu.region()
+    }
+
+    /// Set buttons of the controller at port 0 or 1, usually before each frame
+    pub fn set_buttons(&mut self, port: usize, buttons: Buttons) {
+        self.cpu.mem_mut().joypad_mut(port).set_buttons(buttons);
+    }
+
+    /// Master time of the cpu
+    pub fn master_clock(&self) -> u64 {
+        self.cpu.cycles() * self.region().cpu_divider()
+    }
+
+    /// Press the reset button
+    pub fn reset(&mut self) {
+        self.cpu.mem_mut().ppu_mut().reset();
+        // Channels are silenced and frame counter is restarted
+        self.apu_mut().write_register(0x4015, 0x00);
+        self.apu_mut().write_register(0x4017, 0x00);
+        self.cpu.reset();
+        self.sync();
+    }
+
+    /// Execute one instruction and run PPU to the same time
+    pub fn step(&mut self) {
+        self.follow_load();
+        self.cpu.step();
+        let stall = self.cpu.mem_mut().take_stall();
+        if stall > 0 {
+            // DMA waits one more cycle to start on even cycle
+            let align = self.cpu.cycles() & 1;
+            self.cpu.stall(stall + align);
+        }
+        self.sync();
+    }
+
+    /// Run until PPU completes a frame, returning `RunStop::Condition`. Stop with
+    /// `RunStop::Timeout` if no frame is completed in the cycles of two frames.
+    pub fn run_frame(&mut self) -> RunStop {
+        let frame = self.ppu().frame_count();
+        let limit = 2 * self.region().frame_master_cycles();
+        let end   = self.master_clock() + limit;
+        while self.ppu().frame_count() == frame {
+            if self.master_clock() >= end {
+                return RunStop::Timeout;
+            }
+            self.step();
+        }
+        RunStop::Condition
+    }
+
+    /// Last completed frame as RGB
+    pub fn frame(&self) -> &[u8] {
+        self.ppu().frame()
+    }
+
+    /// Save last completed frame as PNG
+    pub fn save_png<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        png::write_rgb(path, WIDTH as u32, HEIGHT as u32, self.frame())
+    }
+
+    // Take the times of PPU and APU from the cpu if a state was loaded. They were
+    // synchronized to the cpu when the state was saved.
+    fn follow_load(&mut self) {
+        let loads = self.bus().load_count();
+        if loads != self.loads {
+            let divider     = self.region().ppu_divider();
+            self.ppu_time   = self.master_clock() / divider * divider;
+            self.apu_cycles = self.cpu.cycles();
+            self.loads      = loads;
+        }
+    }
+
+    fn sync(&mut self) {
+        self.follow_load();
+        let now     = self.master_clock();
+        let divider = self.region().ppu_divider();
+        let ppu     = self.cpu.mem_mut().ppu_mut();
+        while self.ppu_time + divider <= now {
+            ppu.step();
+            self.ppu_time += divider;
+        }
+        let nmi = ppu.nmi_line();
+        self.cpu.set_nmi(nmi);
+
+        let cycles = self.cpu.cycles();
+        self.cpu.mem_mut().run_apu(cycles - self.apu_cycles);
+        self.apu_cycles = cycles;
+        let irq = self.apu().irq_line();
+        self.cpu.set_irq(irq);
+    }
+}
+
+impl<H: CpuHooks + Default> Nes<H> {
+    /// Turn the power off and on. Ram is cleared and the cartridge is inserted again.
+    /// Hooks are kept.
+    pub fn power(&mut self) {
+        let cart  = self.bus().cartridge().clone();
+        let hooks = std::mem::take(self.hooks_mut());
+        *self = Nes::with_hooks(cart, hooks).expect("cartridge is already accepted by the bus");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu::ApuWrite;
+    use crate::apu_log::ApuRecorder;
+    use crate::cartridge::test::nrom;
+    use crate::memory::Memory;
+    use crate::state::StateError;
+
+    #[test]
+    fn test_nmi() {
+        // Wait vblank, enable NMI and loop. NMI handler counts frames at $00.
+        let program = [
+            0x2C, 0x02, 0x20, 0x10, 0xFB, 0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x0A, 0xC0,
+        ];
+        let mut nes = Nes::from_bytes(&nrom(&program, &[0xE6, 0x00, 0x40])).unwrap();
+        for _ in 0..5 {
+            assert_eq!(nes.run_frame(), RunStop::Condition);
+        }
+        assert_eq!(nes.ppu().frame_count(), 5);
+        assert_eq!(nes.bus().ppu().scanline(), 241);
+        // First frame is spent waiting vblank, and the handler runs after the last frame start
+        assert!((3..=4).contains(&nes.cpu().mem().read_byte(0x00)));
+
+        let frame = nes.master_clock() / Region::Ntsc.frame_master_cycles();
+        assert_eq!(frame, 4);
+    }
+
+    #[test]
+    fn test_apu_irq() {
+        // CLI and loop. IRQ handler at $C200 acknowledges frame interrupt and counts at $01.
+        let mut rom = nrom(&[0x58, 0x4C, 0x01, 0xC0], &[0x40]);
+        rom[16 + 0x200..16 + 0x206].copy_from_slice(&[0xAD, 0x15, 0x40, 0xE6, 0x01, 0x40]);
+        rom[16 + 0x3FFE..].copy_from_slice(&[0x00, 0xC2]);
+        let mut nes = Nes::from_bytes(&rom).unwrap();
+        for _ in 0..10 {
+            nes.run_frame();
+        }
+
+        // Frame interrupt is raised every 29830 cycles (about 60Hz)
+        let expected = nes.cpu().cycles() / 29830;
+        assert_eq!(nes.bus().read_byte(0x01) as u64, expected);
+        assert!(!nes.cpu().irq_line());
+        assert!(!nes.apu_mut().take_samples().is_empty());
+    }
+
+    #[test]
+    fn test_controller() {
+        // Strobe, then read two buttons of port 0 to $00 and $01
+        let program = [
+            0xA9, 0x01, 0x8D, 0x16, 0x40, 0xA9, 0x00, 0x8D, 0x16, 0x40,
+            0xAD, 0x16, 0x40, 0x85, 0x00, 0xAD, 0x16, 0x40, 0x85, 0x01, 0x4C, 0x14, 0xC0,
+        ];
+        let mut nes = Nes::from_bytes(&nrom(&program, &[0x40])).unwrap();
+        nes.set_buttons(0, Buttons::A | Buttons::SELECT);
+        nes.run_frame();
+        // Upper bits are $40 left on the bus by the address
+        assert_eq!((nes.bus().read_byte(0x00), nes.bus().read_byte(0x01)), (0x41, 0x40));
+
+        // DMC fetch while reading the controller loses B
+        let bus = nes.cpu_mut().mem_mut();
+        bus.write_byte(0x4016, 1);
+        bus.write_byte(0x4016, 0);
+        assert_eq!(bus.read_byte(0x4016) & 0x01, 1);
+        bus.write_byte(0x4015, 0x10);
+        bus.run_apu(1);
+        assert_eq!(bus.read_byte(0x4016) & 0x01, 1);
+    }
+
+    #[test]
+    fn test_hooks() {
+        // LDA #$0F; STA $4015; loop
+        let cart    = Cartridge::from_bytes(&nrom(&[0xA9, 0x0F, 0x8D, 0x15, 0x40, 0x4C, 0x05, 0xC0], &[0x40])).unwrap();
+        let mut nes = Nes::with_hooks(cart, ApuRecorder::new()).unwrap();
+        let start   = nes.cpu().cycles();
+        nes.run_frame();
+        assert_eq!(nes.hooks().writes(), [ApuWrite { cycle: start + 5, addr: 0x4015, value: 0x0F }]);
+
+        // Power cycle keeps the hooks
+        nes.power();
+        nes.step();
+        nes.step();
+        assert_eq!(nes.hooks_mut().take_writes().len(), 2);
+    }
+
+    #[test]
+    fn test_peek() {
+        let mut nes = Nes::from_bytes(&nrom(&[0x4C, 0x00, 0xC0], &[0x40])).unwrap();
+        nes.set_buttons(0, Buttons::A);
+        let bus = nes.cpu_mut().mem_mut();
+        for (addr, value) in [(0x2006, 0x24), (0x2006, 0x00), (0x2007, 0x11), (0x2007, 0x22)] {
+            bus.write_byte(addr, value);
+        }
+        bus.write_byte(0x2006, 0x24);
+        bus.write_byte(0x2006, 0x00);
+        bus.write_byte(0x4016, 1);
+        bus.write_byte(0x4016, 0);
+
+        // Peek of the registers does not move the VRAM address or the controller
+        for addr in [0x2002, 0x2007, 0x2007, 0x4015, 0x4016, 0x4016] {
+            bus.peek(addr);
+        }
+        assert_eq!(bus.peek(0x4016) & 0x01, 1);
+        assert_eq!(bus.read_byte(0x4016) & 0x01, 1);
+        bus.read_byte(0x2007);
+        assert_eq!(bus.peek(0x2007), 0x11);
+        assert_eq!((bus.read_byte(0x2007), bus.read_byte(0x2007)), (0x11, 0x22));
+    }
+
+    #[test]
+    fn test_history() {
+        // Write $11, $22 to VRAM $2400 and $55 to $10
+        let program = [
+            0xA9, 0x24, 0x8D, 0x06, 0x20, 0xA9, 0x00, 0x8D, 0x06, 0x20,
+            0xA9, 0x11, 0x8D, 0x07, 0x20, 0xA9, 0x22, 0x8D, 0x07, 0x20,
+            0xA9, 0x55, 0x85, 0x10, 0x4C, 0x18, 0xC0,
+        ];
+        let mut nes = Nes::from_bytes(&nrom(&program, &[0x40])).unwrap();
+        nes.cpu_mut().set_history(100);
+        for _ in 0..10 {
+            nes.cpu_mut().step();
+        }
+        // Recording does not read the registers
+        let ppu = nes.ppu();
+        assert_eq!((ppu.read_vram(0x2400), ppu.read_vram(0x2401)), (0x11, 0x22));
+
+        // Only the RAM is restored
+        while nes.cpu_mut().step_back() {}
+        assert_eq!(nes.bus().peek(0x10), 0x00);
+        assert_eq!(nes.ppu().read_vram(0x2401), 0x22);
+        assert_eq!(nes.cpu().reg().pc, 0xC000);
+    }
+
+    #[test]
+    fn test_load_older_state() {
+        // Count frames at $00 by NMI, as in test_nmi
+        let program = [
+            0x2C, 0x02, 0x20, 0x10, 0xFB, 0xA9, 0x80, 0x8D, 0x00, 0x20, 0x4C, 0x0A, 0xC0,
+        ];
+        let mut nes = Nes::from_bytes(&nrom(&program, &[0xE6, 0x00, 0x40])).unwrap();
+        nes.run_frame();
+        nes.step();
+        let state = nes.cpu().save_state().unwrap();
+        let run   = |nes: &mut Nes| {
+            for _ in 0..3 {
+                nes.run_frame();
+            }
+            (nes.cpu().cycles(), nes.ppu().scanline(), nes.bus().peek(0x00), nes.apu_cycles)
+        };
+        let expected = run(&mut nes);
+
+        // Time goes back with the state, and the run is the same
+        nes.cpu_mut().load_state(&state).unwrap();
+        nes.step();
+        nes.cpu_mut().load_state(&state).unwrap();
+        assert_eq!(run(&mut nes), expected);
+    }
+
+    #[test]
+    fn test_load_corrupt_state() {
+        let mut nes = Nes::from_bytes(&nrom(&[0x4C, 0x00, 0xC0], &[0x40])).unwrap();
+        nes.cpu_mut().set_region(Region::Pal);
+        let state = nes.cpu().save_state().unwrap();
+
+        nes.cpu_mut().mem_mut().write_byte(0x10, 0x55);
+        nes.cpu_mut().set_region(Region::Ntsc);
+        // APU section at the end is cut, after RAM and PPU are read
+        let error = nes.cpu_mut().load_state(&state[..state.len() - 4]);
+        assert_eq!(error, Err(StateError::UnexpectedEnd));
+        assert_eq!(nes.bus().peek(0x10), 0x55);
+        assert_eq!(nes.cpu().region(), Region::Ntsc);
+
+        nes.cpu_mut().load_state(&state).unwrap();
+        assert_eq!(nes.bus().peek(0x10), 0x00);
+        assert_eq!(nes.cpu().region(), Region::Pal);
+    }
+}
@@ -0,0 +1,184 @@
+//! Provide loader of NSF and NSFe music files
+//!
+//! NSF is 6502 code with INIT and PLAY routines. `Nsf::from_bytes` reads NSF and
+//! NSFe (chunked format with metadata) into the same struct, and `NsfPlayer`
+//! runs it on the cpu core. Expansion audio chips are not emulated.
+
+mod nsfe;
+mod player;
+
+use std::fmt;
+
+use crate::region::Region;
+
+pub use player::{NsfMemory, NsfPlayer};
+
+const HEADER_SIZE: usize = 0x80;
+
+/// Default PLAY period in microseconds
+const NTSC_SPEED: u16 = 16_639;
+const PAL_SPEED:  u16 = 19_997;
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum NsfError {
+    /// Image does not start with "NESM\x1A" or "NSFE"
+    InvalidHeader,
+    /// Image is shorter than the size written in it
+    Truncated,
+    /// Required NSFe chunk does not exist
+    MissingChunk([u8; 4]),
+    /// NSFe chunk that must be understood is not supported
+    UnknownChunk([u8; 4]),
+}
+
+impl fmt::Display for NsfError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NsfError::InvalidHeader => write!(f, "invalid NSF header"),
+            NsfError::Truncated     => write!(f, "NSF image is truncated"),
+            NsfError::MissingChunk(id) => write!(f, "missing NSFe chunk '{}'", String::from_utf8_lossy(id)),
+            NsfError::UnknownChunk(id) => write!(f, "unknown NSFe chunk '{}'", String::from_utf8_lossy(id)),
+        }
+    }
+}
+
+impl std::error::Error for NsfError {}
+
+/// Metadata of a track, from NSFe
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Track {
+    pub label: Option<String>,
+    /// Length in milliseconds
+    pub time: Option<u32>,
+    /// Fade-out in milliseconds
+    pub fade: Option<u32>,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Nsf {
+    pub songs: u8,
+    /// First song to play, 0-based
+    pub start_song: u8,
+    pub load_addr: u16,
+    pub init_addr: u16,
+    pub play_addr: u16,
+    pub name: String,
+    pub artist: String,
+    pub copyright: String,
+    /// Ripper, from NSFe
+    pub ripper: String,
+    /// PLAY period of each region in microseconds
+    pub ntsc_speed: u16,
+    pub pal_speed: u16,
+    pub dendy_speed: Option<u16>,
+    /// Initial banks of $8000-$FFFF. None if the file does not use bankswitching.
+    pub banks: Option<[u8; 8]>,
+    pub pal: bool,
+    pub dual: bool,
+    /// Bits of expansion audio chips (not emulated)
+    pub chips: u8,
+    pub data: Vec<u8>,
+    /// Per-track metadata from NSFe. Empty for NSF.
+    pub tracks: Vec<Track>,
+    /// Play order from NSFe
+    pub playlist: Vec<u8>,
+}
+
+impl Nsf {
+    /// Parse NSF or NSFe image
+    pub fn from_bytes(bytes: &[u8]) -> Result<Nsf, NsfError> {
+        if bytes.starts_with(b"NSFE") {
+            return nsfe::parse(bytes);
+        }
+        if bytes.len() < HEADER_SIZE || &bytes[0..5] != b"NESM\x1A" {
+            return Err(NsfError::InvalidHeader);
+        }
+        let word = |i: usize| u16::from_le_bytes([bytes[i], bytes[i + 1]]);
+
+        let mut data = &bytes[HEADER_SIZE..];
+        // NSF2 may have metadata after the program, whose length is given
+        let length = u32::from_le_bytes([bytes[0x7D], bytes[0x7E], bytes[0x7F], 0]) as usize;
+        if bytes[5] >= 2 && length > 0 {
+            data = data.get(..length).ok_or(NsfError::Truncated)?;
+        }
+
+        let banks: [u8; 8] = bytes[0x70..0x78].try_into().unwrap();
+        Ok(Nsf {
+            songs: bytes[6],
+            start_song: bytes[7].saturating_sub(1),
+            load_addr: word(0x08),
+            init_addr: word(0x0A),
+            play_addr: word(0x0C),
+            name: text(&bytes[0x0E..0x2E]),
+            artist: text(&bytes[0x2E..0x4E]),
+            copyright: text(&bytes[0x4E..0x6E]),
+            ripper: String::new(),
+            ntsc_speed: speed(word(0x6E), NTSC_SPEED),
+            pal_speed: speed(word(0x78), PAL_SPEED),
+            dendy_speed: None,
+            banks: banks.iter().any(|&b| b != 0).then_some(banks),
+            pal: bytes[0x7A] & 0x01 != 0,
+            dual: bytes[0x7A] & 0x02 != 0,
+            chips: bytes[0x7B],
+            data: data.to_vec(),
+            tracks: Vec::new(),
+            playlist: Vec::new(),
+        })
+    }
+
+    /// Region to play in when not specified: NTSC unless the file is PAL only
+    pub fn region(&self) -> Region {
+        if self.pal && !self.dual { Region::Pal } else { Region::Ntsc }
+    }
+
+    /// PLAY period of given region in microseconds
+    pub fn speed(&self, region: Region) -> u16 {
+        match region {
+            Region::Ntsc  => self.ntsc_speed,
+            Region::Pal   => self.pal_speed,
+            Region::Dendy => self.dendy_speed.unwrap_or(self.pal_speed),
+        }
+    }
+}
+
+fn speed(value: u16, default: u16) -> u16 {
+    if value == 0 { default } else { value }
+}
+
+// Zero terminated string
+fn text(bytes: &[u8]) -> String {
+    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..end]).into_owned()
+}
+
+#[cfg(test)]
+pub(crate) mod test {
+    use super::*;
+
+    /// NSF image with given program loaded at $8000, INIT at $8000 and PLAY at $8010
+    pub(crate) fn nsf(program: &[u8], banks: [u8; 8]) -> Vec<u8> {
+        let mut nsf = vec![0; HEADER_SIZE];
+        nsf[0..8].copy_from_slice(&[b'N', b'E', b'S', b'M', 0x1A, 1, 3, 2]);
+        nsf[0x08..0x0E].copy_from_slice(&[0x00, 0x80, 0x00, 0x80, 0x10, 0x80]);
+        nsf[0x0E..0x13].copy_from_slice(b"Title");
+        nsf[0x6E..0x70].copy_from_slice(&NTSC_SPEED.to_le_bytes());
+        nsf[0x70..0x78].copy_from_slice(&banks);
+        nsf.extend_from_slice(program);
+        nsf
+    }
+
+    #[test]
+    fn test_parse() {
+        let nsf = Nsf::from_bytes(&nsf(&[0x60], [0; 8])).unwrap();
+        assert_eq!(nsf.songs, 3);
+        assert_eq!(nsf.start_song, 1);
+        assert_eq!(nsf.play_addr, 0x8010);
+        assert_eq!(nsf.name, "Title");
+        assert_eq!(nsf.pal_speed, PAL_SPEED);
+        assert_eq!(nsf.banks, None);
+        assert_eq!(nsf.data, [0x60]);
+        assert_eq!(nsf.region(), Region::Ntsc);
+
+        assert_eq!(Nsf::from_bytes(b"NESM").err(), Some(NsfError::InvalidHeader));
+    }
+}
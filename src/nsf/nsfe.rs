@@ -0,0 +1,165 @@
+//! NSFe: "NSFE" followed by chunks of [length u32][id 4 bytes][data]
+//!
+//! Chunk whose id starts with upper case letter must be understood by the player,
+//! others are metadata and can be skipped.
+
+use super::{speed, text, Nsf, NsfError, Track, NTSC_SPEED, PAL_SPEED};
+
+pub(super) fn parse(bytes: &[u8]) -> Result<Nsf, NsfError> {
+    let mut nsf = Nsf {
+        songs: 1,
+        start_song: 0,
+        load_addr: 0,
+        init_addr: 0,
+        play_addr: 0,
+        name: String::new(),
+        artist: String::new(),
+        copyright: String::new(),
+        ripper: String::new(),
+        ntsc_speed: NTSC_SPEED,
+        pal_speed: PAL_SPEED,
+        dendy_speed: None,
+        banks: None,
+        pal: false,
+        dual: false,
+        chips: 0,
+        data: Vec::new(),
+        tracks: Vec::new(),
+        playlist: Vec::new(),
+    };
+    let mut info  = false;
+    let mut data  = false;
+    let mut rest  = &bytes[4..];
+
+    loop {
+        if rest.len() < 8 {
+            return Err(NsfError::Truncated);
+        }
+        let len  = u32::from_le_bytes([rest[0], rest[1], rest[2], rest[3]]) as usize;
+        let id: [u8; 4] = rest[4..8].try_into().unwrap();
+        let body = rest.get(8..8 + len).ok_or(NsfError::Truncated)?;
+        rest = &rest[8 + len..];
+
+        match &id {
+            b"INFO" => {
+                if body.len() < 9 {
+                    return Err(NsfError::Truncated);
+                }
+                let word = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+                nsf.load_addr  = word(0);
+                nsf.init_addr  = word(2);
+                nsf.play_addr  = word(4);
+                nsf.pal        = body[6] & 0x01 != 0;
+                nsf.dual       = body[6] & 0x02 != 0;
+                nsf.chips      = body[7];
+                nsf.songs      = body.get(8).copied().unwrap_or(1);
+                nsf.start_song = body.get(9).copied().unwrap_or(0);
+                info = true;
+            }
+            b"DATA" => {
+                nsf.data = body.to_vec();
+                data = true;
+            }
+            b"BANK" => {
+                let mut banks = [0; 8];
+                let n = body.len().min(8);
+                banks[..n].copy_from_slice(&body[..n]);
+                nsf.banks = Some(banks);
+            }
+            b"RATE" => {
+                let word = |i: usize| body.get(i..i + 2).map(|b| u16::from_le_bytes([b[0], b[1]]));
+                nsf.ntsc_speed  = speed(word(0).unwrap_or(0), NTSC_SPEED);
+                nsf.pal_speed   = speed(word(2).unwrap_or(0), PAL_SPEED);
+                nsf.dendy_speed = word(4).filter(|&s| s != 0);
+            }
+            b"NEND" => break,
+            b"auth" => {
+                let mut fields = strings(body).into_iter();
+                nsf.name      = fields.next().unwrap_or_default();
+                nsf.artist    = fields.next().unwrap_or_default();
+                nsf.copyright = fields.next().unwrap_or_default();
+                nsf.ripper    = fields.next().unwrap_or_default();
+            }
+            b"tlbl" => {
+                for (track, label) in tracks(&mut nsf.tracks, nsf.songs).iter_mut().zip(strings(body)) {
+                    track.label = Some(label);
+                }
+            }
+            b"time" | b"fade" => {
+                let values = body.chunks_exact(4).map(|b| i32::from_le_bytes([b[0], b[1], b[2], b[3]]));
+                for (track, ms) in tracks(&mut nsf.tracks, nsf.songs).iter_mut().zip(values) {
+                    // Negative value means default
+                    let ms = u32::try_from(ms).ok();
+                    if &id == b"time" { track.time = ms } else { track.fade = ms }
+                }
+            }
+            b"plst" => nsf.playlist = body.to_vec(),
+            _ if id[0].is_ascii_uppercase() => return Err(NsfError::UnknownChunk(id)),
+            _ => (),
+        }
+    }
+
+    if !info {
+        return Err(NsfError::MissingChunk(*b"INFO"));
+    }
+    if !data {
+        return Err(NsfError::MissingChunk(*b"DATA"));
+    }
+    Ok(nsf)
+}
+
+// Metadata of all songs, allocated on first use
+fn tracks(tracks: &mut Vec<Track>, songs: u8) -> &mut Vec<Track> {
+    if tracks.is_empty() {
+        tracks.resize(songs as usize, Track::default());
+    }
+    tracks
+}
+
+// List of zero terminated strings
+fn strings(body: &[u8]) -> Vec<String> {
+    body.split_inclusive(|&b| b == 0).map(text).collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn chunk(id: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut chunk = (body.len() as u32).to_le_bytes().to_vec();
+        chunk.extend_from_slice(id);
+        chunk.extend_from_slice(body);
+        chunk
+    }
+
+    #[test]
+    fn test_parse() {
+        let mut nsfe = b"NSFE".to_vec();
+        nsfe.extend(chunk(b"INFO", &[0x00, 0x80, 0x00, 0x80, 0x10, 0x80, 0x03, 0x00, 0x02, 0x01]));
+        nsfe.extend(chunk(b"BANK", &[0, 1]));
+        nsfe.extend(chunk(b"DATA", &[0x60]));
+        nsfe.extend(chunk(b"auth", b"Game\0Artist\0\0Ripper\0"));
+        nsfe.extend(chunk(b"tlbl", b"Intro\0Boss\0"));
+        nsfe.extend(chunk(b"time", &[0x10, 0x27, 0, 0, 0xFF, 0xFF, 0xFF, 0xFF]));
+        nsfe.extend(chunk(b"xtra", &[1, 2, 3]));
+        nsfe.extend(chunk(b"NEND", &[]));
+
+        let nsf = Nsf::from_bytes(&nsfe).unwrap();
+        assert_eq!(nsf.play_addr, 0x8010);
+        assert!(nsf.pal && nsf.dual);
+        assert_eq!((nsf.songs, nsf.start_song), (2, 1));
+        assert_eq!(nsf.banks, Some([0, 1, 0, 0, 0, 0, 0, 0]));
+        assert_eq!(nsf.data, [0x60]);
+        assert_eq!(nsf.name, "Game");
+        assert_eq!(nsf.copyright, "");
+        assert_eq!(nsf.ripper, "Ripper");
+        assert_eq!(nsf.tracks[1].label.as_deref(), Some("Boss"));
+        assert_eq!(nsf.tracks[0].time, Some(10_000));
+        assert_eq!(nsf.tracks[1].time, None);
+
+        let len = nsfe.len();
+        nsfe[len - 4..].copy_from_slice(b"ZZZZ");
+        assert_eq!(Nsf::from_bytes(&nsfe), Err(NsfError::UnknownChunk(*b"ZZZZ")));
+        assert_eq!(Nsf::from_bytes(b"NSFE"), Err(NsfError::Truncated));
+    }
+}
@@ -0,0 +1,424 @@
+//! Run NSF on the cpu core
+//!
+//! INIT and PLAY are called through a small driver at $5FF0: `JSR routine` then
+//! an idle loop at $5FF3. A routine has returned when pc reaches the idle loop.
+//! Between PLAY calls the cpu is stalled instead of running the loop.
+//! Before INIT, the driver from $5FD6 writes the APU registers, so that the
+//! writes are seen by cpu hooks like the ones of the song.
+
+use super::Nsf;
+use crate::apu::Apu;
+use crate::cpu::{Cpu, CpuHooks, NoHooks};
+use crate::memory::Memory;
+use crate::region::Region;
+use crate::register::Status;
+
+const RAM_SIZE:  usize = 0x0800;
+const WRAM_SIZE: usize = 0x2000;
+const BANK_SIZE: usize = 0x1000;
+
+/// Address of the driver for INIT: reset APU registers and fall to `DRIVER`
+const INIT_DRIVER: u16 = 0x5FD6;
+/// Address of the driver: JSR to the called routine
+const DRIVER: u16 = 0x5FF0;
+/// Address the driver loops after the routine returns
+const IDLE:   u16 = 0x5FF3;
+
+/// Code at `INIT_DRIVER`. A and X given to INIT are saved on the stack.
+const INIT_CODE: [u8; (DRIVER - INIT_DRIVER) as usize] = [
+    0x48, 0x8A, 0x48,       // PHA; TXA; PHA
+    0xA9, 0x00, 0xA2, 0x13, // LDA #$00; LDX #$13
+    0x9D, 0x00, 0x40,       // STA $4000,X
+    0xCA, 0x10, 0xFA,       // DEX; BPL
+    0xA9, 0x0F, 0x8D, 0x15, 0x40, // LDA #$0F; STA $4015
+    0xA9, 0x40, 0x8D, 0x17, 0x40, // LDA #$40; STA $4017
+    0x68, 0xAA, 0x68,       // PLA; TAX; PLA
+];
+
+/// Memory map of NSF player: ram, APU, bank registers at $5FF8-$5FFF,
+/// ram at $6000-$7FFF and 4KiB banks at $8000-$FFFF
+pub struct NsfMemory {
+    ram: [u8; RAM_SIZE],
+    wram: [u8; WRAM_SIZE],
+    rom: Vec<u8>,
+    initial_banks: [u8; 8],
+    banks: [u8; 8],
+    bankswitched: bool,
+    bank_version: u64,
+    // Routine called by the driver
+    call: u16,
+    apu: Option<Apu>,
+    stall: u64,
+}
+
+impl NsfMemory {
+    /// Map data of the NSF. Without APU, register writes are ignored.
+    pub fn new(nsf: &Nsf, apu: Option<Apu>) -> NsfMemory {
+        let (rom, initial_banks) = match nsf.banks {
+            // Data is padded so that load address is at the same offset in its bank
+            Some(banks) => {
+                let mut rom = vec![0; nsf.load_addr as usize & (BANK_SIZE - 1)];
+                rom.extend_from_slice(&nsf.data);
+                rom.resize(rom.len().div_ceil(BANK_SIZE).max(1) * BANK_SIZE, 0);
+                (rom, banks)
+            }
+            None => {
+                let mut rom = vec![0; 8 * BANK_SIZE];
+                let offset  = nsf.load_addr.saturating_sub(0x8000) as usize;
+                let len     = nsf.data.len().min(rom.len() - offset);
+                rom[offset..offset + len].copy_from_slice(&nsf.data[..len]);
+                (rom, [0, 1, 2, 3, 4, 5, 6, 7])
+            }
+        };
+        NsfMemory {
+            ram: [0; RAM_SIZE],
+            wram: [0; WRAM_SIZE],
+            rom,
+            initial_banks,
+            banks: initial_banks,
+            bankswitched: nsf.banks.is_some(),
+            bank_version: 0,
+            call: 0,
+            apu,
+            stall: 0,
+        }
+    }
+
+    pub fn apu(&self) -> Option<&Apu> {
+        self.apu.as_ref()
+    }
+
+    pub fn apu_mut(&mut self) -> Option<&mut Apu> {
+        self.apu.as_mut()
+    }
+
+    /// Current banks of $8000-$FFFF
+    pub fn banks(&self) -> [u8; 8] {
+        self.banks
+    }
+
+    /// Clear ram and restore initial banks
+    fn reset(&mut self) {
+        self.ram.fill(0);
+        self.wram.fill(0);
+        self.banks = self.initial_banks;
+        self.bank_version += 1;
+    }
+
+    fn run_apu(&mut self, cycles: u64) {
+        let Some(apu) = &mut self.apu else { return };
+        let (rom, banks) = (&self.rom, &self.banks);
+        // Samples are in $8000-$FFFF
+        self.stall += apu.run(cycles, |addr| read_rom(rom, banks, addr));
+    }
+}
+
+impl Memory for NsfMemory {
+    fn read_byte(&self, addr: u16) -> u8 {
+        match addr {
+            0x0000..=0x1FFF => self.ram[addr as usize % RAM_SIZE],
+            0x4015 => self.apu.as_ref().map_or(0, |apu| apu.read_status()),
+            INIT_DRIVER..DRIVER => INIT_CODE[(addr - INIT_DRIVER) as usize],
+            DRIVER => 0x20,
+            0x5FF1 => self.call as u8,
+            0x5FF2 => (self.call >> 8) as u8,
+            IDLE   => 0x4C,
+            0x5FF4 => IDLE as u8,
+            0x5FF5 => (IDLE >> 8) as u8,
+            0x6000..=0x7FFF => self.wram[addr as usize - 0x6000],
+            0x8000..=0xFFFF => read_rom(&self.rom, &self.banks, addr),
+            _ => 0,
+        }
+    }
+
+    fn peek(&self, addr: u16) -> u8 {
+        match addr {
+            0x4015 => self.apu.as_ref().map_or(0, |apu| apu.peek_status()),
+            _ => self.read_byte(addr),
+        }
+    }
+
+    fn write_byte(&mut self, addr: u16, value: u8) {
+        match addr {
+            0x0000..=0x1FFF => self.ram[addr as usize % RAM_SIZE] = value,
+            0x4000..=0x4013 | 0x4015 | 0x4017 => {
+                if let Some(apu) = &mut self.apu {
+                    apu.write_register(addr, value);
+                }
+            }
+            0x5FF8..=0x5FFF if self.bankswitched => {
+                self.banks[addr as usize - 0x5FF8] = value;
+                self.bank_version += 1;
+            }
+            0x6000..=0x7FFF => self.wram[addr as usize - 0x6000] = value,
+            _ => (),
+        }
+    }
+
+    fn is_io(&self, addr: u16) -> bool {
+        (0x4000..=0x4017).contains(&addr)
+    }
+
+    fn mirror_of(&self, addr: u16) -> u16 {
+        match addr {
+            0x0000..=0x1FFF => addr % RAM_SIZE as u16,
+            _ => addr,
+        }
+    }
+
+    fn bank_version(&self) -> u64 {
+        self.bank_version
+    }
+}
+
+// Banked rom at $8000-$FFFF
+fn read_rom(rom: &[u8], banks: &[u8; 8], addr: u16) -> u8 {
+    let slot = (addr as usize & 0x7FFF) / BANK_SIZE;
+    let bank = banks[slot] as usize % (rom.len() / BANK_SIZE);
+    rom[bank * BANK_SIZE + (addr as usize & (BANK_SIZE - 1))]
+}
+
+/// Player of a NSF: calls INIT of a song and then PLAY at the rate of the region.
+/// The cpu calls hooks `H`, like `ApuRecorder` to log the register writes.
+pub struct NsfPlayer<H = NoHooks> {
+    nsf: Nsf,
+    cpu: Cpu<NsfMemory, H>,
+    song: u8,
+    // Cpu cycles between PLAY calls
+    period: f64,
+    // Cpu cycle the current PLAY period ends
+    next_play: f64,
+    // Cpu cycles the APU was run to
+    apu_cycles: u64,
+    // INIT of the current song returned
+    init_returned: bool,
+}
+
+impl NsfPlayer {
+    /// Build player with APU, playing the start song. See `init_returned` for
+    /// the result of INIT.
+    pub fn new(nsf: Nsf, region: Region) -> NsfPlayer {
+        NsfPlayer::with_hooks(nsf, region, NoHooks)
+    }
+}
+
+impl<H: CpuHooks> NsfPlayer<H> {
+    /// Build player with APU and hooks called by the cpu
+    pub fn with_hooks(nsf: Nsf, region: Region, hooks: H) -> NsfPlayer<H> {
+        NsfPlayer::build(nsf, region, true, hooks)
+    }
+
+    /// Build player without APU, for recording register writes by the hooks only
+    pub fn without_apu(nsf: Nsf, region: Region, hooks: H) -> NsfPlayer<H> {
+        NsfPlayer::build(nsf, region, false, hooks)
+    }
+
+    fn build(nsf: Nsf, region: Region, apu: bool, hooks: H) -> NsfPlayer<H> {
+        let apu     = apu.then(|| Apu::new(region));
+        let mut cpu = Cpu::with_hooks(NsfMemory::new(&nsf, apu), hooks);
+        cpu.set_region(region);
+        let period  = nsf.speed(region) as f64 * cpu.frequency() / 1_000_000.0;
+        let song    = nsf.start_song;
+        let mut player = NsfPlayer {
+            nsf,
+            cpu,
+            song,
+            period,
+            next_play: 0.0,
+            apu_cycles: 0,
+            init_returned: false,
+        };
+        player.start(song);
+        player
+    }
+
+    pub fn nsf(&self) -> &Nsf {
+        &self.nsf
+    }
+
+    pub fn cpu(&self) -> &Cpu<NsfMemory, H> {
+        &self.cpu
+    }
+
+    pub fn hooks(&self) -> &H {
+        self.cpu.hooks()
+    }
+
+    pub fn hooks_mut(&mut self) -> &mut H {
+        self.cpu.hooks_mut()
+    }
+
+    pub fn region(&self) -> Region {
+        self.cpu.region()
+    }
+
+    /// Current song, 0-based
+    pub fn song(&self) -> u8 {
+        self.song
+    }
+
+    /// INIT of the current song returned in a second. When false, the song is
+    /// played with PLAY called on top of the unfinished INIT.
+    pub fn init_returned(&self) -> bool {
+        self.init_returned
+    }
+
+    pub fn apu(&self) -> Option<&Apu> {
+        self.cpu.mem().apu()
+    }
+
+    pub fn apu_mut(&mut self) -> Option<&mut Apu> {
+        self.cpu.mem_mut().apu_mut()
+    }
+
+    /// Initialize memory and APU, and call INIT for the song. Song out of range
+    /// is clamped. Return false if INIT did not return in a second.
+    pub fn start(&mut self, song: u8) -> bool {
+        self.song = song.min(self.nsf.songs.saturating_sub(1));
+        let region = self.region();
+
+        let mem = self.cpu.mem_mut();
+        mem.reset();
+        if let Some(apu) = &mut mem.apu {
+            let rate = apu.sample_rate();
+            *apu = Apu::new(region);
+            apu.set_sample_rate(rate);
+        }
+
+        let reg = self.cpu.reg_mut();
+        reg.a = self.song;
+        // Dendy runs the code for PAL
+        reg.x = (region != Region::Ntsc) as u8;
+        reg.y = 0;
+        reg.s = 0xFD;
+        reg.p = Status::INTERRUPT | Status::ALWAYS;
+        self.apu_cycles = self.cpu.cycles();
+
+        let limit    = self.cpu.frequency() as u64;
+        self.init_returned = self.call(INIT_DRIVER, self.nsf.init_addr, self.cpu.cycles() + limit);
+        self.next_play = self.cpu.cycles() as f64;
+        self.init_returned
+    }
+
+    /// Run one PLAY period: call PLAY and wait until the next one. PLAY still
+    /// running from the last period is continued instead.
+    pub fn play_frame(&mut self) {
+        let end = self.next_play + self.period;
+        if self.cpu.reg().pc == IDLE {
+            self.call(DRIVER, self.nsf.play_addr, end as u64);
+        } else {
+            self.run_until_idle(end as u64);
+        }
+
+        let now = self.cpu.cycles();
+        if (now as f64) < end {
+            self.cpu.stall(end.ceil() as u64 - now);
+            self.sync();
+        }
+        self.next_play = end;
+    }
+
+    /// Play for given seconds and return the samples rendered by the APU
+    pub fn render(&mut self, seconds: f64) -> Vec<f32> {
+        let end = self.cpu.cycles() as f64 + seconds * self.cpu.frequency();
+        while (self.cpu.cycles() as f64) < end {
+            self.play_frame();
+        }
+        self.apu_mut().map(Apu::take_samples).unwrap_or_default()
+    }
+
+    // Call routine through the driver from given entry. Return true if it returned
+    // before the cycle.
+    fn call(&mut self, driver: u16, addr: u16, end: u64) -> bool {
+        self.cpu.mem_mut().call = addr;
+        self.cpu.reg_mut().pc   = driver;
+        self.run_until_idle(end)
+    }
+
+    fn run_until_idle(&mut self, end: u64) -> bool {
+        while self.cpu.reg().pc != IDLE {
+            if self.cpu.cycles() >= end {
+                return false;
+            }
+            self.cpu.step();
+            let stall = self.cpu.mem_mut().stall;
+            if stall > 0 {
+                self.cpu.mem_mut().stall = 0;
+                self.cpu.stall(stall);
+            }
+            self.sync();
+        }
+        true
+    }
+
+    fn sync(&mut self) {
+        let cycles = self.cpu.cycles();
+        self.cpu.mem_mut().run_apu(cycles - self.apu_cycles);
+        self.apu_cycles = cycles;
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::apu_log::ApuRecorder;
+    use crate::nsf::test::nsf;
+
+    #[test]
+    fn test_bankswitch() {
+        let mut data = vec![0; 3 * BANK_SIZE];
+        data[BANK_SIZE] = 0x11;
+        data[2 * BANK_SIZE] = 0x22;
+        let nsf = Nsf::from_bytes(&nsf(&data, [0, 1, 0, 0, 0, 0, 0, 0])).unwrap();
+        let mut mem = NsfMemory::new(&nsf, None);
+        assert_eq!(mem.read_byte(0x9000), 0x11);
+        mem.write_byte(0x5FF9, 2);
+        assert_eq!(mem.read_byte(0x9000), 0x22);
+        assert_eq!(mem.bank_version(), 1);
+        // Bank out of range is wrapped
+        mem.write_byte(0x5FF8, 4);
+        assert_eq!(mem.read_byte(0x8000), 0x11);
+    }
+
+    #[test]
+    fn test_play() {
+        // INIT: STA $00; STX $01; RTS
+        // PLAY: INC $02; LDA #$3F; STA $4000; RTS
+        let mut program = vec![0; 0x20];
+        program[0x00..0x05].copy_from_slice(&[0x85, 0x00, 0x86, 0x01, 0x60]);
+        program[0x10..0x18].copy_from_slice(&[0xE6, 0x02, 0xA9, 0x3F, 0x8D, 0x00, 0x40, 0x60]);
+        let nsf = Nsf::from_bytes(&nsf(&program, [0; 8])).unwrap();
+
+        let mut player = NsfPlayer::with_hooks(nsf.clone(), Region::Pal, ApuRecorder::new());
+        assert!(player.init_returned());
+        let mem = player.cpu().mem();
+        assert_eq!((mem.read_byte(0x00), mem.read_byte(0x01)), (1, 1));
+
+        // Registers are reset by the driver before INIT
+        let reset = player.hooks_mut().take_writes();
+        assert_eq!(reset.len(), 22);
+        assert_eq!((reset[0].addr, reset[0].value), (0x4013, 0x00));
+        assert_eq!((reset[21].addr, reset[21].value), (0x4017, 0x40));
+
+        // PAL calls PLAY every 19997us, and the last period is run to the end
+        let samples = player.render(1.0);
+        assert_eq!(player.cpu().mem().read_byte(0x02), 51);
+        assert!((44_900..=45_000).contains(&samples.len()));
+
+        let writes = player.hooks_mut().take_writes();
+        assert_eq!(writes.len(), 51);
+        assert_eq!((writes[0].addr, writes[0].value), (0x4000, 0x3F));
+        let period = writes[1].cycle - writes[0].cycle;
+        assert!((33_246..=33_248).contains(&period));
+
+        let mut player = NsfPlayer::without_apu(nsf, Region::Ntsc, NoHooks);
+        assert!(player.start(5));
+        assert_eq!(player.song(), 2);
+        assert_eq!(player.cpu().mem().read_byte(0x00), 2);
+        assert!(player.render(0.1).is_empty());
+
+        // INIT: JMP $8000
+        let stuck = Nsf::from_bytes(&crate::nsf::test::nsf(&[0x4C, 0x00, 0x80], [0; 8])).unwrap();
+        assert!(!NsfPlayer::without_apu(stuck, Region::Ntsc, NoHooks).init_returned());
+    }
+}
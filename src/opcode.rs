@@ -1,9 +1,10 @@
 #![allow(dead_code)]
 
-use once_cell::sync::Lazy;
-use std::collections::HashMap;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum Mnemonic {
     Adc, And, Asl, Bcc, Bcs, Beq, Bit, Bmi, Bne, Bpl, Brk, Bvc, Bvs, Clc,
     Cld, Cli, Clv, Cmp, Cpx, Cpy, Dec, Dex, Dey, Eor, Inc, Inx, Iny, Jmp,
@@ -11,7 +12,8 @@ pub enum Mnemonic {
     Rts, Sbc, Sec, Sed, Sei, Sta, Stx, Sty, Tax, Tay, Tsx, Txa, Txs, Tya,
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub enum AddressingMode {
     Accumulator, Absolute, AbsoluteX, AbsoluteY,
     Immediate,   Implied,  Indirect,  IndirectX,
@@ -19,11 +21,24 @@ pub enum AddressingMode {
     ZeroPageY,
 }
 
+impl AddressingMode {
+    /// Number of operand bytes after opcode
+    pub const fn operand_len(&self) -> u8 {
+        match self {
+            AddressingMode::Accumulator | AddressingMode::Implied => 0,
+            AddressingMode::Absolute  | AddressingMode::AbsoluteX |
+            AddressingMode::AbsoluteY | AddressingMode::Indirect  => 2,
+            _ => 1,
+        }
+    }
+}
+
 pub type Opcode = u8;
 
 // TODO: 
 // - [ ] byte may be unused.
-#[derive(Debug, PartialEq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct OpcodeInfo {
     pub byte: u8,
     pub cycle: u8,
@@ -32,7 +47,7 @@ pub struct OpcodeInfo {
 }
 
 impl OpcodeInfo {
-    fn new(byte: u8, cycle: u8, name: Mnemonic, mode: AddressingMode) -> Self {
+    const fn new(byte: u8, cycle: u8, name: Mnemonic, mode: AddressingMode) -> Self {
         Self { byte, cycle, name, mode }
     }
 }
@@ -40,8 +55,17 @@ impl OpcodeInfo {
 // TODO:
 // - [ ] There may be typos
 // - [ ] If possible, add unofficial opcode
-pub static OPCODE_TABLE: Lazy<HashMap<Opcode, OpcodeInfo>>  = Lazy::new(|| {
-    HashMap::from([
+/// Information of each opcode, indexed by opcode. None for invalid opcode.
+pub static OPCODE_TABLE: [Option<OpcodeInfo>; 256] = table();
+
+/// Information of given opcode. None if the opcode is invalid.
+pub fn lookup(opcode: Opcode) -> Option<&'static OpcodeInfo> {
+    OPCODE_TABLE[opcode as usize].as_ref()
+}
+
+// Evaluated at compile time, also used by the cpu to specialize each opcode
+pub(crate) const fn table() -> [Option<OpcodeInfo>; 256] {
+    let opcodes: &[(Opcode, OpcodeInfo)] = &[
         (0x69, OpcodeInfo::new(2, 2, Mnemonic::Adc, AddressingMode::Immediate  )),
         (0x65, OpcodeInfo::new(2, 3, Mnemonic::Adc, AddressingMode::ZeroPage   )),
         (0x75, OpcodeInfo::new(2, 4, Mnemonic::Adc, AddressingMode::ZeroPageX  )),
@@ -193,8 +217,17 @@ pub static OPCODE_TABLE: Lazy<HashMap<Opcode, OpcodeInfo>>  = Lazy::new(|| {
         (0x8A, OpcodeInfo::new(1, 2, Mnemonic::Txa, AddressingMode::Implied    )),
         (0x9A, OpcodeInfo::new(1, 2, Mnemonic::Txs, AddressingMode::Implied    )),
         (0x98, OpcodeInfo::new(1, 2, Mnemonic::Tya, AddressingMode::Implied    )),
-    ])
-});
+    ];
+
+    let mut table = [None; 256];
+    let mut i = 0;
+    while i < opcodes.len() {
+        let (opcode, info) = opcodes[i];
+        table[opcode as usize] = Some(info);
+        i += 1;
+    }
+    table
+}
 
 #[cfg(test)]
 mod test {
@@ -203,11 +236,12 @@ mod test {
     #[test]
     fn test_valid_opcode() {
         let info = OpcodeInfo::new(1, 7, Mnemonic::Brk, AddressingMode::Implied);
-        assert_eq!(OPCODE_TABLE.get(&0x00), Some(&info));
+        assert_eq!(OPCODE_TABLE[0x00], Some(info));
+        assert_eq!(lookup(0x00), Some(&info));
     }
 
     #[test]
     fn test_invalid_opcode() {
-        assert_eq!(OPCODE_TABLE.get(&0x02), None);
+        assert_eq!(OPCODE_TABLE[0x02], None);
     }
 }
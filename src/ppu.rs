@@ -0,0 +1,696 @@
+//! Provide headless PPU that renders frames into a buffer
+//!
+//! PPU is clocked dot by dot with `Ppu::step`. Background is fetched through shift
+//! registers with the VRAM address (`v`, `t`, fine x and the write toggle) updated
+//! at the same dots as the real chip, so mid-frame scroll changes work. Sprites of
+//! each line are evaluated at dot 257 of the line before.
+//!
+//! Register reads with side effect ($2002 and $2007) are done through `&self`,
+//! since `Memory::read_byte` takes `&self`, so the state changed by them is kept
+//! in `Cell`. Tools read them with `Ppu::peek_register` that has no side effect.
+
+use std::cell::Cell;
+
+use crate::cartridge::Mirroring;
+use crate::region::Region;
+use crate::state::{StateError, StateReader, StateWriter};
+
+pub const WIDTH:  usize = 256;
+pub const HEIGHT: usize = 240;
+
+const CHR_RAM_SIZE: usize = 0x2000;
+
+// PPUCTRL
+const CTRL_INCREMENT:  u8 = 0x04;
+const CTRL_SPRITE_TBL: u8 = 0x08;
+const CTRL_BG_TBL:     u8 = 0x10;
+const CTRL_TALL:       u8 = 0x20;
+const CTRL_NMI:        u8 = 0x80;
+
+// PPUMASK
+const MASK_GREYSCALE:   u8 = 0x01;
+const MASK_BG_LEFT:     u8 = 0x02;
+const MASK_SPRITE_LEFT: u8 = 0x04;
+const MASK_BG:          u8 = 0x08;
+const MASK_SPRITE:      u8 = 0x10;
+
+// PPUSTATUS
+const STATUS_OVERFLOW: u8 = 0x20;
+const STATUS_HIT:      u8 = 0x40;
+const STATUS_VBLANK:   u8 = 0x80;
+
+/// RGB of each color of 2C02
+pub const PALETTE: [[u8; 3]; 64] = [
+    [ 84,  84,  84], [  0,  30, 116], [  8,  16, 144], [ 48,   0, 136],
+    [ 68,   0, 100], [ 92,   0,  48], [ 84,   4,   0], [ 60,  24,   0],
+    [ 32,  42,   0], [  8,  58,   0], [  0,  64,   0], [  0,  60,   0],
+    [  0,  50,  60], [  0,   0,   0], [  0,   0,   0], [  0,   0,   0],
+    [152, 150, 152], [  8,  76, 196], [ 48,  50, 236], [ 92,  30, 228],
+    [136,  20, 176], [160,  20, 100], [152,  34,  32], [120,  60,   0],
+    [ 84,  90,   0], [ 40, 114,   0], [  8, 124,   0], [  0, 118,  40],
+    [  0, 102, 120], [  0,   0,   0], [  0,   0,   0], [  0,   0,   0],
+    [236, 238, 236], [ 76, 154, 236], [120, 124, 236], [176,  98, 236],
+    [228,  84, 236], [236,  88, 180], [236, 106, 100], [212, 136,  32],
+    [160, 170,   0], [116, 196,   0], [ 76, 208,  32], [ 56, 204, 108],
+    [ 56, 180, 204], [ 60,  60,  60], [  0,   0,   0], [  0,   0,   0],
+    [236, 238, 236], [168, 204, 236], [188, 188, 236], [212, 178, 236],
+    [236, 174, 236], [236, 174, 212], [236, 180, 176], [228, 196, 144],
+    [204, 210, 120], [180, 222, 120], [168, 226, 144], [152, 226, 180],
+    [160, 214, 228], [160, 162, 160], [  0,   0,   0], [  0,   0,   0],
+];
+
+// Sprite evaluated for a line, with pattern already fetched and flipped
+#[derive(Clone, Copy)]
+struct Sprite {
+    x: u8,
+    attr: u8,
+    lo: u8,
+    hi: u8,
+    zero: bool,
+}
+
+pub struct Ppu {
+    region: Region,
+    chr: Vec<u8>,
+    chr_ram: bool,
+    mirroring: Mirroring,
+    vram: [u8; 0x1000],
+    palette: [u8; 32],
+    oam: [u8; 256],
+
+    ctrl: u8,
+    mask: u8,
+    status: Cell<u8>,
+    oam_addr: u8,
+    v: Cell<u16>,
+    t: u16,
+    x: u8,
+    w: Cell<bool>,
+    buffer: Cell<u8>,
+    // Last value on the data bus of the registers, returned by write-only registers
+    latch: Cell<u8>,
+
+    scanline: u16,
+    dot: u16,
+    odd: bool,
+    frames: u64,
+
+    nt: u8,
+    at: u8,
+    bg_lo: u8,
+    bg_hi: u8,
+    pattern_lo: u16,
+    pattern_hi: u16,
+    attr_lo: u16,
+    attr_hi: u16,
+    sprites: Vec<Sprite>,
+
+    pixels: Box<[u8; WIDTH * HEIGHT]>,
+    rgb: Vec<u8>,
+}
+
+impl Ppu {
+    /// PPU with given CHR ROM. 8KiB CHR RAM is used if `chr` is empty.
+    pub fn new(chr: Vec<u8>, mirroring: Mirroring, region: Region) -> Ppu {
+        let chr_ram = chr.is_empty();
+        let chr     = if chr_ram { vec![0; CHR_RAM_SIZE] } else { chr };
+        Ppu {
+            region,
+            chr,
+            chr_ram,
+            mirroring,
+            vram: [0; 0x1000],
+            palette: [0; 32],
+            oam: [0; 256],
+            ctrl: 0,
+            mask: 0,
+            status: Cell::new(0),
+            oam_addr: 0,
+            v: Cell::new(0),
+            t: 0,
+            x: 0,
+            w: Cell::new(false),
+            buffer: Cell::new(0),
+            latch: Cell::new(0),
+            scanline: 0,
+            dot: 0,
+            odd: false,
+            frames: 0,
+            nt: 0,
+            at: 0,
+            bg_lo: 0,
+            bg_hi: 0,
+            pattern_lo: 0,
+            pattern_hi: 0,
+            attr_lo: 0,
+            attr_hi: 0,
+            sprites: Vec::with_capacity(8),
+            pixels: Box::new([0; WIDTH * HEIGHT]),
+            rgb: vec![0; WIDTH * HEIGHT * 3],
+        }
+    }
+
+    pub fn region(&self) -> Region {
+        self.region
+    }
+
+    pub fn scanline(&self) -> u16 {
+        self.scanline
+    }
+
+    pub fn dot(&self) -> u16 {
+        self.dot
+    }
+
+    /// Number of frames completed, counted at start of vblank
+    pub fn frame_count(&self) -> u64 {
+        self.frames
+    }
+
+    /// Last completed frame as RGB, 3 bytes per pixel
+    pub fn frame(&self) -> &[u8] {
+        &self.rgb
+    }
+
+    /// Color index (0-63) of each pixel of the frame being rendered
+    pub fn pixels(&self) -> &[u8] {
+        &self.pixels[..]
+    }
+
+    /// Level of NMI output, asserted in vblank while NMI is enabled in PPUCTRL
+    pub fn nmi_line(&self) -> bool {
+        self.ctrl & CTRL_NMI != 0 && self.status.get() & STATUS_VBLANK != 0
+    }
+
+    pub fn oam(&self) -> &[u8; 256] {
+        &self.oam
+    }
+
+    /// Reset by the reset button: registers written by the cpu are cleared
+    pub fn reset(&mut self) {
+        self.ctrl = 0;
+        self.mask = 0;
+        self.t    = 0;
+        self.x    = 0;
+        self.w.set(false);
+        self.buffer.set(0);
+    }
+
+    /// Read register of $2000-$2007
+    pub fn read_register(&self, addr: u16) -> u8 {
+        let value = match addr & 7 {
+            2 => {
+                let status = self.status.get();
+                self.status.set(status & !STATUS_VBLANK);
+                self.w.set(false);
+                (status & 0xE0) | (self.latch.get() & 0x1F)
+            }
+            4 => self.oam[self.oam_addr as usize],
+            7 => {
+                let v     = self.v.get() & 0x3FFF;
+                let value = self.read_vram(v);
+                self.v.set(v.wrapping_add(self.increment()));
+                if v >= 0x3F00 {
+                    // Palette is read directly, and the nametable under it goes to the buffer
+                    self.buffer.set(self.read_vram(v - 0x1000));
+                    (value & 0x3F) | (self.latch.get() & 0xC0)
+                } else {
+                    self.buffer.replace(value)
+                }
+            }
+            _ => self.latch.get(),
+        };
+        self.latch.set(value);
+        value
+    }
+
+    /// Value of register of $2000-$2007 without side effect of the read. The value of
+    /// $2007 is what the read would return, and the address is not incremented.
+    pub fn peek_register(&self, addr: u16) -> u8 {
+        match addr & 7 {
+            2 => (self.status.get() & 0xE0) | (self.latch.get() & 0x1F),
+            4 => self.oam[self.oam_addr as usize],
+            7 => {
+                let v = self.v.get() & 0x3FFF;
+                if v >= 0x3F00 {
+                    (self.read_vram(v) & 0x3F) | (self.latch.get() & 0xC0)
+                } else {
+                    self.buffer.get()
+                }
+            }
+            _ => self.latch.get(),
+        }
+    }
+
+    /// Write register of $2000-$2007
+    pub fn write_register(&mut self, addr: u16, value: u8) {
+        self.latch.set(value);
+        match addr & 7 {
+            0 => {
+                self.ctrl = value;
+                self.t    = (self.t & 0xF3FF) | ((value as u16 & 0x03) << 10);
+            }
+            1 => self.mask = value,
+            3 => self.oam_addr = value,
+            4 => {
+                self.oam[self.oam_addr as usize] = value;
+                self.oam_addr = self.oam_addr.wrapping_add(1);
+            }
+            5 => {
+                if !self.w.get() {
+                    self.t = (self.t & !0x001F) | (value as u16 >> 3);
+                    self.x = value & 0x07;
+                } else {
+                    self.t = (self.t & 0x8C1F) | ((value as u16 & 0x07) << 12) | ((value as u16 & 0xF8) << 2);
+                }
+                self.w.set(!self.w.get());
+            }
+            6 => {
+                if !self.w.get() {
+                    self.t = (self.t & 0x00FF) | ((value as u16 & 0x3F) << 8);
+                } else {
+                    self.t = (self.t & 0xFF00) | value as u16;
+                    self.v.set(self.t);
+                }
+                self.w.set(!self.w.get());
+            }
+            7 => {
+                let v = self.v.get() & 0x3FFF;
+                self.write_vram(v, value);
+                self.v.set(v.wrapping_add(self.increment()));
+            }
+            _ => (),
+        }
+    }
+
+    /// Copy a page to OAM from current OAMADDR, as done by DMA of $4014
+    pub fn write_oam_dma(&mut self, page: &[u8; 256]) {
+        for &value in page {
+            self.oam[self.oam_addr as usize] = value;
+            self.oam_addr = self.oam_addr.wrapping_add(1);
+        }
+    }
+
+    /// Read PPU address space ($0000-$3FFF)
+    pub fn read_vram(&self, addr: u16) -> u8 {
+        match addr & 0x3FFF {
+            0x0000..=0x1FFF => self.chr[addr as usize % self.chr.len()],
+            0x2000..=0x3EFF => self.vram[self.nametable_index(addr)],
+            _ => self.palette[palette_index(addr)],
+        }
+    }
+
+    fn write_vram(&mut self, addr: u16, value: u8) {
+        match addr & 0x3FFF {
+            0x0000..=0x1FFF => {
+                if self.chr_ram {
+                    let len = self.chr.len();
+                    self.chr[addr as usize % len] = value;
+                }
+            }
+            0x2000..=0x3EFF => self.vram[self.nametable_index(addr)] = value,
+            _ => self.palette[palette_index(addr)] = value & 0x3F,
+        }
+    }
+
+    fn nametable_index(&self, addr: u16) -> usize {
+        let table = (addr as usize >> 10) & 3;
+        let bank  = match self.mirroring {
+            Mirroring::Horizontal => table >> 1,
+            Mirroring::Vertical   => table & 1,
+            Mirroring::FourScreen => table,
+        };
+        bank * 0x400 + (addr as usize & 0x3FF)
+    }
+
+    fn increment(&self) -> u16 {
+        if self.ctrl & CTRL_INCREMENT != 0 { 32 } else { 1 }
+    }
+
+    fn rendering(&self) -> bool {
+        self.mask & (MASK_BG | MASK_SPRITE) != 0
+    }
+
+    /// Run one dot
+    pub fn step(&mut self) {
+        let pre       = self.region.scanlines() - 1;
+        let line      = self.scanline;
+        let dot       = self.dot;
+        let rendering = self.rendering();
+
+        if line < HEIGHT as u16 || line == pre {
+            if line == pre && dot == 1 {
+                self.status.set(self.status.get() & !(STATUS_VBLANK | STATUS_HIT | STATUS_OVERFLOW));
+            }
+            if rendering {
+                self.fetch_background(line == pre);
+            }
+            if dot == 257 {
+                self.evaluate_sprites(line, line == pre || !rendering);
+            }
+        }
+        if line < HEIGHT as u16 && (1..=WIDTH as u16).contains(&dot) {
+            self.render_pixel();
+        }
+        if line == self.region.vblank_line() && dot == 1 {
+            self.status.set(self.status.get() | STATUS_VBLANK);
+            self.finish_frame();
+        }
+
+        self.dot += 1;
+        if line == pre && dot == 339 && self.odd && rendering && self.region.skips_odd_dot() {
+            self.dot = 341;
+        }
+        if self.dot > 340 {
+            self.dot       = 0;
+            self.scanline += 1;
+            if self.scanline > pre {
+                self.scanline = 0;
+                self.odd      = !self.odd;
+            }
+        }
+    }
+
+    fn finish_frame(&mut self) {
+        for (rgb, &color) in self.rgb.chunks_exact_mut(3).zip(self.pixels.iter()) {
+            rgb.copy_from_slice(&PALETTE[color as usize]);
+        }
+        self.frames += 1;
+    }
+
+    fn fetch_background(&mut self, pre: bool) {
+        let dot = self.dot;
+        if (2..=257).contains(&dot) || (321..=337).contains(&dot) {
+            self.pattern_lo <<= 1;
+            self.pattern_hi <<= 1;
+            self.attr_lo    <<= 1;
+            self.attr_hi    <<= 1;
+
+            let v = self.v.get();
+            match (dot - 1) % 8 {
+                0 => {
+                    self.load_shifters();
+                    self.nt = self.read_vram(0x2000 | (v & 0x0FFF));
+                }
+                2 => {
+                    let addr  = 0x23C0 | (v & 0x0C00) | ((v >> 4) & 0x38) | ((v >> 2) & 0x07);
+                    let shift = ((v >> 4) & 0x04) | (v & 0x02);
+                    self.at   = (self.read_vram(addr) >> shift) & 0x03;
+                }
+                4 => self.bg_lo = self.read_vram(self.bg_pattern_addr()),
+                6 => self.bg_hi = self.read_vram(self.bg_pattern_addr() + 8),
+                7 => self.increment_x(),
+                _ => (),
+            }
+        }
+        if dot == 256 {
+            self.increment_y();
+        }
+        if dot == 257 {
+            self.load_shifters();
+            let v = self.v.get();
+            self.v.set((v & !0x041F) | (self.t & 0x041F));
+        }
+        if pre && (280..=304).contains(&dot) {
+            let v = self.v.get();
+            self.v.set((v & !0x7BE0) | (self.t & 0x7BE0));
+        }
+    }
+
+    fn bg_pattern_addr(&self) -> u16 {
+        let table = if self.ctrl & CTRL_BG_TBL != 0 { 0x1000 } else { 0 };
+        table + self.nt as u16 * 16 + ((self.v.get() >> 12) & 0x07)
+    }
+
+    fn load_shifters(&mut self) {
+        self.pattern_lo = (self.pattern_lo & 0xFF00) | self.bg_lo as u16;
+        self.pattern_hi = (self.pattern_hi & 0xFF00) | self.bg_hi as u16;
+        self.attr_lo    = (self.attr_lo & 0xFF00) | if self.at & 1 != 0 { 0xFF } else { 0 };
+        self.attr_hi    = (self.attr_hi & 0xFF00) | if self.at & 2 != 0 { 0xFF } else { 0 };
+    }
+
+    fn increment_x(&mut self) {
+        let v = self.v.get();
+        if v & 0x001F == 31 {
+            self.v.set((v & !0x001F) ^ 0x0400);
+        } else {
+            self.v.set(v + 1);
+        }
+    }
+
+    fn increment_y(&mut self) {
+        let mut v = self.v.get();
+        if v & 0x7000 != 0x7000 {
+            v += 0x1000;
+        } else {
+            v &= !0x7000;
+            let y = match (v & 0x03E0) >> 5 {
+                29 => {
+                    v ^= 0x0800;
+                    0
+                }
+                31 => 0,
+                y  => y + 1,
+            };
+            v = (v & !0x03E0) | (y << 5);
+        }
+        self.v.set(v);
+    }
+
+    // Find sprites shown on the next line of `line`
+    fn evaluate_sprites(&mut self, line: u16, clear: bool) {
+        self.sprites.clear();
+        if clear {
+            return;
+        }
+        let height = if self.ctrl & CTRL_TALL != 0 { 16 } else { 8 };
+        for i in 0..64 {
+            let entry = &self.oam[i * 4..i * 4 + 4];
+            let row   = line.wrapping_sub(entry[0] as u16);
+            if row >= height {
+                continue;
+            }
+            if self.sprites.len() == 8 {
+                self.status.set(self.status.get() | STATUS_OVERFLOW);
+                break;
+            }
+
+            let (tile, attr) = (entry[1], entry[2]);
+            let row  = if attr & 0x80 != 0 { height - 1 - row } else { row };
+            let addr = if height == 16 {
+                let table = (tile as u16 & 1) * 0x1000;
+                let tile  = (tile & 0xFE) as u16 + (row >> 3);
+                table + tile * 16 + (row & 7)
+            } else {
+                let table = if self.ctrl & CTRL_SPRITE_TBL != 0 { 0x1000 } else { 0 };
+                table + tile as u16 * 16 + row
+            };
+            let mut lo = self.read_vram(addr);
+            let mut hi = self.read_vram(addr + 8);
+            if attr & 0x40 != 0 {
+                lo = lo.reverse_bits();
+                hi = hi.reverse_bits();
+            }
+            self.sprites.push(Sprite { x: entry[3], attr, lo, hi, zero: i == 0 });
+        }
+    }
+
+    fn render_pixel(&mut self) {
+        let x = self.dot as usize - 1;
+
+        let mut bg = 0;
+        if self.mask & MASK_BG != 0 && (x >= 8 || self.mask & MASK_BG_LEFT != 0) {
+            let bit   = 0x8000 >> self.x;
+            let pixel = (self.pattern_lo & bit != 0) as u8 | ((self.pattern_hi & bit != 0) as u8) << 1;
+            let pal   = (self.attr_lo & bit != 0) as u8 | ((self.attr_hi & bit != 0) as u8) << 1;
+            if pixel != 0 {
+                bg = pal << 2 | pixel;
+            }
+        }
+
+        let mut sprite = None;
+        if self.mask & MASK_SPRITE != 0 && (x >= 8 || self.mask & MASK_SPRITE_LEFT != 0) {
+            for s in &self.sprites {
+                let col = x.wrapping_sub(s.x as usize);
+                if col >= 8 {
+                    continue;
+                }
+                let bit   = 7 - col;
+                let pixel = (s.lo >> bit) & 1 | ((s.hi >> bit) & 1) << 1;
+                if pixel != 0 {
+                    sprite = Some((0x10 | (s.attr & 0x03) << 2 | pixel, s.attr & 0x20 != 0, s.zero));
+                    break;
+                }
+            }
+        }
+
+        let index = match sprite {
+            Some((color, behind, zero)) => {
+                if zero && bg != 0 && x != 255 {
+                    self.status.set(self.status.get() | STATUS_HIT);
+                }
+                if behind && bg != 0 { bg } else { color }
+            }
+            None => bg,
+        };
+        let mut color = self.palette[palette_index(index as u16)];
+        if self.mask & MASK_GREYSCALE != 0 {
+            color &= 0x30;
+        }
+        self.pixels[self.scanline as usize * WIDTH + x] = color;
+    }
+
+    pub fn save_state(&self, out: &mut StateWriter) {
+        out.section(*b"PPU ", 1, |out| {
+            if self.chr_ram {
+                out.write_bytes(&self.chr);
+            }
+            out.write_bytes(&self.vram);
+            out.write_bytes(&self.palette);
+            out.write_bytes(&self.oam);
+            for value in [self.ctrl, self.mask, self.status.get(), self.oam_addr, self.x] {
+                out.write_u8(value);
+            }
+            out.write_u16(self.v.get());
+            out.write_u16(self.t);
+            out.write_bool(self.w.get());
+            out.write_u8(self.buffer.get());
+            out.write_u8(self.latch.get());
+            out.write_u16(self.scanline);
+            out.write_u16(self.dot);
+            out.write_bool(self.odd);
+            out.write_u64(self.frames);
+            for value in [self.nt, self.at, self.bg_lo, self.bg_hi] {
+                out.write_u8(value);
+            }
+            for value in [self.pattern_lo, self.pattern_hi, self.attr_lo, self.attr_hi] {
+                out.write_u16(value);
+            }
+        });
+    }
+
+    /// Load state saved by `save_state`. Sprites of current line are evaluated again.
+    pub fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError> {
+        let (_, mut ppu) = input.section(*b"PPU ", 1)?;
+        if self.chr_ram {
+            ppu.read_into(&mut self.chr)?;
+        }
+        ppu.read_into(&mut self.vram)?;
+        ppu.read_into(&mut self.palette)?;
+        ppu.read_into(&mut self.oam)?;
+        self.ctrl     = ppu.read_u8()?;
+        self.mask     = ppu.read_u8()?;
+        self.status.set(ppu.read_u8()?);
+        self.oam_addr = ppu.read_u8()?;
+        self.x        = ppu.read_u8()?;
+        self.v.set(ppu.read_u16()?);
+        self.t        = ppu.read_u16()?;
+        self.w.set(ppu.read_bool()?);
+        self.buffer.set(ppu.read_u8()?);
+        self.latch.set(ppu.read_u8()?);
+        self.scanline = ppu.read_u16()?;
+        self.dot      = ppu.read_u16()?;
+        self.odd      = ppu.read_bool()?;
+        self.frames   = ppu.read_u64()?;
+        self.nt       = ppu.read_u8()?;
+        self.at       = ppu.read_u8()?;
+        self.bg_lo    = ppu.read_u8()?;
+        self.bg_hi    = ppu.read_u8()?;
+        self.pattern_lo = ppu.read_u16()?;
+        self.pattern_hi = ppu.read_u16()?;
+        self.attr_lo    = ppu.read_u16()?;
+        self.attr_hi    = ppu.read_u16()?;
+        if self.scanline < HEIGHT as u16 && self.dot > 257 {
+            let pre = self.scanline == self.region.scanlines() - 1;
+            self.evaluate_sprites(self.scanline, pre || !self.rendering());
+        }
+        Ok(())
+    }
+}
+
+fn palette_index(addr: u16) -> usize {
+    let index = addr as usize & 0x1F;
+    if index >= 0x10 && index & 0x03 == 0 { index - 0x10 } else { index }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn ppu() -> Ppu {
+        Ppu::new(Vec::new(), Mirroring::Vertical, Region::Ntsc)
+    }
+
+    fn write_vram(ppu: &mut Ppu, addr: u16, data: &[u8]) {
+        ppu.write_register(6, (addr >> 8) as u8);
+        ppu.write_register(6, addr as u8);
+        for &value in data {
+            ppu.write_register(7, value);
+        }
+    }
+
+    fn run_frame(ppu: &mut Ppu) {
+        let frames = ppu.frame_count();
+        while ppu.frame_count() == frames {
+            ppu.step();
+        }
+    }
+
+    #[test]
+    fn test_registers() {
+        let mut ppu = ppu();
+        write_vram(&mut ppu, 0x2400, &[0x11, 0x22]);
+        write_vram(&mut ppu, 0x3F10, &[0x0F]);
+
+        // Read is delayed by the buffer
+        ppu.write_register(6, 0x2C);
+        ppu.write_register(6, 0x00);
+        ppu.read_register(7);
+        assert_eq!(ppu.read_register(7), 0x11);
+        assert_eq!(ppu.read_register(7), 0x22);
+        assert_eq!(ppu.read_vram(0x3F00), 0x0F);
+
+        // Write toggle is reset by status read
+        ppu.write_register(0, 0x00);
+        ppu.write_register(5, 0x7D);
+        ppu.read_register(2);
+        ppu.write_register(5, 0x5E);
+        ppu.write_register(5, 0x3D);
+        assert_eq!(ppu.t, 0x50EB);
+        assert_eq!(ppu.x, 0x06);
+    }
+
+    #[test]
+    fn test_render() {
+        let mut ppu = ppu();
+        // Tile 1 is solid color 1 (low plane), tile 2 is solid color 3
+        write_vram(&mut ppu, 0x0010, &[0xFF; 8]);
+        write_vram(&mut ppu, 0x0020, &[0xFF; 16]);
+        write_vram(&mut ppu, 0x2000, &[1]);
+        write_vram(&mut ppu, 0x3F00, &[0x0F, 0x16, 0x27, 0x30, 0x0F, 0x01, 0x02, 0x03]);
+        write_vram(&mut ppu, 0x3F10, &[0x0F, 0x11, 0x12, 0x13]);
+        // Sprite 0 with tile 2 at (16, 1), over the background at (0-7, 0-7)
+        ppu.write_oam_dma(&{
+            let mut oam = [0xFF; 256];
+            oam[0..4].copy_from_slice(&[0, 2, 0, 4]);
+            oam
+        });
+        ppu.write_register(0, CTRL_NMI);
+        ppu.write_register(1, MASK_BG | MASK_SPRITE | MASK_BG_LEFT | MASK_SPRITE_LEFT);
+        // Scroll to (0, 0). First frame starts without the tiles fetched on pre-render line.
+        write_vram(&mut ppu, 0x2000, &[]);
+        run_frame(&mut ppu);
+        run_frame(&mut ppu);
+        assert!(ppu.nmi_line());
+        assert_eq!(ppu.read_register(2) & (STATUS_VBLANK | STATUS_HIT), STATUS_VBLANK | STATUS_HIT);
+        assert!(!ppu.nmi_line());
+
+        let pixel = |x: usize, y: usize| ppu.pixels()[y * WIDTH + x];
+        assert_eq!(pixel(0, 0), 0x16);
+        assert_eq!(pixel(8, 0), 0x0F);
+        assert_eq!(pixel(4, 0), 0x16);
+        assert_eq!(pixel(4, 1), 0x13);
+        assert_eq!(pixel(12, 8), 0x0F);
+        assert_eq!(&ppu.frame()[..3], &PALETTE[0x16]);
+    }
+}
@@ -0,0 +1,132 @@
+//! Provide timing profiles of NES-family regions
+
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+
+const NTSC_MASTER_CLOCK: f64 = 236_250_000.0 / 11.0;
+const PAL_MASTER_CLOCK:  f64 = 26_601_712.5;
+
+const NTSC_DMC_RATES: [u16; 16] = [
+    428, 380, 340, 320, 286, 254, 226, 214, 190, 160, 142, 128, 106, 84, 72, 54,
+];
+const PAL_DMC_RATES: [u16; 16] = [
+    398, 354, 316, 298, 276, 236, 210, 198, 176, 148, 132, 118, 98, 78, 66, 50,
+];
+
+/// Region of the console, which decides the clocks and frame length
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+pub enum Region {
+    /// RP2A03 and RP2C02
+    #[default]
+    Ntsc,
+    /// RP2A07 and RP2C07
+    Pal,
+    /// UA6527P and UA6538 clone
+    Dendy,
+}
+
+impl Region {
+    /// Region from CPU/PPU timing field of NES 2.0 header (byte 12).
+    /// Multiple-region rom is run as NTSC.
+    pub fn from_nes2_timing(byte: u8) -> Region {
+        match byte & 0b11 {
+            1 => Region::Pal,
+            3 => Region::Dendy,
+            _ => Region::Ntsc,
+        }
+    }
+
+    /// Frequency of master clock in Hz
+    pub fn master_clock(self) -> f64 {
+        match self {
+            Region::Ntsc => NTSC_MASTER_CLOCK,
+            Region::Pal | Region::Dendy => PAL_MASTER_CLOCK,
+        }
+    }
+
+    /// Master cycles per cpu cycle
+    pub fn cpu_divider(self) -> u64 {
+        match self {
+            Region::Ntsc  => 12,
+            Region::Pal   => 16,
+            Region::Dendy => 15,
+        }
+    }
+
+    /// Master cycles per PPU dot
+    pub fn ppu_divider(self) -> u64 {
+        match self {
+            Region::Ntsc => 4,
+            Region::Pal | Region::Dendy => 5,
+        }
+    }
+
+    /// Frequency of cpu clock in Hz
+    pub fn cpu_frequency(self) -> f64 {
+        self.master_clock() / self.cpu_divider() as f64
+    }
+
+    /// PPU dots per cpu cycle: 3 on NTSC and Dendy, 3.2 on PAL
+    pub fn dots_per_cpu_cycle(self) -> f64 {
+        self.cpu_divider() as f64 / self.ppu_divider() as f64
+    }
+
+    /// Scanlines per frame, including vblank and pre-render line
+    pub fn scanlines(self) -> u16 {
+        match self {
+            Region::Ntsc => 262,
+            Region::Pal | Region::Dendy => 312,
+        }
+    }
+
+    /// Scanline where vblank starts and NMI is raised
+    pub fn vblank_line(self) -> u16 {
+        match self {
+            Region::Ntsc | Region::Pal => 241,
+            Region::Dendy => 291,
+        }
+    }
+
+    /// True if one dot is skipped on odd frames with rendering enabled
+    pub fn skips_odd_dot(self) -> bool {
+        self == Region::Ntsc
+    }
+
+    /// Master cycles of a frame, without the skipped dot of NTSC
+    pub fn frame_master_cycles(self) -> u64 {
+        341 * self.scanlines() as u64 * self.ppu_divider()
+    }
+
+    /// Frames per second
+    pub fn frame_rate(self) -> f64 {
+        self.master_clock() / self.frame_master_cycles() as f64
+    }
+
+    /// Period of each DMC rate index in cpu cycles. Dendy uses the NTSC table.
+    pub fn dmc_rates(self) -> &'static [u16; 16] {
+        match self {
+            Region::Ntsc | Region::Dendy => &NTSC_DMC_RATES,
+            Region::Pal => &PAL_DMC_RATES,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_timing() {
+        assert_eq!(Region::from_nes2_timing(0x01), Region::Pal);
+        assert_eq!(Region::from_nes2_timing(0x02), Region::Ntsc);
+        assert_eq!(Region::from_nes2_timing(0x03), Region::Dendy);
+
+        assert_eq!(Region::Ntsc.cpu_frequency().round(), 1_789_773.0);
+        assert_eq!(Region::Pal.cpu_frequency().round(), 1_662_607.0);
+        assert_eq!(Region::Dendy.cpu_frequency().round(), 1_773_448.0);
+        assert_eq!(Region::Pal.dots_per_cpu_cycle(), 3.2);
+        assert_eq!(Region::Ntsc.frame_rate().round(), 60.0);
+        assert_eq!(Region::Dendy.frame_rate().round(), 50.0);
+    }
+}
@@ -2,10 +2,29 @@
 
 use std::ops::{BitAnd, BitOr, BitXor};
 
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct Status {
     bits: u8,
 }
 
+// Serialized as the bits, same as the `p` of SingleStepTests
+#[cfg(feature = "serde")]
+impl Serialize for Status {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_u8(self.as_bits())
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<'de> Deserialize<'de> for Status {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        u8::deserialize(deserializer).map(Status::from_bits)
+    }
+}
+
 impl BitAnd for Status {
     type Output = Self;
     fn bitand(self, rhs: Self) -> Self::Output {
@@ -68,7 +87,7 @@ impl Status {
 
     // If flags is on, then return true
     pub fn contains(&self, other: Self) -> bool {
-        self.as_bits() & other.as_bits() != 0
+        self.as_bits() & other.bits != 0
     }
 
     // Change flags depend on given value
@@ -78,6 +97,8 @@ impl Status {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
 pub struct Register {
     pub a: u8,
     pub x: u8,
@@ -93,6 +114,12 @@ impl Register {
     }
 }
 
+impl Default for Register {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
@@ -110,4 +137,12 @@ mod test {
         assert_eq!(or.as_bits(),  0b1111_1111);
         assert_eq!(xor.as_bits(), 0b1111_1111);
     }
+
+    #[test]
+    fn test_status_contains() {
+        let status = Status::from_bits(0b0000_0001);
+
+        assert!( status.contains(Status::CARRY));
+        assert!(!status.contains(Status::INTERRUPT));
+    }
 }
@@ -0,0 +1,225 @@
+//! Provide rewind buffer of periodic snapshots
+//!
+//! Only the newest snapshot is kept as a whole save state. Each older snapshot is
+//! kept as a delta from the one after it: xor of the two states, with runs of
+//! unchanged bytes compressed away. Consecutive snapshots differ in a few bytes of
+//! ram, so a snapshot costs much less than a full state.
+
+use std::collections::VecDeque;
+
+use crate::cpu::{Cpu, CpuHooks};
+use crate::memory::Memory;
+use crate::state::StateError;
+
+pub struct Rewind {
+    interval: u64,
+    capacity: usize,
+    // Cycle to take the next snapshot at
+    next: u64,
+    latest: Option<Vec<u8>>,
+    // Last delta restores the snapshot before `latest`, and so on
+    deltas: VecDeque<Vec<u8>>,
+}
+
+impl Rewind {
+    /// Keep up to `capacity` snapshots taken every `interval` cycles.
+    /// For snapshot per frame, give cycles of a frame as interval.
+    /// Zero capacity keeps no snapshot.
+    pub fn new(interval: u64, capacity: usize) -> Rewind {
+        Rewind { interval, capacity, next: 0, latest: None, deltas: VecDeque::new() }
+    }
+
+    /// Take snapshot if interval has elapsed since the last one. Call after each step.
+    /// Return true if snapshot was taken.
+    pub fn update<M: Memory, H: CpuHooks>(&mut self, cpu: &Cpu<M, H>) -> Result<bool, StateError> {
+        if cpu.cycles() < self.next {
+            return Ok(false);
+        }
+        self.snapshot(cpu)?;
+        Ok(true)
+    }
+
+    /// Take snapshot now
+    pub fn snapshot<M: Memory, H: CpuHooks>(&mut self, cpu: &Cpu<M, H>) -> Result<(), StateError> {
+        let state = cpu.save_state()?;
+        if let Some(prev) = self.latest.replace(state) {
+            self.deltas.push_back(diff(self.latest.as_ref().unwrap(), &prev));
+        }
+        while self.len() > self.capacity {
+            if self.deltas.pop_front().is_none() {
+                self.latest = None;
+            }
+        }
+        self.next = cpu.cycles() + self.interval;
+        Ok(())
+    }
+
+    /// Restore the newest snapshot and remove it. Return false if no snapshot is left.
+    /// If loading fails, the snapshot is kept.
+    pub fn rewind<M: Memory, H: CpuHooks>(&mut self, cpu: &mut Cpu<M, H>) -> Result<bool, StateError> {
+        let Some(state) = &self.latest else { return Ok(false) };
+        cpu.load_state(state)?;
+        let state   = self.latest.take().unwrap();
+        self.latest = self.deltas.pop_back().map(|delta| patch(&state, &delta));
+        self.next   = cpu.cycles() + self.interval;
+        Ok(true)
+    }
+
+    /// Number of snapshots kept
+    pub fn len(&self) -> usize {
+        self.latest.as_ref().map_or(0, |_| 1 + self.deltas.len())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.latest.is_none()
+    }
+
+    /// Bytes used by the snapshots
+    pub fn size(&self) -> usize {
+        self.latest.as_ref().map_or(0, Vec::len) + self.deltas.iter().map(Vec::len).sum::<usize>()
+    }
+}
+
+// Delta is length of `to`, then pairs of (unchanged run, changed run, xor of changed bytes).
+// Lengths are LEB128.
+fn diff(from: &[u8], to: &[u8]) -> Vec<u8> {
+    let xor: Vec<u8> = to
+        .iter()
+        .enumerate()
+        .map(|(i, b)| b ^ from.get(i).copied().unwrap_or(0))
+        .collect();
+
+    let mut delta = Vec::new();
+    write_len(&mut delta, to.len());
+    let mut i = 0;
+    while i < xor.len() {
+        let same    = xor[i..].iter().take_while(|b| **b == 0).count();
+        let changed = xor[i + same..].iter().take_while(|b| **b != 0).count();
+        write_len(&mut delta, same);
+        write_len(&mut delta, changed);
+        delta.extend_from_slice(&xor[i + same..i + same + changed]);
+        i += same + changed;
+    }
+    delta
+}
+
+fn patch(from: &[u8], delta: &[u8]) -> Vec<u8> {
+    let mut delta = delta.iter().copied();
+    let len = read_len(&mut delta);
+    let mut to = from.to_vec();
+    to.resize(len, 0);
+
+    let mut i = 0;
+    while i < len {
+        i += read_len(&mut delta);
+        let changed = read_len(&mut delta);
+        for b in &mut to[i..i + changed] {
+            *b ^= delta.next().unwrap_or(0);
+        }
+        i += changed;
+    }
+    to
+}
+
+fn write_len(out: &mut Vec<u8>, mut len: usize) {
+    while len >= 0x80 {
+        out.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+}
+
+fn read_len(input: &mut impl Iterator<Item = u8>) -> usize {
+    let mut len   = 0;
+    let mut shift = 0;
+    for b in input.by_ref() {
+        len |= ((b & 0x7F) as usize) << shift;
+        if b & 0x80 == 0 {
+            break;
+        }
+        shift += 7;
+    }
+    len
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::memory::FlatMemory;
+
+    #[test]
+    fn test_diff_and_patch() {
+        let from = [1, 2, 3, 4, 5, 6];
+        for to in [&[1, 2, 9, 4, 5, 6][..], &[1, 2, 3][..], &[0, 2, 3, 4, 5, 6, 7, 8][..], &[][..]] {
+            assert_eq!(patch(&from, &diff(&from, to)), to);
+        }
+    }
+
+    #[test]
+    fn test_rewind() {
+        // INC $10; JMP $8000
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0xE6, 0x10, 0x4C, 0x00, 0x80]);
+        let mut cpu = Cpu::new(Box::new(mem));
+        cpu.reg_mut().pc = 0x8000;
+
+        // Snapshot every 2 loops (16 cycles), keeping last 3
+        let mut rewind = Rewind::new(16, 3);
+        for _ in 0..20 {
+            rewind.update(&cpu).unwrap();
+            cpu.step();
+        }
+        assert_eq!(rewind.len(), 3);
+        assert!(rewind.size() < 0x10000 + 100);
+
+        let mut counts = Vec::new();
+        while rewind.rewind(&mut cpu).unwrap() {
+            counts.push(cpu.mem().read_byte(0x10));
+        }
+        assert_eq!(counts, [8, 6, 4]);
+    }
+
+    // Memory without save state
+    struct NoState;
+
+    impl Memory for NoState {
+        fn read_byte(&self, _: u16) -> u8 {
+            0
+        }
+
+        fn write_byte(&mut self, _: u16, _: u8) {}
+    }
+
+    #[test]
+    fn test_failed_load() {
+        // INC $10; JMP $8000
+        let mut mem = FlatMemory::new();
+        mem.load(0x8000, &[0xE6, 0x10, 0x4C, 0x00, 0x80]);
+        let mut cpu = Cpu::with_memory(mem);
+        cpu.reg_mut().pc = 0x8000;
+        let mut rewind = Rewind::new(16, 3);
+        for _ in 0..3 {
+            rewind.snapshot(&cpu).unwrap();
+            cpu.run_instructions(2);
+        }
+
+        let mut other = Cpu::with_memory(NoState);
+        assert_eq!(rewind.rewind(&mut other), Err(StateError::NotSupported));
+        assert_eq!(rewind.len(), 3);
+
+        let mut counts = Vec::new();
+        while rewind.rewind(&mut cpu).unwrap() {
+            counts.push(cpu.mem().read_byte(0x10));
+        }
+        assert_eq!(counts, [2, 1, 0]);
+    }
+
+    #[test]
+    fn test_zero_capacity() {
+        let mut cpu = Cpu::with_memory(FlatMemory::new());
+        let mut rewind = Rewind::new(16, 0);
+        rewind.snapshot(&cpu).unwrap();
+        assert!(rewind.is_empty());
+        assert!(!rewind.rewind(&mut cpu).unwrap());
+    }
+}
@@ -0,0 +1,242 @@
+//! Provide binary save state of the cpu and memory
+//!
+//! State starts with `MAGIC` and `FORMAT_VERSION`, followed by sections. Section is
+//! a 4 byte tag, a version and the length of its data, so each part of the state
+//! (cpu, ram, devices) can change its format independently. Loader of a section
+//! is given the version it was saved with and may read older versions.
+
+use std::fmt;
+
+/// First bytes of the state
+pub const MAGIC: &[u8; 4] = b"6502";
+
+/// Version of the framing of the state: header and section layout
+pub const FORMAT_VERSION: u16 = 1;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum StateError {
+    /// State ended before all values were read
+    UnexpectedEnd,
+    /// Value in the state can not be loaded
+    InvalidData(String),
+    /// Memory does not implement `MemoryState`
+    NotSupported,
+    /// Data does not start with `MAGIC`
+    InvalidMagic,
+    /// Section, or the whole state for tag None, was saved by newer version
+    UnsupportedVersion { tag: Option<[u8; 4]>, version: u16, supported: u16 },
+    /// Section with the tag was expected but not found
+    MissingSection([u8; 4]),
+}
+
+impl fmt::Display for StateError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            StateError::UnexpectedEnd       => write!(f, "unexpected end of state"),
+            StateError::InvalidData(reason) => write!(f, "invalid state: {}", reason),
+            StateError::NotSupported        => write!(f, "memory does not support save state"),
+            StateError::InvalidMagic        => write!(f, "not a save state"),
+            StateError::UnsupportedVersion { tag: None, version, supported } => {
+                write!(f, "state format version {} is newer than supported version {}", version, supported)
+            }
+            StateError::UnsupportedVersion { tag: Some(tag), version, supported } => write!(
+                f,
+                "section '{}' version {} is newer than supported version {}",
+                String::from_utf8_lossy(tag),
+                version,
+                supported
+            ),
+            StateError::MissingSection(tag) => {
+                write!(f, "section '{}' not found", String::from_utf8_lossy(tag))
+            }
+        }
+    }
+}
+
+impl std::error::Error for StateError {}
+
+/// Trait for memory that can save and restore its own content.
+///
+/// Implementor writes one or more sections by `StateWriter::section`, and reads them
+/// back in the same order by `StateReader::section`.
+pub trait MemoryState {
+    fn save_state(&self, out: &mut StateWriter);
+
+    fn load_state(&mut self, input: &mut StateReader) -> Result<(), StateError>;
+}
+
+/// Little endian writer of state values
+#[derive(Default)]
+pub struct StateWriter {
+    buf: Vec<u8>,
+}
+
+impl StateWriter {
+    pub fn new() -> StateWriter {
+        StateWriter { buf: Vec::new() }
+    }
+
+    pub fn write_u8(&mut self, value: u8) {
+        self.buf.push(value);
+    }
+
+    pub fn write_bool(&mut self, value: bool) {
+        self.buf.push(value as u8);
+    }
+
+    pub fn write_u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, value: u32) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, value: u64) {
+        self.buf.extend_from_slice(&value.to_le_bytes());
+    }
+
+    /// Write bytes prefixed with its length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.write_u32(bytes.len() as u32);
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Write `MAGIC` and `FORMAT_VERSION`
+    pub fn write_header(&mut self) {
+        self.buf.extend_from_slice(MAGIC);
+        self.write_u16(FORMAT_VERSION);
+    }
+
+    /// Write section with given tag and version. Data is written by `f`.
+    pub fn section<F>(&mut self, tag: [u8; 4], version: u16, f: F)
+    where
+        F: FnOnce(&mut StateWriter),
+    {
+        let mut data = StateWriter::new();
+        f(&mut data);
+        self.buf.extend_from_slice(&tag);
+        self.write_u16(version);
+        self.write_bytes(&data.buf);
+    }
+
+    pub fn into_bytes(self) -> Vec<u8> {
+        self.buf
+    }
+}
+
+/// Reader of values written by `StateWriter`
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> StateReader<'a> {
+    pub fn new(buf: &'a [u8]) -> StateReader<'a> {
+        StateReader { buf }
+    }
+
+    /// Bytes not read yet
+    pub fn remaining(&self) -> &'a [u8] {
+        self.buf
+    }
+
+    fn take(&mut self, len: usize) -> Result<&'a [u8], StateError> {
+        if self.buf.len() < len {
+            return Err(StateError::UnexpectedEnd);
+        }
+        let (head, tail) = self.buf.split_at(len);
+        self.buf = tail;
+        Ok(head)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, StateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, StateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, StateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, StateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, StateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    /// Read bytes written by `StateWriter::write_bytes`
+    pub fn read_bytes(&mut self) -> Result<&'a [u8], StateError> {
+        let len = self.read_u32()? as usize;
+        self.take(len)
+    }
+
+    /// Read bytes into given buffer. Length must be the same as the buffer.
+    pub fn read_into(&mut self, dest: &mut [u8]) -> Result<(), StateError> {
+        let bytes = self.read_bytes()?;
+        if bytes.len() != dest.len() {
+            return Err(StateError::InvalidData(format!(
+                "expected {} bytes, found {}",
+                dest.len(),
+                bytes.len()
+            )));
+        }
+        dest.copy_from_slice(bytes);
+        Ok(())
+    }
+
+    /// Read and check `MAGIC` and `FORMAT_VERSION`
+    pub fn read_header(&mut self) -> Result<(), StateError> {
+        if self.take(MAGIC.len()).ok() != Some(MAGIC.as_slice()) {
+            return Err(StateError::InvalidMagic);
+        }
+        let version = self.read_u16()?;
+        if version > FORMAT_VERSION {
+            return Err(StateError::UnsupportedVersion { tag: None, version, supported: FORMAT_VERSION });
+        }
+        Ok(())
+    }
+
+    /// Read next section, which must have given tag and version up to `supported`.
+    /// Return the version it was saved with and reader of its data.
+    pub fn section(&mut self, tag: [u8; 4], supported: u16) -> Result<(u16, StateReader<'a>), StateError> {
+        if self.buf.get(..4) != Some(tag.as_slice()) {
+            return Err(StateError::MissingSection(tag));
+        }
+        self.take(4)?;
+        let version = self.read_u16()?;
+        if version > supported {
+            return Err(StateError::UnsupportedVersion { tag: Some(tag), version, supported });
+        }
+        Ok((version, StateReader::new(self.read_bytes()?)))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_section() {
+        let mut out = StateWriter::new();
+        out.write_header();
+        out.section(*b"TEST", 2, |out| out.write_u16(0x1234));
+        let state = out.into_bytes();
+
+        let mut input = StateReader::new(&state);
+        input.read_header().unwrap();
+        let (version, mut data) = input.section(*b"TEST", 2).unwrap();
+        assert_eq!(version, 2);
+        assert_eq!(data.read_u16(), Ok(0x1234));
+
+        let mut input = StateReader::new(&state);
+        input.read_header().unwrap();
+        let error = input.section(*b"TEST", 1).err().unwrap();
+        assert_eq!(error.to_string(), "section 'TEST' version 2 is newer than supported version 1");
+        assert_eq!(StateReader::new(b"NES\x1A").read_header(), Err(StateError::InvalidMagic));
+    }
+}
@@ -0,0 +1,141 @@
+//! Provide writer and reader of WAV files with 16bit mono PCM
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Seek, SeekFrom, Write};
+use std::path::Path;
+
+const HEADER_SIZE: u32 = 44;
+
+/// Convert sample in -1.0 to 1.0 to 16bit, clipping out of range values
+pub fn to_i16(sample: f32) -> i16 {
+    (sample.clamp(-1.0, 1.0) * i16::MAX as f32).round() as i16
+}
+
+/// Streaming writer of WAV. Sizes in the header are written by `finish`.
+pub struct WavWriter<W: Write + Seek> {
+    out: W,
+    samples: u32,
+}
+
+impl WavWriter<BufWriter<File>> {
+    pub fn create<P: AsRef<Path>>(path: P, sample_rate: u32) -> io::Result<Self> {
+        WavWriter::new(BufWriter::new(File::create(path)?), sample_rate)
+    }
+}
+
+impl<W: Write + Seek> WavWriter<W> {
+    pub fn new(mut out: W, sample_rate: u32) -> io::Result<Self> {
+        out.write_all(&header(sample_rate, 0))?;
+        Ok(WavWriter { out, samples: 0 })
+    }
+
+    pub fn write_samples(&mut self, samples: &[f32]) -> io::Result<()> {
+        for &sample in samples {
+            self.out.write_all(&to_i16(sample).to_le_bytes())?;
+        }
+        self.samples += samples.len() as u32;
+        Ok(())
+    }
+
+    /// Write sizes in the header and return the output
+    pub fn finish(mut self) -> io::Result<W> {
+        let data = self.samples * 2;
+        self.out.seek(SeekFrom::Start(4))?;
+        self.out.write_all(&(HEADER_SIZE - 8 + data).to_le_bytes())?;
+        self.out.seek(SeekFrom::Start(40))?;
+        self.out.write_all(&data.to_le_bytes())?;
+        self.out.seek(SeekFrom::End(0))?;
+        self.out.flush()?;
+        Ok(self.out)
+    }
+}
+
+/// Encode samples as WAV
+pub fn encode(sample_rate: u32, samples: &[f32]) -> Vec<u8> {
+    let mut wav = WavWriter::new(io::Cursor::new(Vec::new()), sample_rate).unwrap();
+    wav.write_samples(samples).unwrap();
+    wav.finish().unwrap().into_inner()
+}
+
+fn header(sample_rate: u32, data: u32) -> [u8; HEADER_SIZE as usize] {
+    let mut h = [0; HEADER_SIZE as usize];
+    h[0..4].copy_from_slice(b"RIFF");
+    h[4..8].copy_from_slice(&(HEADER_SIZE - 8 + data).to_le_bytes());
+    h[8..16].copy_from_slice(b"WAVEfmt ");
+    h[16..20].copy_from_slice(&16u32.to_le_bytes());
+    // PCM, 1 channel
+    h[20..22].copy_from_slice(&1u16.to_le_bytes());
+    h[22..24].copy_from_slice(&1u16.to_le_bytes());
+    h[24..28].copy_from_slice(&sample_rate.to_le_bytes());
+    h[28..32].copy_from_slice(&(sample_rate * 2).to_le_bytes());
+    h[32..34].copy_from_slice(&2u16.to_le_bytes());
+    h[34..36].copy_from_slice(&16u16.to_le_bytes());
+    h[36..40].copy_from_slice(b"data");
+    h[40..44].copy_from_slice(&data.to_le_bytes());
+    h
+}
+
+#[derive(Debug, PartialEq, Eq)]
+pub enum WavError {
+    /// Not RIFF WAVE, or chunk is truncated
+    InvalidFormat,
+    /// Format other than 16bit mono PCM
+    Unsupported,
+}
+
+impl fmt::Display for WavError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            WavError::InvalidFormat => write!(f, "invalid wav file"),
+            WavError::Unsupported   => write!(f, "only 16bit mono PCM is supported"),
+        }
+    }
+}
+
+impl std::error::Error for WavError {}
+
+/// Read 16bit mono PCM WAV, like a reference recording. Return sample rate and samples.
+pub fn decode(bytes: &[u8]) -> Result<(u32, Vec<i16>), WavError> {
+    if bytes.len() < 12 || &bytes[0..4] != b"RIFF" || &bytes[8..12] != b"WAVE" {
+        return Err(WavError::InvalidFormat);
+    }
+    let mut rate = None;
+    let mut rest = &bytes[12..];
+    while rest.len() >= 8 {
+        let len  = u32::from_le_bytes([rest[4], rest[5], rest[6], rest[7]]) as usize;
+        let body = rest.get(8..8 + len).ok_or(WavError::InvalidFormat)?;
+        match &rest[0..4] {
+            b"fmt " => {
+                let field = |i: usize| u16::from_le_bytes([body[i], body[i + 1]]);
+                if body.len() < 16 || field(0) != 1 || field(2) != 1 || field(14) != 16 {
+                    return Err(WavError::Unsupported);
+                }
+                rate = Some(u32::from_le_bytes([body[4], body[5], body[6], body[7]]));
+            }
+            b"data" => {
+                let rate = rate.ok_or(WavError::InvalidFormat)?;
+                let samples = body.chunks_exact(2).map(|b| i16::from_le_bytes([b[0], b[1]])).collect();
+                return Ok((rate, samples));
+            }
+            _ => (),
+        }
+        // Chunks are padded to even size
+        rest = rest.get(8 + len + (len & 1)..).unwrap_or(&[]);
+    }
+    Err(WavError::InvalidFormat)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_encode_and_decode() {
+        let wav = encode(44_100, &[0.0, 0.5, -1.0, 2.0]);
+        assert_eq!(wav.len(), 44 + 8);
+        assert_eq!(&wav[4..8], &44u32.to_le_bytes());
+        assert_eq!(decode(&wav), Ok((44_100, vec![0, 16384, -32767, 32767])));
+        assert_eq!(decode(&wav[..20]), Err(WavError::InvalidFormat));
+    }
+}